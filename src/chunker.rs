@@ -1,9 +1,150 @@
-use std::num::NonZeroUsize;
+use std::{borrow::Cow, collections::HashSet, num::NonZeroUsize, ops::Range, path::Path};
+
+use crate::{document_chunker, indexer::ESTIMATED_BYTES_PER_TOKEN, syntax_chunker};
+
+/// How source files are split into chunks.
+///
+/// Documents supported by [`document_chunker`] are split into heading sections with either strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkStrategy {
+    /// Sliding windows of lines.
+    #[default]
+    Lines,
+
+    /// Top-level items (functions, classes, ...) packed into windows for the languages supported by
+    /// [`syntax_chunker`] (other files are split into line windows).
+    Syntax,
+}
+
+impl std::fmt::Display for ChunkStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkStrategy::Lines => write!(f, "lines"),
+            ChunkStrategy::Syntax => write!(f, "syntax"),
+        }
+    }
+}
+
+impl std::str::FromStr for ChunkStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lines" => Ok(ChunkStrategy::Lines),
+            "syntax" => Ok(ChunkStrategy::Syntax),
+            _ => Err(format!(
+                "unknown chunk strategy: expected 'lines' or 'syntax', found '{s}'"
+            )),
+        }
+    }
+}
+
+impl nojson::DisplayJson for ChunkStrategy {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.string(self)
+    }
+}
+
+impl<'text> nojson::FromRawJsonValue<'text> for ChunkStrategy {
+    fn from_raw_json_value(
+        value: nojson::RawJsonValue<'text, '_>,
+    ) -> Result<Self, nojson::JsonParseError> {
+        value
+            .to_unquoted_string_str()?
+            .parse()
+            .map_err(|e| nojson::JsonParseError::invalid_value(value, e))
+    }
+}
+
+/// Context information about a chunk prepended to the text embedded for it.
+///
+/// The header lines are written in the order of the variants, as `Name: value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkHeaderField {
+    /// Name of the repository directory.
+    Repository,
+
+    /// Path of the file relative to the repository.
+    Path,
+
+    /// Language of the file, guessed from its extension.
+    Language,
+
+    /// Signature of the top-level item where the chunk starts for the languages supported by
+    /// [`syntax_chunker`], or headings of the section for the documents supported by [`document_chunker`].
+    Symbol,
+}
+
+impl ChunkHeaderField {
+    const ALL: [Self; 4] = [Self::Repository, Self::Path, Self::Language, Self::Symbol];
+
+    /// Parses a comma-separated list of fields (e.g., `path,symbol`).
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl std::fmt::Display for ChunkHeaderField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkHeaderField::Repository => write!(f, "repository"),
+            ChunkHeaderField::Path => write!(f, "path"),
+            ChunkHeaderField::Language => write!(f, "language"),
+            ChunkHeaderField::Symbol => write!(f, "symbol"),
+        }
+    }
+}
+
+impl std::str::FromStr for ChunkHeaderField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "repository" => Ok(ChunkHeaderField::Repository),
+            "path" => Ok(ChunkHeaderField::Path),
+            "language" => Ok(ChunkHeaderField::Language),
+            "symbol" => Ok(ChunkHeaderField::Symbol),
+            _ => Err(format!(
+                "unknown chunk header field: expected 'repository', 'path', 'language' or 'symbol', found '{s}'"
+            )),
+        }
+    }
+}
+
+impl nojson::DisplayJson for ChunkHeaderField {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.string(self)
+    }
+}
+
+impl<'text> nojson::FromRawJsonValue<'text> for ChunkHeaderField {
+    fn from_raw_json_value(
+        value: nojson::RawJsonValue<'text, '_>,
+    ) -> Result<Self, nojson::JsonParseError> {
+        value
+            .to_unquoted_string_str()?
+            .parse()
+            .map_err(|e| nojson::JsonParseError::invalid_value(value, e))
+    }
+}
 
 #[derive(Debug)]
 pub struct Chunker {
     pub window_size: NonZeroUsize,
     pub step_size: NonZeroUsize,
+    pub strategy: ChunkStrategy,
+    pub max_chars: Option<NonZeroUsize>,
+
+    /// Maximum number of tokens (as estimated by [`estimate_tokens()`](crate::indexer::estimate_tokens)).
+    pub max_tokens: Option<NonZeroUsize>,
+    pub header_fields: Vec<ChunkHeaderField>,
+
+    /// Name of the repository, used for [`ChunkHeaderField::Repository`].
+    pub repository_name: String,
 }
 
 impl Chunker {
@@ -11,37 +152,398 @@ impl Chunker {
         Self {
             window_size,
             step_size,
+            strategy: ChunkStrategy::Lines,
+            max_chars: None,
+            max_tokens: None,
+            header_fields: Vec::new(),
+            repository_name: String::new(),
         }
     }
 
-    pub fn apply(&self, input: &str) -> Vec<Chunk<String>> {
-        let mut chunks = Vec::new();
+    pub fn with_strategy(mut self, strategy: ChunkStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Limits the size of the chunk data, so that chunks fit in the context of embedding models.
+    pub fn with_limits(
+        mut self,
+        max_chars: Option<NonZeroUsize>,
+        max_tokens: Option<NonZeroUsize>,
+    ) -> Self {
+        self.max_chars = max_chars;
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Prepends the given context fields to the embedding text of chunks (the chunk data stays raw).
+    ///
+    /// Headers taking more than half of the size limits are dropped, leaving room for the data.
+    pub fn with_header(mut self, fields: Vec<ChunkHeaderField>, repository_path: &Path) -> Self {
+        self.header_fields = fields;
+        self.repository_name = repository_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self
+    }
+
+    /// Splits the content of a file into chunks of at most `window_size` lines.
+    ///
+    /// Every line belongs to at least one chunk.
+    /// Chunks exceeding the size limits (including their headers) are split into smaller ones
+    /// (see [`Chunker::with_limits()`]).
+    pub fn apply(&self, path: &Path, input: &str) -> Vec<Chunk<String>> {
         let lines = input.lines().collect::<Vec<_>>();
-        for (i, lines) in lines.windows(self.window_size.get()).enumerate() {
-            if i % self.step_size.get() != 0 {
+        let context = HeaderContext::new(self, path, &lines);
+        let mut chunks = self.split_lines(path, &lines);
+        if self.max_chars.is_some() || self.max_tokens.is_some() {
+            chunks = self.limit_size(&lines, chunks, &context);
+        }
+        for chunk in &mut chunks {
+            chunk.header = self.header(&context, chunk.line, chunk.header.as_deref());
+        }
+        chunks
+    }
+
+    /// Returns the header of a chunk starting at `line`, unless it is too large to leave
+    /// as much room for the data within the size limits.
+    fn header(
+        &self,
+        context: &HeaderContext,
+        line: usize,
+        breadcrumb: Option<&str>,
+    ) -> Option<String> {
+        context
+            .header(line, breadcrumb)
+            .filter(|header| self.fits(Some(header), header.chars().count(), header.len()))
+    }
+
+    fn split_lines(&self, path: &Path, lines: &[&str]) -> Vec<Chunk<String>> {
+        if let Some(sections) = document_chunker::sections(path, lines)
+            && !sections.is_empty()
+        {
+            // Each chunk is embedded with the headings of its section, regardless of the strategy
+            let mut chunks = Vec::new();
+            for section in sections {
+                for mut chunk in self.pack_items(lines, section.blocks) {
+                    chunk.header = section.breadcrumb.clone();
+                    chunks.push(chunk);
+                }
+            }
+            return chunks;
+        }
+
+        let items = match self.strategy {
+            ChunkStrategy::Lines => None,
+            ChunkStrategy::Syntax => syntax_chunker::item_ranges(path, lines),
+        };
+        match items {
+            Some(items) => self.pack_items(lines, items),
+            None => self.windows(lines, 0),
+        }
+    }
+
+    /// Returns whether a text of the given numbers of characters and bytes, embedded with the header,
+    /// is within the size limits.
+    fn fits(&self, header: Option<&str>, chars: usize, bytes: usize) -> bool {
+        let (chars, bytes) = match header {
+            Some(header) => (chars + header.chars().count() + 2, bytes + header.len() + 2),
+            None => (chars, bytes),
+        };
+        self.max_chars.is_none_or(|max| chars <= max.get())
+            && self
+                .max_tokens
+                .is_none_or(|max| bytes.div_ceil(ESTIMATED_BYTES_PER_TOKEN) <= max.get())
+    }
+
+    /// Splits the chunks exceeding the size limits into consecutive chunks of as many lines as fit,
+    /// and lines exceeding the limits on their own into pieces.
+    fn limit_size(
+        &self,
+        lines: &[&str],
+        chunks: Vec<Chunk<String>>,
+        context: &HeaderContext,
+    ) -> Vec<Chunk<String>> {
+        let mut limited = Vec::new();
+        for chunk in chunks {
+            let header = self.header(context, chunk.line, chunk.header.as_deref());
+            if self.fits(
+                header.as_deref(),
+                chunk.data.chars().count(),
+                chunk.data.len(),
+            ) {
+                limited.push(chunk);
                 continue;
             }
 
-            chunks.push(Chunk {
-                line: i,
-                data: lines.join("\n"),
-            });
+            let end = chunk.line + chunk.line_count;
+            let mut start = chunk.line;
+            while start < end {
+                let header = self.header(context, start, chunk.header.as_deref());
+                let (mut chars, mut bytes, mut count) = (0, 0, 0);
+                for line in &lines[start..end] {
+                    let separator = usize::from(count > 0);
+                    if !self.fits(
+                        header.as_deref(),
+                        chars + separator + line.chars().count(),
+                        bytes + separator + line.len(),
+                    ) {
+                        break;
+                    }
+                    chars += separator + line.chars().count();
+                    bytes += separator + line.len();
+                    count += 1;
+                }
+
+                if count > 0 {
+                    let mut limited_chunk = Chunk::new(lines, start..start + count, 0);
+                    limited_chunk.header = chunk.header.clone();
+                    limited.push(limited_chunk);
+                    start += count;
+                    continue;
+                }
+                for columns in self.split_line(header.as_deref(), lines[start]) {
+                    limited.push(Chunk {
+                        line: start,
+                        line_count: 1,
+                        data: lines[start][columns.clone()].to_owned(),
+                        columns: Some(columns),
+                        header: chunk.header.clone(),
+                    });
+                }
+                start += 1;
+            }
+        }
+
+        // Overlapping windows may be split into the same chunks
+        let mut seen = HashSet::new();
+        limited.retain(|c| seen.insert((c.line, c.line_count, c.columns.clone())));
+        limited
+    }
+
+    /// Splits a line into byte ranges of as many characters as fit (but at least one character).
+    fn split_line(&self, header: Option<&str>, line: &str) -> Vec<Range<usize>> {
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        let mut chars = 0;
+        for (i, c) in line.char_indices() {
+            if i > start && !self.fits(header, chars + 1, i + c.len_utf8() - start) {
+                pieces.push(start..i);
+                start = i;
+                chars = 0;
+            }
+            chars += 1;
+        }
+        pieces.push(start..line.len());
+        pieces
+    }
+
+    /// Splits lines into windows of `window_size` lines starting every `step_size` lines.
+    ///
+    /// Steps longer than the window are shortened to the window size, and if the last window
+    /// does not reach the end of the lines, a final window anchored at the last line is added.
+    fn windows(&self, lines: &[&str], offset: usize) -> Vec<Chunk<String>> {
+        let window_size = self.window_size.get();
+        let step_size = self.step_size.min(self.window_size).get();
+        let mut chunks = Vec::new();
+        for i in (0..lines.len().saturating_sub(window_size - 1)).step_by(step_size) {
+            chunks.push(Chunk::new(lines, i..i + window_size, offset));
         }
         if chunks.is_empty() {
-            assert!(lines.len() < self.window_size.get());
-            chunks.push(Chunk {
-                line: 0,
-                data: lines.join("\n"),
-            });
+            chunks.push(Chunk::new(lines, 0..lines.len(), offset));
+        } else if let Some(tail) = lines.len().checked_sub(window_size)
+            && tail % step_size != 0
+        {
+            chunks.push(Chunk::new(lines, tail..lines.len(), offset));
+        }
+        chunks
+    }
+
+    /// Packs consecutive items (line ranges covering all lines) into chunks of at most `window_size` lines.
+    ///
+    /// Items longer than the window are split into windows on their own.
+    fn pack_items(&self, lines: &[&str], items: Vec<Range<usize>>) -> Vec<Chunk<String>> {
+        let window_size = self.window_size.get();
+        let mut chunks = Vec::new();
+        let first_line = items.first().map_or(0, |item| item.start);
+        let mut current = first_line..first_line;
+        for item in items {
+            if item.end - current.start <= window_size {
+                current.end = item.end;
+                continue;
+            }
+            if !current.is_empty() {
+                chunks.push(Chunk::new(lines, current, 0));
+            }
+            if item.len() > window_size {
+                chunks.extend(self.windows(&lines[item.clone()], item.start));
+                current = item.end..item.end;
+            } else {
+                current = item;
+            }
+        }
+        if !current.is_empty() || chunks.is_empty() {
+            chunks.push(Chunk::new(lines, current, 0));
         }
         chunks
     }
 }
 
+/// Header information of the chunks of a file.
+#[derive(Debug)]
+struct HeaderContext {
+    /// Header lines shared by all chunks of the file.
+    file_header: Vec<String>,
+
+    /// Header lines of the enclosing symbols of consecutive line ranges,
+    /// if [`ChunkHeaderField::Symbol`] is enabled.
+    symbols: Option<Vec<(Range<usize>, String)>>,
+}
+
+impl HeaderContext {
+    fn new(chunker: &Chunker, path: &Path, lines: &[&str]) -> Self {
+        let mut file_header = Vec::new();
+        let mut symbols = None;
+        for field in ChunkHeaderField::ALL {
+            if !chunker.header_fields.contains(&field) {
+                continue;
+            }
+            match field {
+                ChunkHeaderField::Repository => {
+                    file_header.push(format!("Repository: {}", chunker.repository_name));
+                }
+                ChunkHeaderField::Path => file_header.push(format!("Path: {}", path.display())),
+                ChunkHeaderField::Language => {
+                    if let Some(language) = language_name(path) {
+                        file_header.push(format!("Language: {language}"));
+                    }
+                }
+                ChunkHeaderField::Symbol => symbols = Some(enclosing_symbols(path, lines)),
+            }
+        }
+        Self {
+            file_header,
+            symbols,
+        }
+    }
+
+    /// Returns the header of a chunk starting at `line`.
+    ///
+    /// The breadcrumb of a document section is kept as the last line,
+    /// unless the section is already given as the enclosing symbol.
+    fn header(&self, line: usize, breadcrumb: Option<&str>) -> Option<String> {
+        let symbol = match &self.symbols {
+            Some(symbols) => {
+                let i = symbols.partition_point(|(range, _)| range.end <= line);
+                symbols
+                    .get(i)
+                    .filter(|(range, _)| range.start <= line)
+                    .map(|(_, symbol)| symbol.as_str())
+            }
+            None => breadcrumb,
+        };
+        let header = self
+            .file_header
+            .iter()
+            .map(String::as_str)
+            .chain(symbol)
+            .collect::<Vec<_>>();
+        (!header.is_empty()).then(|| header.join("\n"))
+    }
+}
+
+/// Returns the header lines of the top-level items or document sections of a file.
+fn enclosing_symbols(path: &Path, lines: &[&str]) -> Vec<(Range<usize>, String)> {
+    if let Some(sections) = document_chunker::sections(path, lines) {
+        return sections
+            .into_iter()
+            .filter_map(|section| {
+                let start = section.blocks.first()?.start;
+                let end = section.blocks.last()?.end;
+                Some((start..end, format!("Section: {}", section.breadcrumb?)))
+            })
+            .collect();
+    }
+    syntax_chunker::item_ranges(path, lines)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|item| {
+            let signature = syntax_chunker::item_signature(path, &lines[item.clone()])?;
+            Some((item, format!("Symbol: {signature}")))
+        })
+        .collect()
+}
+
+/// Returns the name of the language of a file guessed from its extension.
+fn language_name(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    let name = match extension.as_str() {
+        "rs" => "Rust",
+        "c" | "h" => "C",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" => "C++",
+        "cs" => "C#",
+        "go" => "Go",
+        "java" => "Java",
+        "js" | "jsx" | "mjs" | "cjs" => "JavaScript",
+        "ts" | "tsx" => "TypeScript",
+        "kt" | "kts" => "Kotlin",
+        "swift" => "Swift",
+        "scala" => "Scala",
+        "php" => "PHP",
+        "dart" => "Dart",
+        "zig" => "Zig",
+        "py" | "pyi" => "Python",
+        "rb" => "Ruby",
+        "sh" | "bash" | "zsh" => "Shell",
+        "sql" => "SQL",
+        "html" | "htm" => "HTML",
+        "css" => "CSS",
+        "json" => "JSON",
+        "toml" => "TOML",
+        "yaml" | "yml" => "YAML",
+        "md" | "markdown" | "mdx" => "Markdown",
+        "adoc" | "asciidoc" | "asc" => "AsciiDoc",
+        "rst" => "reStructuredText",
+        _ => return None,
+    };
+    Some(name)
+}
+
 #[derive(Debug)]
 pub struct Chunk<T> {
     pub line: usize,
+
+    /// Number of lines of the chunk.
+    pub line_count: usize,
     pub data: T,
+
+    /// Byte range of the data within the line, if the chunk is a piece of a line too long on its own.
+    pub columns: Option<Range<usize>>,
+
+    /// Text prepended to the data when embedding (e.g., the headings of a document section
+    /// or the fields enabled by [`Chunker::with_header()`]), which is not part of the file content.
+    pub header: Option<String>,
+}
+
+impl Chunk<String> {
+    fn new(lines: &[&str], range: Range<usize>, offset: usize) -> Self {
+        Self {
+            line: offset + range.start,
+            line_count: range.len(),
+            data: lines[range].join("\n"),
+            columns: None,
+            header: None,
+        }
+    }
+
+    /// Returns the text to be embedded.
+    pub fn embedding_text(&self) -> Cow<'_, str> {
+        match &self.header {
+            Some(header) => Cow::Owned(format!("{header}\n\n{}", self.data)),
+            None => Cow::Borrowed(&self.data),
+        }
+    }
 }
 
 impl<T> nojson::DisplayJson for Chunk<T>
@@ -51,7 +553,15 @@ where
     fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
         f.object(|f| {
             f.member("line", self.line)?;
-            f.member("data", &self.data)
+            f.member("line_count", self.line_count)?;
+            f.member("data", &self.data)?;
+            if let Some(columns) = &self.columns {
+                f.member("columns", [columns.start, columns.end])?;
+            }
+            if let Some(header) = &self.header {
+                f.member("header", header)?;
+            }
+            Ok(())
         })
     }
 }
@@ -63,10 +573,345 @@ where
     fn from_raw_json_value(
         value: nojson::RawJsonValue<'text, '_>,
     ) -> Result<Self, nojson::JsonParseError> {
-        let ([line, data], []) = value.to_fixed_object(["line", "data"], [])?;
+        let ([line, line_count, data], [columns, header]) =
+            value.to_fixed_object(["line", "line_count", "data"], ["columns", "header"])?;
         Ok(Chunk {
             line: line.try_to()?,
+            line_count: line_count.try_to()?,
             data: data.try_to()?,
+            columns: columns
+                .map(|v| v.try_to().map(|[start, end]: [usize; 2]| start..end))
+                .transpose()?,
+            header: header.map(|v| v.try_to()).transpose()?,
         })
     }
 }
+
+/// Extracts the text of a chunk from the content of its file.
+///
+/// Returns `None` if the columns are not within the line or not at character boundaries
+/// (i.e., the file changed since indexing).
+pub fn extract_chunk_text(
+    content: &str,
+    line: usize,
+    line_count: usize,
+    columns: Option<&Range<usize>>,
+) -> Option<String> {
+    let mut lines = content.lines().skip(line).take(line_count);
+    match columns {
+        Some(columns) => lines.next()?.get(columns.clone()).map(str::to_owned),
+        None => Some(lines.collect::<Vec<_>>().join("\n")),
+    }
+}
+
+/// A 64-bit FNV-1a hash of chunk content, used to detect unchanged chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ContentHash(pub u64);
+
+impl ContentHash {
+    pub fn of(text: &str) -> Self {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for b in text.bytes() {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        Self(hash)
+    }
+}
+
+impl std::fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl std::str::FromStr for ContentHash {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(Self)
+    }
+}
+
+impl nojson::DisplayJson for ContentHash {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.string(self)
+    }
+}
+
+impl<'text> nojson::FromRawJsonValue<'text> for ContentHash {
+    fn from_raw_json_value(
+        value: nojson::RawJsonValue<'text, '_>,
+    ) -> Result<Self, nojson::JsonParseError> {
+        value
+            .to_unquoted_string_str()?
+            .parse()
+            .map_err(|e| nojson::JsonParseError::invalid_value(value, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestRng;
+
+    fn chunk_ranges(chunker: &Chunker, path: &str, input: &str) -> Vec<(usize, usize)> {
+        chunker
+            .apply(Path::new(path), input)
+            .into_iter()
+            .map(|c| (c.line, c.line + c.line_count))
+            .collect()
+    }
+
+    fn line_ranges(chunker: &Chunker, line_count: usize) -> Vec<(usize, usize)> {
+        let input = (0..line_count)
+            .map(|i| format!("line {i}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        chunk_ranges(chunker, "a.txt", &input)
+    }
+
+    fn chunker(window_size: usize, step_size: usize) -> Chunker {
+        Chunker::new(
+            NonZeroUsize::new(window_size).expect("non-zero"),
+            NonZeroUsize::new(step_size).expect("non-zero"),
+        )
+    }
+
+    #[test]
+    fn final_window_covers_tail() {
+        let chunker = chunker(100, 50);
+        assert_eq!(line_ranges(&chunker, 180), [(0, 100), (50, 150), (80, 180)]);
+        assert_eq!(
+            line_ranges(&chunker, 200),
+            [(0, 100), (50, 150), (100, 200)]
+        );
+        assert_eq!(line_ranges(&chunker, 30), [(0, 30)]);
+    }
+
+    #[test]
+    fn syntax_chunks_pack_items() {
+        let source =
+            "use a;\n\nfn f() {\n    1\n}\n\nfn g() {\n    2\n    3\n    4\n    5\n}\nfn h() {}\n";
+        let chunker = chunker(5, 2).with_strategy(ChunkStrategy::Syntax);
+
+        // `fn g` does not fit in a window and is split into windows on its own
+        assert_eq!(
+            chunk_ranges(&chunker, "a.rs", source),
+            [(0, 2), (2, 6), (6, 11), (7, 12), (12, 13)]
+        );
+        assert_eq!(
+            chunk_ranges(&chunker, "a.txt", source),
+            chunk_ranges(&chunker.with_strategy(ChunkStrategy::Lines), "a.rs", source)
+        );
+    }
+
+    #[test]
+    fn document_chunks_have_breadcrumbs() {
+        let source = "# Install\n\n## Linux\n\nmake\n\nmake install\n\n## macOS\n\nbrew\n";
+        let chunks = chunker(4, 2)
+            .with_strategy(ChunkStrategy::Syntax)
+            .apply(Path::new("README.md"), source);
+        let summary = chunks
+            .iter()
+            .map(|c| (c.line, c.line_count, c.header.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (0, 4, Some("Install > Linux")),
+                (4, 4, Some("Install > Linux")),
+                (8, 3, Some("Install > macOS")),
+            ]
+        );
+
+        // Documents are split into sections regardless of the strategy
+        let chunks = chunker(4, 2).apply(Path::new("README.md"), source);
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|c| (c.line, c.line_count, c.header.as_deref()))
+                .collect::<Vec<_>>(),
+            summary
+        );
+        assert_eq!(chunks[2].data, "## macOS\n\nbrew");
+        assert_eq!(
+            chunks[2].embedding_text(),
+            "Install > macOS\n\n## macOS\n\nbrew"
+        );
+    }
+
+    #[test]
+    fn size_limits_split_chunks() {
+        let input = "a\nbb\nccc\n\u{3042}\u{3044}\u{3046}\u{3048}\u{304a}\nd";
+        let chunker = chunker(5, 5).with_limits(NonZeroUsize::new(4), None);
+        let chunks = chunker.apply(Path::new("a.txt"), input);
+        let summary = chunks
+            .iter()
+            .map(|c| (c.line, c.line_count, c.columns.clone(), c.data.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (0, 2, None, "a\nbb"),
+                (2, 1, None, "ccc"),
+                (3, 1, Some(0..12), "\u{3042}\u{3044}\u{3046}\u{3048}"),
+                (3, 1, Some(12..15), "\u{304a}"),
+                (4, 1, None, "d"),
+            ]
+        );
+        for c in &chunks {
+            assert_eq!(
+                extract_chunk_text(input, c.line, c.line_count, c.columns.as_ref()).as_deref(),
+                Some(c.data.as_str())
+            );
+        }
+
+        // 2 tokens are estimated to be up to 6 bytes
+        let chunker = chunker.with_limits(None, NonZeroUsize::new(2));
+        assert_eq!(
+            chunker
+                .apply(Path::new("a.txt"), "abcdefgh")
+                .into_iter()
+                .map(|c| c.columns)
+                .collect::<Vec<_>>(),
+            [Some(0..6), Some(6..8)]
+        );
+    }
+
+    #[test]
+    fn headers_too_large_for_size_limits_are_dropped() {
+        let path = Path::new("a/long/path/to/the/file.txt");
+        let chunker = chunker(5, 5)
+            .with_limits(NonZeroUsize::new(40), None)
+            .with_header(vec![ChunkHeaderField::Path], Path::new("/repo"));
+        let chunks = chunker.apply(path, "abcdefghijklmnopqrstuvwxyz0123456789");
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].header, None);
+
+        let chunker = chunker.with_limits(NonZeroUsize::new(80), None);
+        let chunks = chunker.apply(path, &"abcdefghijklmnopqrstuvwxyz0123456789".repeat(2));
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|c| c.header.is_some()));
+    }
+
+    #[test]
+    fn chunk_headers() {
+        let fields =
+            ChunkHeaderField::parse_list("symbol,path,language,repository").expect("valid");
+        let chunker = chunker(3, 3).with_header(fields, Path::new("/src/dokosa"));
+        let source = "use std::path::Path;\n\n/// Doc\nfn f() {\n    1\n}\n";
+        let chunks = chunker.apply(Path::new("src/a.rs"), source);
+        let headers = chunks
+            .iter()
+            .map(|c| (c.line, c.header.as_deref(), c.data.as_str()))
+            .collect::<Vec<_>>();
+        let file_header = "Repository: dokosa\nPath: src/a.rs\nLanguage: Rust";
+        assert_eq!(
+            headers,
+            [
+                (0, Some(file_header), "use std::path::Path;\n\n/// Doc"),
+                (
+                    3,
+                    Some(&*format!("{file_header}\nSymbol: fn f()")),
+                    "fn f() {\n    1\n}"
+                ),
+            ]
+        );
+        assert!(
+            chunks[1]
+                .embedding_text()
+                .ends_with("Symbol: fn f()\n\nfn f() {\n    1\n}")
+        );
+
+        // Document sections are named once, even when chunked along headings
+        let chunker = chunker
+            .with_strategy(ChunkStrategy::Syntax)
+            .with_header(vec![ChunkHeaderField::Symbol], Path::new("/src/dokosa"));
+        let chunks = chunker.apply(Path::new("README.md"), "# A\n\n## B\ntext\n");
+        assert_eq!(chunks[0].header.as_deref(), Some("Section: A > B"));
+        let chunker = chunker.with_header(vec![ChunkHeaderField::Path], Path::new("/src/dokosa"));
+        let chunks = chunker.apply(Path::new("README.md"), "# A\n\n## B\ntext\n");
+        assert_eq!(chunks[0].header.as_deref(), Some("Path: README.md\nA > B"));
+
+        // Headers count toward the size limits
+        let chunker = chunker.with_limits(NonZeroUsize::new(30), None);
+        let chunks = chunker.apply(Path::new("a.txt"), "0123456789abcdefghij");
+        assert_eq!(chunks[0].header.as_deref(), Some("Path: a.txt"));
+        assert_eq!(chunks[0].columns, Some(0..17));
+    }
+
+    #[test]
+    fn every_line_belongs_to_a_chunk() {
+        let mut rng = TestRng::new(0x1234_5678_9abc_def0);
+        let mut next = |max: u64| rng.below(max);
+        let source_lines = [
+            "fn f() {",
+            "    x;",
+            "}",
+            "",
+            "// c",
+            "#[test]",
+            "struct S;",
+        ];
+        for _ in 0..1000 {
+            let window_size = next(20) as usize + 1;
+            let step_size = next(25) as usize + 1;
+            let input = (0..next(100) + 1)
+                .map(|_| source_lines[next(source_lines.len() as u64) as usize])
+                .collect::<Vec<_>>()
+                .join("\n");
+            let line_count = input.lines().count();
+            for strategy in [ChunkStrategy::Lines, ChunkStrategy::Syntax] {
+                let chunker = chunker(window_size, step_size).with_strategy(strategy);
+                let ranges = chunk_ranges(&chunker, "a.rs", &input);
+
+                let case = format!(
+                    "strategy={strategy}, window={window_size}, step={step_size}, lines={line_count}"
+                );
+                for line in 0..line_count {
+                    assert!(
+                        ranges
+                            .iter()
+                            .any(|&(start, end)| start <= line && line < end),
+                        "line {line} is not covered ({case})"
+                    );
+                }
+                assert!(
+                    ranges
+                        .iter()
+                        .all(|&(start, end)| end - start <= window_size),
+                    "{case}"
+                );
+                assert!(ranges.windows(2).all(|w| w[0].0 < w[1].0), "{case}");
+            }
+
+            let max_chars = next(12) as usize + 1;
+            let chunker = chunker(window_size, step_size)
+                .with_strategy(ChunkStrategy::Syntax)
+                .with_limits(NonZeroUsize::new(max_chars), None);
+            let chunks = chunker.apply(Path::new("a.rs"), &input);
+            let case = format!(
+                "max_chars={max_chars}, window={window_size}, step={step_size}, lines={line_count}"
+            );
+            for (line, text) in input.lines().enumerate() {
+                let pieces = chunks
+                    .iter()
+                    .filter(|c| c.line == line && c.columns.is_some())
+                    .map(|c| c.data.as_str())
+                    .collect::<String>();
+                assert!(
+                    pieces == text
+                        || chunks
+                            .iter()
+                            .any(|c| c.line <= line && line < c.line + c.line_count),
+                    "line {line} is not covered ({case})"
+                );
+            }
+            assert!(
+                chunks.iter().all(|c| c.data.chars().count() <= max_chars),
+                "{case}"
+            );
+        }
+    }
+}
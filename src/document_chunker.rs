@@ -0,0 +1,387 @@
+//! Splitting of Markdown, AsciiDoc and reStructuredText documents into heading sections.
+//!
+//! Each section is further divided into blocks (paragraphs, lists, code blocks, ...) separated
+//! by blank lines, so that long sections can be split between blocks. Code fences and delimited
+//! blocks are kept whole, and headings-like lines inside them are ignored.
+use std::{ops::Range, path::Path};
+
+/// Separator of the headings in a breadcrumb.
+const BREADCRUMB_SEPARATOR: &str = " > ";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentSection {
+    /// Titles of the headings enclosing the section (e.g., "Install > Linux > Packages"),
+    /// or `None` for the text before the first heading.
+    pub breadcrumb: Option<String>,
+
+    /// Consecutive line ranges of the blocks of the section.
+    pub blocks: Vec<Range<usize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Markup {
+    Markdown,
+    AsciiDoc,
+    ReStructuredText,
+}
+
+impl Markup {
+    fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "md" | "markdown" | "mdx" => Some(Markup::Markdown),
+            "adoc" | "asciidoc" | "asc" => Some(Markup::AsciiDoc),
+            "rst" => Some(Markup::ReStructuredText),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Heading {
+    level: usize,
+    title: String,
+
+    /// Number of lines of the heading (including underlines and overlines).
+    line_count: usize,
+}
+
+/// Splits the lines of a document into sections covering all lines.
+///
+/// Returns `None` if the file is not a supported document.
+/// Sections consisting of a heading only are merged into the following subsection.
+pub fn sections(path: &Path, lines: &[&str]) -> Option<Vec<DocumentSection>> {
+    let markup = Markup::from_path(path)?;
+    let mut parser = Parser {
+        markup,
+        rst_styles: Vec::new(),
+    };
+
+    // (start line, heading level, breadcrumb, whether the section has content besides its heading)
+    let mut section_starts = vec![(0, 0, None, false)];
+    let mut block_starts = vec![0];
+    let mut headings = Vec::<(usize, String)>::new();
+    let mut fence = None::<String>;
+    let mut previous_blank = true;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if let Some(closing) = &fence {
+            if parser.closes_fence(line, closing) {
+                fence = None;
+            }
+            i += 1;
+            previous_blank = false;
+            continue;
+        }
+
+        let blank = line.trim().is_empty();
+        if let Some(heading) = parser.heading(lines, i, previous_blank) {
+            let level = heading.level;
+            headings.retain(|(l, _)| *l < level);
+            headings.push((level, heading.title));
+            let breadcrumb = headings
+                .iter()
+                .map(|(_, title)| title.as_str())
+                .collect::<Vec<_>>()
+                .join(BREADCRUMB_SEPARATOR);
+            section_starts.push((i, level, Some(breadcrumb), false));
+            block_starts.push(i);
+            i += heading.line_count;
+            previous_blank = false;
+            continue;
+        }
+
+        if !blank {
+            if previous_blank {
+                block_starts.push(i);
+            }
+            section_starts.last_mut().expect("non-empty").3 = true;
+            fence = parser.opens_fence(line, i);
+        }
+        previous_blank = blank;
+        i += 1;
+    }
+
+    let mut sections = Vec::new();
+    let mut pending_blocks = Vec::new();
+    for (k, (start, level, breadcrumb, has_content)) in section_starts.iter().enumerate() {
+        let next = section_starts.get(k + 1);
+        let end = next.map_or(lines.len(), |s| s.0);
+        if start == &end {
+            continue;
+        }
+        let mut starts = block_starts
+            .iter()
+            .copied()
+            .filter(|&b| *start <= b && b < end)
+            .collect::<Vec<_>>();
+        if starts.first() != Some(start) {
+            starts.insert(0, *start);
+        }
+        starts.dedup();
+        starts.push(end);
+        pending_blocks.extend(starts.windows(2).map(|w| w[0]..w[1]));
+
+        if *has_content || next.is_none_or(|s| s.1 <= *level) {
+            sections.push(DocumentSection {
+                breadcrumb: breadcrumb.clone(),
+                blocks: std::mem::take(&mut pending_blocks),
+            });
+        }
+    }
+    Some(sections)
+}
+
+#[derive(Debug)]
+struct Parser {
+    markup: Markup,
+
+    /// Adornment styles (character and whether overlined) of reStructuredText section titles
+    /// in the order of appearance, which defines their levels.
+    rst_styles: Vec<(char, bool)>,
+}
+
+impl Parser {
+    /// Parses the heading starting at the `i`-th line.
+    ///
+    /// Underlined headings must follow a blank line, as they are otherwise continuations of paragraphs.
+    fn heading(&mut self, lines: &[&str], i: usize, previous_blank: bool) -> Option<Heading> {
+        let line = lines[i];
+        if !previous_blank && self.markup == Markup::ReStructuredText {
+            return None;
+        }
+        match self.markup {
+            Markup::Markdown => {
+                if let Some(heading) = prefixed_heading(line.trim_start(), '#', 6) {
+                    return (line.len() - line.trim_start().len() <= 3).then_some(heading);
+                }
+                if !previous_blank {
+                    return None;
+                }
+
+                // Setext headings
+                let underline = lines.get(i + 1)?.trim();
+                let level = match underline.chars().next()? {
+                    '=' => 1,
+                    '-' => 2,
+                    _ => return None,
+                };
+                (!line.trim().is_empty()
+                    && !line.starts_with(char::is_whitespace)
+                    && underline
+                        .chars()
+                        .all(|c| c == underline.as_bytes()[0] as char))
+                .then(|| Heading {
+                    level,
+                    title: line.trim().to_owned(),
+                    line_count: 2,
+                })
+            }
+            Markup::AsciiDoc => prefixed_heading(line, '=', 6),
+            Markup::ReStructuredText => {
+                let (title, overlined) = if is_rst_adornment(line) {
+                    let title = lines.get(i + 1)?;
+                    (lines.get(i + 2) == Some(&line)).then_some((title.trim(), true))?
+                } else {
+                    (line, false)
+                };
+                let underline = lines.get(i + usize::from(overlined) + 1)?;
+                if title.is_empty()
+                    || (!overlined && title.starts_with(char::is_whitespace))
+                    || is_rst_adornment(title)
+                    || !is_rst_adornment(underline)
+                    || underline.chars().count() < title.chars().count()
+                {
+                    return None;
+                }
+
+                let style = (underline.chars().next()?, overlined);
+                let level = match self.rst_styles.iter().position(|s| *s == style) {
+                    Some(i) => i + 1,
+                    None => {
+                        self.rst_styles.push(style);
+                        self.rst_styles.len()
+                    }
+                };
+                Some(Heading {
+                    level,
+                    title: title.to_owned(),
+                    line_count: if overlined { 3 } else { 2 },
+                })
+            }
+        }
+    }
+
+    /// Returns the closing delimiter if the line opens a code fence or a delimited block.
+    fn opens_fence(&self, line: &str, i: usize) -> Option<String> {
+        let trimmed = line.trim_end();
+        match self.markup {
+            Markup::Markdown => {
+                // YAML front matter
+                if i == 0 && trimmed == "---" {
+                    return Some(trimmed.to_owned());
+                }
+                let fence = line.trim_start();
+                if line.len() - fence.len() > 3 {
+                    return None;
+                }
+                let c = fence.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+                let len = fence.chars().take_while(|&x| x == c).count();
+                (len >= 3).then(|| c.to_string().repeat(len))
+            }
+            Markup::AsciiDoc => {
+                let c = trimmed.chars().next()?;
+                let is_delimiter = (matches!(c, '-' | '.' | '=' | '*' | '+' | '/' | '_')
+                    && trimmed.len() >= 4
+                    || c == '`' && trimmed.len() >= 3)
+                    && trimmed.chars().all(|x| x == c);
+                is_delimiter.then(|| trimmed.to_owned())
+            }
+            // Literal blocks are indented, so they never contain section titles
+            Markup::ReStructuredText => None,
+        }
+    }
+
+    fn closes_fence(&self, line: &str, closing: &str) -> bool {
+        match self.markup {
+            Markup::Markdown if !closing.starts_with('-') => {
+                let fence = line.trim();
+                fence.starts_with(closing) && fence.chars().all(|c| closing.starts_with(c))
+            }
+            _ => line.trim_end() == closing,
+        }
+    }
+}
+
+/// Parses a heading such as `## Title` (Markdown) or `== Title` (AsciiDoc).
+fn prefixed_heading(line: &str, marker: char, max_level: usize) -> Option<Heading> {
+    let level = line.chars().take_while(|&c| c == marker).count();
+    let rest = &line[level..];
+    if level == 0 || level > max_level || !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+    let title = rest.trim().trim_end_matches(marker).trim_end();
+    if title.is_empty() {
+        return None;
+    }
+    Some(Heading {
+        level,
+        title: title.to_owned(),
+        line_count: 1,
+    })
+}
+
+fn is_rst_adornment(line: &str) -> bool {
+    let line = line.trim_end();
+    let Some(c) = line.chars().next() else {
+        return false;
+    };
+    line.len() >= 2 && c.is_ascii_punctuation() && line.chars().all(|x| x == c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type SectionSummary = (Option<String>, Vec<(usize, usize)>);
+
+    fn section_summary(path: &str, text: &str) -> Vec<SectionSummary> {
+        let lines = text.lines().collect::<Vec<_>>();
+        sections(Path::new(path), &lines)
+            .expect("supported")
+            .into_iter()
+            .map(|s| {
+                let blocks = s.blocks.iter().map(|b| (b.start, b.end)).collect();
+                (s.breadcrumb, blocks)
+            })
+            .collect()
+    }
+
+    fn breadcrumb(s: &str) -> Option<String> {
+        Some(s.to_owned())
+    }
+
+    #[test]
+    fn markdown_sections() {
+        let text = "\
+Intro
+
+# Install
+
+## Linux
+Run:
+
+```sh
+# not a heading
+
+make install
+```
+
+### Packages ###
+
+Text
+
+Guide
+-----
+End
+";
+        assert_eq!(
+            section_summary("README.md", text),
+            [
+                (None, vec![(0, 2)]),
+                (breadcrumb("Install > Linux"), vec![(2, 4), (4, 7), (7, 13)]),
+                (
+                    breadcrumb("Install > Linux > Packages"),
+                    vec![(13, 15), (15, 17)]
+                ),
+                (breadcrumb("Install > Guide"), vec![(17, 20)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn asciidoc_sections() {
+        let text = "\
+= Manual
+
+== Usage
+
+----
+== not a heading
+----
+";
+        assert_eq!(
+            section_summary("manual.adoc", text),
+            [(breadcrumb("Manual > Usage"), vec![(0, 2), (2, 4), (4, 7)])]
+        );
+    }
+
+    #[test]
+    fn rst_sections() {
+        let text = "\
+=====
+Guide
+=====
+
+Install
+-------
+
+Text
+
+Linux
+~~~~~
+
+Usage
+-----
+";
+        assert_eq!(
+            section_summary("guide.rst", text),
+            [
+                (breadcrumb("Guide > Install"), vec![(0, 4), (4, 7), (7, 9)]),
+                (breadcrumb("Guide > Install > Linux"), vec![(9, 12)]),
+                (breadcrumb("Guide > Usage"), vec![(12, 14)]),
+            ]
+        );
+    }
+}
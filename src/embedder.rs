@@ -1,52 +1,342 @@
+use std::{
+    cell::Cell,
+    sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
 use orfail::OrFail;
 
+use crate::{
+    http::HttpResponse,
+    index_file::RepositoryEntry,
+    lexical_embedder::{DEFAULT_LEXICAL_DIMENSION, LexicalEmbedder},
+};
+
+/// A backend that converts texts into embedding vectors.
+pub trait EmbeddingProvider: std::fmt::Debug {
+    /// Returns the kind of this provider.
+    fn kind(&self) -> EmbeddingProviderKind;
+
+    /// Returns the endpoint of the API used to generate embeddings, if any.
+    fn endpoint(&self) -> Option<&str> {
+        None
+    }
+
+    /// Returns the identifier of the model used to generate embeddings.
+    fn model(&self) -> &str;
+
+    /// Returns the dimension of the generated embeddings, if it is known in advance.
+    fn dimension(&self) -> Option<usize>;
+
+    /// Embeds the given texts, returning one embedding per input in the same order.
+    fn embed(&self, input_texts: &[String]) -> orfail::Result<Vec<Embedding>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingProviderKind {
+    OpenAi,
+    Lexical,
+}
+
+impl std::fmt::Display for EmbeddingProviderKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmbeddingProviderKind::OpenAi => write!(f, "openai"),
+            EmbeddingProviderKind::Lexical => write!(f, "lexical"),
+        }
+    }
+}
+
+impl std::str::FromStr for EmbeddingProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "openai" => Ok(EmbeddingProviderKind::OpenAi),
+            "lexical" => Ok(EmbeddingProviderKind::Lexical),
+            _ => Err(format!(
+                "unknown embedding provider: expected 'openai' or 'lexical', found '{s}'"
+            )),
+        }
+    }
+}
+
+/// Default endpoint of the OpenAI embeddings API.
+pub const DEFAULT_OPENAI_ENDPOINT: &str = "https://api.openai.com/v1/embeddings";
+
+/// Default OpenAI embedding model.
+pub const DEFAULT_OPENAI_MODEL: &str = "text-embedding-3-small";
+
+/// Command-line options shared by the subcommands that need to generate embeddings.
+#[derive(Debug, Clone)]
+pub struct EmbedderOptions {
+    pub provider: Option<EmbeddingProviderKind>,
+    pub openai_api_key: Option<String>,
+    pub model: Option<String>,
+    pub dimensions: Option<usize>,
+    pub endpoint: Option<String>,
+    pub headers: Vec<String>,
+    pub max_retries: usize,
+}
+
+impl EmbedderOptions {
+    pub fn take(args: &mut noargs::RawArgs) -> noargs::Result<Self> {
+        let provider: Option<EmbeddingProviderKind> = noargs::opt("embedding-provider")
+            .ty("PROVIDER")
+            .doc(concat!(
+                "Embedding provider to use for text vectorization (openai | lexical)\n",
+                "[default: the one recorded in the index, or openai]"
+            ))
+            .env("DOKOSA_EMBEDDING_PROVIDER")
+            .take(args)
+            .present_and_then(|a| a.value().parse())?;
+        let openai_api_key: Option<String> = noargs::opt("openai-api-key")
+            .ty("STRING")
+            .doc("OpenAI API key for generating embeddings")
+            .example("YOUR_API_KEY")
+            .env("OPENAI_API_KEY")
+            .take(args)
+            .present_and_then(|a| a.value().parse())?;
+        let model: Option<String> = noargs::opt("embedding-model")
+            .ty("STRING")
+            .doc(concat!(
+                "OpenAI embedding model to use for text vectorization\n",
+                "[default: the one recorded in the index, or text-embedding-3-small]"
+            ))
+            .take(args)
+            .present_and_then(|a| a.value().parse())?;
+        let dimensions: Option<usize> = noargs::opt("embedding-dimensions")
+            .ty("INTEGER")
+            .doc(concat!(
+                "Number of dimensions of the embeddings, for models that support shortening them\n",
+                "[default: the one recorded in the index, or the model's default]"
+            ))
+            .take(args)
+            .present_and_then(|a| a.value().parse())?;
+        let endpoint: Option<String> = noargs::opt("embedding-endpoint")
+            .ty("URL")
+            .doc("URL of an OpenAI-compatible embeddings endpoint (defaults to the OpenAI API)")
+            .env("DOKOSA_EMBEDDING_ENDPOINT")
+            .take(args)
+            .present_and_then(|a| a.value().parse())?;
+        let mut headers = Vec::new();
+        while let Some(a) = noargs::opt("embedding-header")
+            .ty("NAME:VALUE")
+            .doc(concat!(
+                "Extra HTTP header sent with embedding requests (can be used multiple times)\n",
+                "If not specified, the newline-separated headers in DOKOSA_EMBEDDING_HEADER are used"
+            ))
+            .take(args)
+            .present()
+        {
+            headers.push(a.then(|a| parse_header(a.value()))?);
+        }
+        if headers.is_empty()
+            && let Ok(value) = std::env::var("DOKOSA_EMBEDDING_HEADER")
+        {
+            for line in value.lines().filter(|l| !l.trim().is_empty()) {
+                let header = parse_header(line).map_err(|e| {
+                    noargs::Error::other(args, format!("invalid DOKOSA_EMBEDDING_HEADER: {e}"))
+                })?;
+                headers.push(header);
+            }
+        }
+        let max_retries: usize = noargs::opt("embedding-max-retries")
+            .ty("COUNT")
+            .doc("Maximum number of retries for transient embedding request failures")
+            .env("DOKOSA_EMBEDDING_MAX_RETRIES")
+            .default("5")
+            .take(args)
+            .then(|a| a.value().parse())?;
+        Ok(Self {
+            provider,
+            openai_api_key,
+            model,
+            dimensions,
+            endpoint,
+            headers,
+            max_retries,
+        })
+    }
+
+    /// Returns a copy of these options where unspecified settings are taken from those
+    /// recorded for the repository.
+    ///
+    /// It is an error to specify a provider or model different from the recorded one,
+    /// as the resulting embeddings would not be comparable with the indexed ones.
+    pub fn inherit(&self, repo: &RepositoryEntry) -> orfail::Result<Self> {
+        let mut options = self.clone();
+        inherit_setting(
+            "embedding provider",
+            &mut options.provider,
+            repo.embedding_provider,
+            repo,
+        )
+        .or_fail()?;
+        inherit_setting(
+            "embedding model",
+            &mut options.model,
+            repo.embedding_model.clone(),
+            repo,
+        )
+        .or_fail()?;
+        inherit_setting(
+            "embedding dimensions",
+            &mut options.dimensions,
+            repo.embedding_dimensions,
+            repo,
+        )
+        .or_fail()?;
+        if options.endpoint.is_none() {
+            options.endpoint = repo.embedding_endpoint.clone();
+        }
+        Ok(options)
+    }
+
+    /// Combines the settings inherited from two repositories, failing if they conflict.
+    pub fn merge(&mut self, other: &Self) -> Result<(), String> {
+        merge_setting("embedding provider", &mut self.provider, &other.provider)?;
+        merge_setting("embedding model", &mut self.model, &other.model)?;
+        merge_setting(
+            "embedding dimensions",
+            &mut self.dimensions,
+            &other.dimensions,
+        )?;
+        if self.endpoint.is_none() {
+            self.endpoint = other.endpoint.clone();
+        }
+        Ok(())
+    }
+
+    pub fn provider(&self) -> EmbeddingProviderKind {
+        self.provider.unwrap_or(EmbeddingProviderKind::OpenAi)
+    }
+
+    pub fn build(&self) -> orfail::Result<Box<dyn EmbeddingProvider>> {
+        match self.provider() {
+            EmbeddingProviderKind::OpenAi => {
+                let endpoint = self.endpoint.as_deref().unwrap_or(DEFAULT_OPENAI_ENDPOINT);
+                (self.openai_api_key.is_some() || endpoint != DEFAULT_OPENAI_ENDPOINT)
+                    .or_fail_with(|()| {
+                        "OpenAI API key is required (set --openai-api-key or OPENAI_API_KEY)"
+                            .to_owned()
+                    })?;
+                let retry_policy = RetryPolicy {
+                    max_retries: self.max_retries,
+                    ..RetryPolicy::default()
+                };
+                Ok(Box::new(
+                    OpenAiEmbedder::new(
+                        self.openai_api_key.clone(),
+                        self.model
+                            .clone()
+                            .unwrap_or_else(|| DEFAULT_OPENAI_MODEL.to_owned()),
+                        endpoint.to_owned(),
+                        self.headers.clone(),
+                    )
+                    .with_dimensions(self.dimensions)
+                    .with_retry_policy(retry_policy),
+                ))
+            }
+            EmbeddingProviderKind::Lexical => Ok(Box::new(LexicalEmbedder::new(
+                self.dimensions.unwrap_or(DEFAULT_LEXICAL_DIMENSION),
+            ))),
+        }
+    }
+}
+
+fn inherit_setting<T: PartialEq + std::fmt::Display>(
+    name: &str,
+    specified: &mut Option<T>,
+    stored: Option<T>,
+    repo: &RepositoryEntry,
+) -> orfail::Result<()> {
+    match (&*specified, stored) {
+        (Some(specified), Some(stored)) => (*specified == stored).or_fail_with(|()| {
+            format!(
+                "The {name} '{specified}' does not match '{stored}' used to index {}",
+                repo.path.display()
+            )
+        }),
+        (None, stored) => {
+            *specified = stored;
+            Ok(())
+        }
+        (Some(_), None) => Ok(()),
+    }
+}
+
+fn merge_setting<T: Clone + PartialEq + std::fmt::Display>(
+    name: &str,
+    setting: &mut Option<T>,
+    other: &Option<T>,
+) -> Result<(), String> {
+    match (&*setting, other) {
+        (Some(a), Some(b)) if a != b => Err(format!("{name} '{a}' vs '{b}'")),
+        (None, Some(b)) => {
+            *setting = Some(b.clone());
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns the dimension of the embeddings generated by the given embedder,
+/// sending a probe request if it is not known in advance.
+pub fn probe_dimension(embedder: &dyn EmbeddingProvider) -> orfail::Result<usize> {
+    if let Some(dimension) = embedder.dimension() {
+        return Ok(dimension);
+    }
+    let embeddings = embedder.embed(&["dimension probe".to_owned()]).or_fail()?;
+    Ok(embeddings.first().or_fail()?.0.len())
+}
+
 #[derive(Debug)]
-pub struct Embedder {
-    openai_api_key: String,
+pub struct OpenAiEmbedder {
+    openai_api_key: Option<String>,
     model: String,
+    dimensions: Option<usize>,
+    endpoint: String,
+    headers: Vec<String>,
+    retry_policy: RetryPolicy,
+    next_request_time: Cell<Option<Instant>>,
 }
 
-impl Embedder {
-    pub fn new(openai_api_key: String, model: String) -> Self {
+impl OpenAiEmbedder {
+    pub fn new(
+        openai_api_key: Option<String>,
+        model: String,
+        endpoint: String,
+        headers: Vec<String>,
+    ) -> Self {
         Self {
             openai_api_key,
             model,
+            dimensions: None,
+            endpoint,
+            headers,
+            retry_policy: RetryPolicy::default(),
+            next_request_time: Cell::new(None),
         }
     }
 
-    pub fn embed(&self, input_texts: &[String]) -> orfail::Result<Vec<Embedding>> {
-        let content = nojson::json(|f| {
-            f.object(|f| {
-                f.member("model", &self.model)?;
-                f.member("input", input_texts)
-            })
-        })
-        .to_string();
+    /// Requests shortened embeddings with the given number of dimensions
+    /// (supported by `text-embedding-3` and later models).
+    pub fn with_dimensions(mut self, dimensions: Option<usize>) -> Self {
+        self.dimensions = dimensions;
+        self
+    }
 
-        let mut cmd = std::process::Command::new("curl");
-        cmd.arg("https://api.openai.com/v1/embeddings")
-            .arg("-H")
-            .arg(format!("Authorization: Bearer {}", self.openai_api_key))
-            .arg("-H")
-            .arg("Content-Type: application/json")
-            .arg("-d")
-            .arg(&content)
-            .arg("--silent")
-            .arg("--show-error");
-
-        let output = cmd.output().or_fail()?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(orfail::Failure::new(format!(
-                "curl command failed: {}",
-                stderr
-            )));
-        }
-
-        let response = String::from_utf8(output.stdout)
-            .or_fail_with(|e| format!("Failed to parse curl response as UTF-8: {e}"))?;
-        let response = nojson::RawJson::parse(&response).or_fail()?;
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    fn parse_response(&self, input_count: usize, response: &str) -> orfail::Result<Vec<Embedding>> {
+        let response = nojson::RawJson::parse(response).or_fail()?;
         let ([data], []) = response
             .value()
             .to_fixed_object(["data"], [])
@@ -54,7 +344,7 @@ impl Embedder {
                 format!("Unexpected embeddings API response: {e}\n\nJSON:\n{response}\n")
             })?;
 
-        let mut embeddings = vec![Embedding::default(); input_texts.len()];
+        let mut embeddings = vec![Embedding::default(); input_count];
         for object in data.to_array().or_fail()? {
             let ([index, embedding], []) = object
                 .to_fixed_object(["index", "embedding"], [])
@@ -69,6 +359,270 @@ impl Embedder {
     }
 }
 
+impl EmbeddingProvider for OpenAiEmbedder {
+    fn kind(&self) -> EmbeddingProviderKind {
+        EmbeddingProviderKind::OpenAi
+    }
+
+    fn endpoint(&self) -> Option<&str> {
+        Some(&self.endpoint)
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        if self.dimensions.is_some() {
+            return self.dimensions;
+        }
+        match self.model.as_str() {
+            "text-embedding-3-small" | "text-embedding-ada-002" => Some(1536),
+            "text-embedding-3-large" => Some(3072),
+            _ => None,
+        }
+    }
+
+    fn embed(&self, input_texts: &[String]) -> orfail::Result<Vec<Embedding>> {
+        let content = nojson::json(|f| {
+            f.object(|f| {
+                f.member("model", &self.model)?;
+                if let Some(dimensions) = self.dimensions {
+                    f.member("dimensions", dimensions)?;
+                }
+                f.member("input", input_texts)
+            })
+        })
+        .to_string();
+
+        let mut headers = vec!["Content-Type: application/json".to_owned()];
+        if let Some(api_key) = &self.openai_api_key {
+            headers.push(format!("Authorization: Bearer {api_key}"));
+        }
+        headers.extend(self.headers.iter().cloned());
+
+        let mut retries = 0;
+        loop {
+            if let Some(time) = self.next_request_time.take() {
+                std::thread::sleep(time.saturating_duration_since(Instant::now()));
+            }
+
+            let (reason, delay) =
+                match crate::http::post(&self.endpoint, &headers, &content).or_fail()? {
+                    Ok(response) if response.is_success() => {
+                        self.next_request_time.set(
+                            rate_limit_delay(&response, false).map(|delay| Instant::now() + delay),
+                        );
+                        return self.parse_response(input_texts.len(), &response.body);
+                    }
+                    Ok(response) if is_retryable_status(response.status) => (
+                        format!("HTTP {}: {}", response.status, response.body.trim()),
+                        rate_limit_delay(&response, true),
+                    ),
+                    Ok(response) => {
+                        return Err(orfail::Failure::new(format!(
+                            "Embeddings API returned HTTP {}: {}",
+                            response.status, response.body
+                        )));
+                    }
+                    Err(e) if e.is_transient() => (e.to_string(), None),
+                    Err(e) => return Err(orfail::Failure::new(e.to_string())),
+                };
+
+            (retries < self.retry_policy.max_retries)
+                .or_fail_with(|()| format!("Gave up after {retries} retries: {reason}"))?;
+            let delay = delay.unwrap_or_else(|| self.retry_policy.backoff(retries));
+            eprintln!("  Retrying in {:.1}s: {reason}", delay.as_secs_f64());
+            std::thread::sleep(delay);
+            retries += 1;
+        }
+    }
+}
+
+/// Validates a header in the form `NAME: VALUE`.
+fn parse_header(header: &str) -> Result<String, &'static str> {
+    match header.split_once(':') {
+        Some((name, _)) if !name.trim().is_empty() => Ok(header.to_owned()),
+        _ => Err("expected a header in the form 'NAME: VALUE'"),
+    }
+}
+
+/// Policy for retrying transient embedding request failures.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the given (zero-origin) retry, using exponential backoff with jitter.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let exp = self
+            .initial_backoff
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max_backoff);
+        exp.mul_f64(0.5 + random_unit() / 2.0)
+    }
+}
+
+/// Returns a pseudo-random number in `[0, 1)` for the backoff jitter.
+///
+/// This is SplitMix64 over a process-wide sequence seeded from the clock and the process ID,
+/// so that processes retrying at the same time spread out their requests.
+fn random_unit() -> f64 {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(1);
+
+    let seed = *SEED.get_or_init(|| {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        nanos ^ (u64::from(std::process::id()) << 32)
+    });
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut z = seed.wrapping_add(n.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+fn is_retryable_status(status: u16) -> bool {
+    status == 408 || status == 429 || status >= 500
+}
+
+/// Returns how long to wait before sending the next request, as instructed by the response headers.
+///
+/// `Retry-After` (and its millisecond variant) is only honoured for failed requests,
+/// while the `x-ratelimit-*` headers are also used to proactively throttle after successful ones.
+fn rate_limit_delay(response: &HttpResponse, failed: bool) -> Option<Duration> {
+    if failed {
+        if let Some(ms) = response
+            .header("retry-after-ms")
+            .and_then(|v| v.parse::<f64>().ok())
+        {
+            return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+        }
+        if let Some(value) = response.header("retry-after") {
+            if let Ok(secs) = value.parse::<f64>() {
+                return Some(Duration::from_secs_f64(secs.max(0.0)));
+            }
+            if let Some(time) = parse_http_date(value) {
+                return Some(
+                    time.duration_since(SystemTime::now())
+                        .unwrap_or(Duration::ZERO),
+                );
+            }
+        }
+    }
+
+    ["requests", "tokens"]
+        .into_iter()
+        .filter(|kind| {
+            response
+                .header(&format!("x-ratelimit-remaining-{kind}"))
+                .is_some_and(|v| v.trim() == "0")
+        })
+        .filter_map(|kind| {
+            response
+                .header(&format!("x-ratelimit-reset-{kind}"))
+                .and_then(parse_rate_limit_duration)
+        })
+        .max()
+}
+
+/// Parses durations such as "1s", "6m0s", "1h2m3.5s" or "20ms" used by `x-ratelimit-reset-*` headers.
+fn parse_rate_limit_duration(s: &str) -> Option<Duration> {
+    let mut s = s.trim();
+    let mut total = 0.0;
+    while !s.is_empty() {
+        let number_len = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let number: f64 = s[..number_len].parse().ok()?;
+        s = &s[number_len..];
+
+        let unit_len = s
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(s.len());
+        total += number
+            * match &s[..unit_len] {
+                "h" => 3600.0,
+                "m" => 60.0,
+                "s" | "" => 1.0,
+                "ms" => 0.001,
+                _ => return None,
+            };
+        s = &s[unit_len..];
+    }
+    Some(Duration::from_secs_f64(total))
+}
+
+/// Parses an HTTP date such as "Sun, 06 Nov 1994 08:49:37 GMT" used by `Retry-After`.
+///
+/// The obsolete RFC 850 ("Sunday, 06-Nov-94 08:49:37 GMT") and asctime
+/// ("Sun Nov  6 08:49:37 1994") formats are also accepted, as HTTP recipients must.
+fn parse_http_date(s: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let mut month = None;
+    let mut time = None;
+    let mut numbers = Vec::new();
+    for token in s
+        .split(|c: char| c.is_ascii_whitespace() || c == ',' || c == '-')
+        .filter(|t| !t.is_empty())
+    {
+        if let Some(i) = MONTHS.iter().position(|m| *m == token) {
+            month = Some(i as u64 + 1);
+        } else if token.contains(':') {
+            let mut parts = token.split(':').map(|p| p.parse::<u64>().ok());
+            let (h, m, s) = (parts.next()??, parts.next()??, parts.next()??);
+            (parts.next().is_none() && h < 24 && m < 60 && s < 61).then_some(())?;
+            time = Some(h * 3600 + m * 60 + s);
+        } else if token.bytes().all(|b| b.is_ascii_digit()) {
+            numbers.push(token.parse::<u64>().ok()?);
+        }
+    }
+
+    // The day comes before the year in all formats
+    let [day, year] = numbers[..] else {
+        return None;
+    };
+    let year = match year {
+        0..70 => year + 2000,
+        70..100 => year + 1900,
+        _ => year,
+    };
+    let (month, time) = (month?, time?);
+    (1..=31).contains(&day).then_some(())?;
+
+    // Days since the epoch of the proleptic Gregorian calendar date (Howard Hinnant's algorithm)
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let year_of_era = y % 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(days * 86_400 + time))
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Embedding(pub Vec<f64>);
 
@@ -85,3 +639,251 @@ impl<'text> nojson::FromRawJsonValue<'text> for Embedding {
         value.try_to().map(Self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    use super::*;
+    use crate::test_util;
+
+    const OK: &str = "200 OK";
+    const EMBEDDINGS: &str =
+        r#"{"data":[{"index":1,"embedding":[0.5,1.5]},{"index":0,"embedding":[1.0,2.0]}]}"#;
+
+    /// Starts an HTTP server that answers each request with the next response
+    /// (a status line optionally followed by extra header lines, and a body),
+    /// and returns its URL together with a handle yielding the raw requests received.
+    fn spawn_mock_server(
+        responses: Vec<(&'static str, &'static str)>,
+    ) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let url = format!(
+            "http://{}/v1/embeddings",
+            listener.local_addr().expect("addr")
+        );
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (head, body) in responses {
+                let (stream, _) = listener.accept().expect("accept");
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).expect("read");
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        content_length = value.trim().parse().expect("content-length");
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut content = vec![0; content_length];
+                reader.read_exact(&mut content).expect("read body");
+                request.push_str(&String::from_utf8(content).expect("utf-8"));
+                requests.push(request);
+
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {head}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .expect("write");
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn openai_embedder_uses_custom_endpoint_and_headers() {
+        let (url, server) = spawn_mock_server(vec![(OK, EMBEDDINGS)]);
+        let embedder = OpenAiEmbedder::new(
+            None,
+            "local-model".to_owned(),
+            url,
+            vec!["X-Team: search".to_owned()],
+        );
+
+        let embeddings = embedder
+            .embed(&["foo".to_owned(), "bar".to_owned()])
+            .expect("embed");
+        assert_eq!(embeddings[0].0, [1.0, 2.0]);
+        assert_eq!(embeddings[1].0, [0.5, 1.5]);
+
+        let requests = server.join().expect("join");
+        assert!(requests[0].starts_with("POST /v1/embeddings "));
+        assert!(requests[0].contains("X-Team: search\r\n"));
+        assert!(!requests[0].contains("Authorization:"));
+        assert!(requests[0].contains(r#""model":"local-model""#));
+        assert!(requests[0].contains(r#""input":["foo","bar"]"#));
+    }
+
+    #[test]
+    fn openai_embedder_requests_shortened_embeddings() {
+        let (url, server) = spawn_mock_server(vec![(OK, EMBEDDINGS)]);
+        let embedder =
+            OpenAiEmbedder::new(None, "m".to_owned(), url, Vec::new()).with_dimensions(Some(2));
+        assert_eq!(embedder.dimension(), Some(2));
+
+        embedder
+            .embed(&["foo".to_owned(), "bar".to_owned()])
+            .expect("embed");
+        let requests = server.join().expect("join");
+        assert!(requests[0].contains(r#""dimensions":2"#));
+    }
+
+    fn embedder_with_retries(url: String, max_retries: usize) -> OpenAiEmbedder {
+        OpenAiEmbedder::new(None, "local-model".to_owned(), url, Vec::new()).with_retry_policy(
+            RetryPolicy {
+                max_retries,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(10),
+            },
+        )
+    }
+
+    #[test]
+    fn openai_embedder_retries_transient_failures() {
+        let (url, server) = spawn_mock_server(vec![
+            ("429 Too Many Requests\r\nRetry-After: 0", "{}"),
+            ("503 Service Unavailable", "{}"),
+            (OK, EMBEDDINGS),
+        ]);
+        let embeddings = embedder_with_retries(url, 2)
+            .embed(&["foo".to_owned(), "bar".to_owned()])
+            .expect("embed");
+        assert_eq!(embeddings[0].0, [1.0, 2.0]);
+        assert_eq!(server.join().expect("join").len(), 3);
+    }
+
+    #[test]
+    fn openai_embedder_gives_up_after_max_retries() {
+        let (url, server) = spawn_mock_server(vec![
+            ("500 Internal Server Error", "{}"),
+            ("500 Internal Server Error", "{}"),
+        ]);
+        let result = embedder_with_retries(url, 1).embed(&["foo".to_owned()]);
+        assert!(result.is_err());
+        assert_eq!(server.join().expect("join").len(), 2);
+    }
+
+    #[test]
+    fn openai_embedder_does_not_retry_client_errors() {
+        let (url, server) = spawn_mock_server(vec![("400 Bad Request", "{}")]);
+        let result = embedder_with_retries(url, 3).embed(&["foo".to_owned()]);
+        assert!(result.is_err());
+        assert_eq!(server.join().expect("join").len(), 1);
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_with_jitter() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        };
+        for (retry, max) in [(0, 1), (1, 2), (2, 4), (3, 8), (8, 30)] {
+            let delay = policy.backoff(retry);
+            let max = Duration::from_secs(max);
+            assert!(max / 2 <= delay && delay <= max, "{retry}: {delay:?}");
+        }
+    }
+
+    #[test]
+    fn parse_http_dates() {
+        let parse =
+            |s| parse_http_date(s).map(|t| t.duration_since(UNIX_EPOCH).expect("epoch").as_secs());
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), Some(784111777));
+        assert_eq!(parse("Thu, 29 Feb 2024 00:00:00 GMT"), Some(1709164800));
+        assert_eq!(parse("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse("120"), None);
+    }
+
+    #[test]
+    fn parse_rate_limit_durations() {
+        let parse = |s| parse_rate_limit_duration(s).map(|d| d.as_secs_f64());
+        assert_eq!(parse("1s"), Some(1.0));
+        assert_eq!(parse("6m0s"), Some(360.0));
+        assert_eq!(parse("1h2m3.5s"), Some(3723.5));
+        assert_eq!(parse("20ms"), Some(0.02));
+        assert_eq!(parse("2"), Some(2.0));
+        assert_eq!(parse("1x"), None);
+    }
+
+    fn repository_entry(
+        provider: Option<EmbeddingProviderKind>,
+        model: Option<&str>,
+    ) -> RepositoryEntry {
+        RepositoryEntry {
+            embedding_provider: provider,
+            embedding_model: model.map(|m| m.to_owned()),
+            embedding_endpoint: Some("http://localhost:8080/v1/embeddings".to_owned()),
+            ..test_util::repository_entry()
+        }
+    }
+
+    fn options(provider: Option<EmbeddingProviderKind>, model: Option<&str>) -> EmbedderOptions {
+        EmbedderOptions {
+            provider,
+            openai_api_key: None,
+            model: model.map(|m| m.to_owned()),
+            dimensions: None,
+            endpoint: None,
+            headers: Vec::new(),
+            max_retries: 0,
+        }
+    }
+
+    #[test]
+    fn inherit_recorded_embedding_settings() {
+        let repo = repository_entry(Some(EmbeddingProviderKind::OpenAi), Some("local-model"));
+        let inherited = options(None, None).inherit(&repo).expect("inherit");
+        assert_eq!(inherited.provider, Some(EmbeddingProviderKind::OpenAi));
+        assert_eq!(inherited.model.as_deref(), Some("local-model"));
+        assert_eq!(inherited.endpoint, repo.embedding_endpoint);
+
+        // Repositories indexed before the settings were recorded accept anything.
+        let legacy = repository_entry(None, None);
+        let inherited = options(None, Some("other-model"))
+            .inherit(&legacy)
+            .expect("inherit");
+        assert_eq!(inherited.model.as_deref(), Some("other-model"));
+    }
+
+    #[test]
+    fn reject_mismatched_embedding_settings() {
+        let repo = repository_entry(Some(EmbeddingProviderKind::OpenAi), Some("local-model"));
+        assert!(options(None, Some("other-model")).inherit(&repo).is_err());
+        assert!(
+            options(Some(EmbeddingProviderKind::Lexical), None)
+                .inherit(&repo)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn merge_settings_of_repositories() {
+        let a = repository_entry(Some(EmbeddingProviderKind::OpenAi), Some("model-a"));
+        let b = repository_entry(None, None);
+        let c = repository_entry(Some(EmbeddingProviderKind::OpenAi), Some("model-c"));
+
+        let mut merged = options(None, None).inherit(&b).expect("inherit");
+        merged
+            .merge(&options(None, None).inherit(&a).expect("inherit"))
+            .expect("merge");
+        assert_eq!(merged.model.as_deref(), Some("model-a"));
+
+        let error = merged
+            .merge(&options(None, None).inherit(&c).expect("inherit"))
+            .expect_err("conflict");
+        assert!(error.contains("'model-a' vs 'model-c'"), "{error}");
+    }
+}
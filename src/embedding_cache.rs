@@ -0,0 +1,181 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use orfail::OrFail;
+
+use crate::{
+    chunker::ContentHash,
+    embedder::{Embedding, EmbeddingProvider},
+};
+
+/// A persistent cache of embeddings keyed by model and content hash.
+///
+/// The cache file is a JSON Lines file where each line holds a single embedding.
+/// Only the keys and line offsets are kept in memory; embeddings are read on demand.
+#[derive(Debug)]
+pub struct EmbeddingCache {
+    path: PathBuf,
+    offsets: HashMap<String, HashMap<ContentHash, u64>>,
+    end_offset: u64,
+    pub hits: usize,
+}
+
+impl EmbeddingCache {
+    pub fn open<P: AsRef<Path>>(path: P) -> orfail::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .or_fail_with(|e| format!("Failed to open {}: {e}", path.display()))?;
+
+        let mut offsets: HashMap<String, HashMap<_, _>> = HashMap::new();
+        let mut reader = BufReader::new(file);
+        let mut offset = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let size = reader.read_line(&mut line).or_fail()?;
+            if size == 0 {
+                break;
+            }
+            if line.ends_with('\n') {
+                let (model, hash) = parse_key(&line).or_fail_with(|e| {
+                    format!("Broken embedding cache file {}: {e}", path.display())
+                })?;
+                offsets.entry(model).or_default().insert(hash, offset);
+            }
+            offset += size as u64;
+        }
+
+        Ok(Self {
+            path,
+            offsets,
+            end_offset: offset,
+            hits: 0,
+        })
+    }
+
+    pub fn get(&mut self, model: &str, hash: ContentHash) -> orfail::Result<Option<Embedding>> {
+        let Some(&offset) = self.offsets.get(model).and_then(|m| m.get(&hash)) else {
+            return Ok(None);
+        };
+
+        let mut file = std::fs::File::open(&self.path).or_fail()?;
+        file.seek(SeekFrom::Start(offset)).or_fail()?;
+        let mut line = String::new();
+        BufReader::new(file).read_line(&mut line).or_fail()?;
+
+        let json = nojson::RawJson::parse(line.trim_end()).or_fail()?;
+        let ([embedding], []) = json.value().to_fixed_object(["embedding"], []).or_fail()?;
+        self.hits += 1;
+        embedding.try_to().map(Some).or_fail()
+    }
+
+    pub fn insert(
+        &mut self,
+        model: &str,
+        hash: ContentHash,
+        embedding: &Embedding,
+    ) -> orfail::Result<()> {
+        let line = nojson::json(|f| {
+            f.object(|f| {
+                f.member("model", model)?;
+                f.member("hash", hash)?;
+                f.member("embedding", embedding)
+            })
+        })
+        .to_string();
+
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .or_fail()?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "{line}").or_fail()?;
+        writer.flush().or_fail()?;
+
+        self.offsets
+            .entry(model.to_owned())
+            .or_default()
+            .insert(hash, self.end_offset);
+        self.end_offset += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// Returns the key identifying embeddings that are interchangeable with those of the given embedder.
+///
+/// The provider and endpoint are part of the key, as different services may serve
+/// different models under the same name.
+pub fn model_key(embedder: &dyn EmbeddingProvider) -> String {
+    let mut key = embedder.kind().to_string();
+    if let Some(endpoint) = embedder.endpoint() {
+        key.push('@');
+        key.push_str(endpoint);
+    }
+    key.push(':');
+    key.push_str(embedder.model());
+    if let Some(dimension) = embedder.dimension() {
+        key.push('/');
+        key.push_str(&dimension.to_string());
+    }
+    key
+}
+
+fn parse_key(line: &str) -> Result<(String, ContentHash), nojson::JsonParseError> {
+    let json = nojson::RawJson::parse(line.trim_end())?;
+    let ([model, hash], []) = json.value().to_fixed_object(["model", "hash"], [])?;
+    Ok((model.try_to()?, hash.try_to()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        embedder::OpenAiEmbedder, lexical_embedder::LexicalEmbedder, test_util::temp_path,
+    };
+
+    #[test]
+    fn cached_embeddings_survive_reopening() {
+        let path = temp_path("embedding-cache");
+        let foo = ContentHash::of("foo");
+        let bar = ContentHash::of("bar");
+
+        let mut cache = EmbeddingCache::open(&path).expect("open");
+        cache
+            .insert("m1", foo, &Embedding(vec![1.0, 2.0]))
+            .expect("insert");
+        cache
+            .insert("m2", bar, &Embedding(vec![3.0]))
+            .expect("insert");
+        assert_eq!(
+            cache.get("m1", foo).expect("get").expect("hit").0,
+            [1.0, 2.0]
+        );
+
+        let mut cache = EmbeddingCache::open(&path).expect("reopen");
+        assert_eq!(cache.get("m2", bar).expect("get").expect("hit").0, [3.0]);
+        assert!(cache.get("m1", bar).expect("get").is_none());
+        assert!(cache.get("m2", foo).expect("get").is_none());
+        assert_eq!(cache.hits, 1);
+
+        std::fs::remove_file(&path).expect("remove");
+    }
+
+    #[test]
+    fn model_keys_distinguish_providers_and_endpoints() {
+        let openai = |endpoint: &str| {
+            OpenAiEmbedder::new(None, "m".to_owned(), endpoint.to_owned(), Vec::new())
+        };
+        let local = model_key(&openai("http://localhost/v1/embeddings"));
+        let remote = model_key(&openai("https://example.com/v1/embeddings"));
+        assert_eq!(local, "openai@http://localhost/v1/embeddings:m");
+        assert_ne!(local, remote);
+        assert_eq!(model_key(&LexicalEmbedder::new(8)), "lexical:lexical-v1/8");
+    }
+}
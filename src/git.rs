@@ -95,6 +95,36 @@ impl GitRepository {
         Ok(files)
     }
 
+    /// Get the tracked files with uncommitted changes in the working tree or the index
+    pub fn modified_files(&self) -> orfail::Result<Vec<PathBuf>> {
+        let output = Command::new("git")
+            .args([
+                "-C",
+                self.root_dir.to_str().unwrap_or(""),
+                "diff",
+                "--name-only",
+                "HEAD",
+            ])
+            .output()
+            .or_fail_with(|e| format!("Failed to execute git diff --name-only: {e}"))?;
+
+        output.status.success().or_fail_with(|()| {
+            format!(
+                "Git diff command failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )
+        })?;
+
+        let files_str = String::from_utf8(output.stdout).or_fail()?;
+        let files: Vec<PathBuf> = files_str
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| PathBuf::from(line.trim()))
+            .collect();
+
+        Ok(files)
+    }
+
     pub fn diff_files(
         &self,
         old_commit_hash: &str,
@@ -0,0 +1,959 @@
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use orfail::OrFail;
+
+use crate::{
+    chunker::ContentHash,
+    embedder::Embedding,
+    glob::GlobPathFilter,
+    index_file::{
+        BINARY_HEADER_SIZE, ChunkEntry, IndexFile, IndexFormat, MatchedChunk,
+        RECORD_KIND_REPOSITORY, Record, RepositoryEntry, unit_cosine_similarity, unit_query_vector,
+    },
+    mmap::MappedFile,
+};
+
+/// Default maximum number of neighbours per node (doubled on the bottom layer).
+pub const DEFAULT_HNSW_M: usize = 16;
+
+/// [`DEFAULT_HNSW_M`] as the default value of a command-line option.
+pub const DEFAULT_HNSW_M_STR: &str = "16";
+
+/// Default size of the dynamic candidate list used when searching.
+pub const DEFAULT_EF_SEARCH: usize = 64;
+
+/// [`DEFAULT_EF_SEARCH`] as the default value of a command-line option.
+pub const DEFAULT_EF_SEARCH_STR: &str = "64";
+
+const EF_CONSTRUCTION: usize = 100;
+
+const MAX_LEVEL: usize = 16;
+
+const MAGIC: &[u8; 8] = b"DOKOSAHN";
+
+const VERSION: u32 = 1;
+
+/// An approximate nearest neighbour graph (Hierarchical Navigable Small World) over
+/// the chunk vectors of a binary index file.
+///
+/// The graph is stored in a sidecar file next to the index file and refers to chunks by
+/// the offsets of their records, so vectors are read from the (memory-mapped) index file itself.
+/// Each node also has a fingerprint of its repository, path, line and content hash,
+/// which is used to carry nodes over when the index file is rewritten by `sync` or `remove`.
+///
+/// The length and modification time of the index file are recorded to detect changes to it.
+#[derive(Debug, Clone)]
+pub struct HnswIndex {
+    graph: Graph,
+    index_len: u64,
+    index_modified: u64,
+    repository_offsets: Vec<u64>,
+    offsets: Vec<u64>,
+    keys: Vec<u64>,
+}
+
+impl HnswIndex {
+    pub fn new(m: usize) -> Self {
+        Self {
+            graph: Graph {
+                m: m.max(2),
+                rng_state: 0,
+                entry_point: None,
+                links: Vec::new(),
+            },
+            index_len: 0,
+            index_modified: 0,
+            repository_offsets: Vec::new(),
+            offsets: Vec::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    /// Returns the path of the sidecar file of the given index file (e.g., `.dokosa.hnsw`).
+    pub fn sidecar_path<P: AsRef<Path>>(index_file_path: P) -> PathBuf {
+        let mut path = index_file_path.as_ref().as_os_str().to_owned();
+        path.push(".hnsw");
+        PathBuf::from(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Updates the sidecar file of the index file if it exists.
+    ///
+    /// If it does not exist and `create_m` is specified, a new graph is built with that `M`.
+    pub fn update_sidecar(index_file: &IndexFile, create_m: Option<usize>) -> orfail::Result<()> {
+        let path = Self::sidecar_path(&index_file.path);
+        let mut hnsw = match Self::load(&path).or_fail()? {
+            Some(hnsw) => hnsw,
+            None => match create_m {
+                Some(m) => Self::new(m),
+                None => return Ok(()),
+            },
+        };
+        hnsw.update(index_file).or_fail()?;
+        hnsw.save(&path).or_fail()?;
+        eprintln!("=> Updated HNSW index ({} chunk(s))", hnsw.len());
+        Ok(())
+    }
+
+    /// Returns `true` if the graph reflects the current content of the index file.
+    pub fn is_up_to_date(&self, index_file: &IndexFile) -> orfail::Result<bool> {
+        let metadata = std::fs::metadata(&index_file.path).or_fail()?;
+        Ok(index_file.format == IndexFormat::Binary
+            && metadata.len() == self.index_len
+            && modified_nanos(&metadata) == self.index_modified)
+    }
+
+    /// Brings the graph in line with the current content of the index file.
+    ///
+    /// Nodes whose chunks are still in the index file are kept (with their neighbours
+    /// repaired if some of them have gone), and only the new chunks are inserted.
+    pub fn update(&mut self, index_file: &IndexFile) -> orfail::Result<()> {
+        (index_file.format == IndexFormat::Binary)
+            .or_fail_with(|()| "HNSW index requires a binary index file".to_owned())?;
+        let metadata = std::fs::metadata(&index_file.path).or_fail()?;
+        let file = MappedFile::open(&index_file.path).or_fail()?;
+        let data = file.as_bytes();
+
+        let mut repository_offsets = Vec::new();
+        let mut offsets = Vec::new();
+        let mut keys = Vec::new();
+        let mut repository_path = None;
+        let mut dimension = None;
+        let mut offset = BINARY_HEADER_SIZE;
+        while let Some((record, next)) = Record::parse(data, offset).or_fail()? {
+            if record.kind == RECORD_KIND_REPOSITORY {
+                repository_offsets.push(offset as u64);
+                repository_path = Some(record.parse_repository().or_fail()?.path);
+            } else {
+                let repository_path = repository_path.as_ref().or_fail()?;
+                let chunk = record.parse_chunk_metadata().or_fail()?;
+
+                // Similarities between vectors of different dimensions are meaningless
+                let expected = *dimension.get_or_insert(record.vector_len);
+                (record.vector_len == expected).or_fail_with(|()| {
+                    format!(
+                        "HNSW index requires all vectors to have the same dimension, \
+                         but {} in {} has {} instead of {expected}",
+                        chunk.path.display(),
+                        repository_path.display(),
+                        record.vector_len
+                    )
+                })?;
+                offsets.push(offset as u64);
+                keys.push(chunk_key(repository_path, &chunk));
+            }
+            offset = next;
+        }
+
+        let old_nodes = self
+            .keys
+            .iter()
+            .enumerate()
+            .map(|(i, &key)| (key, i))
+            .collect::<HashMap<_, _>>();
+        let mut mapping = vec![None; self.keys.len()];
+        for (new, key) in keys.iter().enumerate() {
+            if let Some(&old) = old_nodes.get(key) {
+                mapping[old].get_or_insert(new as u32);
+            }
+        }
+
+        self.index_len = data.len() as u64;
+        self.index_modified = modified_nanos(&metadata);
+        self.repository_offsets = repository_offsets;
+        self.offsets = offsets;
+        self.keys = keys;
+        let vectors = Vectors::new(data, &self.offsets);
+        self.graph.remap(&vectors, &mapping).or_fail()?;
+        Ok(())
+    }
+
+    pub fn search(
+        &self,
+        index_file: &IndexFile,
+        query: &Embedding,
+        count: usize,
+        similarity_threshold: f64,
+        filter: &GlobPathFilter,
+        ef_search: usize,
+    ) -> orfail::Result<Vec<MatchedChunk>> {
+        let file = MappedFile::open(&index_file.path).or_fail()?;
+        let data = file.as_bytes();
+        (data.len() as u64 == self.index_len)
+            .or_fail_with(|()| "HNSW index is out of date".to_owned())?;
+        let Some(entry_point) = self.graph.entry_point else {
+            return Ok(Vec::new());
+        };
+
+        let vectors = Vectors::new(data, &self.offsets);
+        let query = unit_query_vector(query);
+        let dimension = vectors.with_vector(entry_point, |v, _| v.len()).or_fail()?;
+        (dimension == query.len()).or_fail_with(|()| {
+            format!(
+                "Embedding dimension mismatch: query has {} but the index has {dimension}",
+                query.len()
+            )
+        })?;
+
+        // The filter is applied to the nearest nodes found, so the search is widened
+        // until enough of them pass it (or all nodes are within reach)
+        let mut repositories = HashMap::<u64, RepositoryEntry>::new();
+        let mut ef = ef_search.max(count);
+        loop {
+            let nearest = self.graph.search(&vectors, &query, ef).or_fail()?;
+            let mut matched = Vec::new();
+            let mut below_threshold = false;
+            for Scored { similarity, node } in nearest {
+                if similarity < similarity_threshold {
+                    below_threshold = true;
+                    break;
+                }
+                if matched.len() == count {
+                    break;
+                }
+
+                let offset = self.offsets[node as usize];
+                let (record, _) = Record::parse(data, offset as usize).or_fail()?.or_fail()?;
+                let chunk = record.parse_chunk_metadata().or_fail()?;
+                let i = self.repository_offsets.partition_point(|&o| o < offset);
+                let repository_offset = *i
+                    .checked_sub(1)
+                    .and_then(|i| self.repository_offsets.get(i))
+                    .or_fail()?;
+                let repository = match repositories.entry(repository_offset) {
+                    std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                    std::collections::hash_map::Entry::Vacant(e) => {
+                        let (record, _) = Record::parse(data, repository_offset as usize)
+                            .or_fail()?
+                            .or_fail()?;
+                        e.insert(record.parse_repository().or_fail()?)
+                    }
+                };
+                if !filter.matches(repository.path.join(&chunk.path)) {
+                    continue;
+                }
+                matched.push(MatchedChunk {
+                    repository_path: repository.path.clone(),
+                    line_count: chunk.line_count.unwrap_or(repository.chunk_window_size),
+                    columns: chunk.columns,
+                    file_path: chunk.path,
+                    line: chunk.line,
+                    hash: chunk.hash,
+                    similarity,
+                    vector: vectors.unit_vector(node).or_fail()?,
+                });
+            }
+            if matched.len() == count || below_threshold || ef >= self.len() {
+                return Ok(matched);
+            }
+            ef = ef.saturating_mul(2).min(self.len());
+        }
+    }
+
+    /// Loads a sidecar file, returning `None` if it does not exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> orfail::Result<Option<Self>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = std::fs::read(path).or_fail()?;
+        Self::decode(&data)
+            .or_fail_with(|e| format!("Invalid HNSW index file {}: {e}", path.display()))
+            .map(Some)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> orfail::Result<()> {
+        let path = path.as_ref();
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".temp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut file = std::fs::File::create(&temp_path).or_fail()?;
+        file.write_all(&self.encode()).or_fail()?;
+        std::fs::rename(&temp_path, path).or_fail()?;
+        Ok(())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&(self.graph.m as u32).to_le_bytes());
+        buf.extend_from_slice(&self.graph.rng_state.to_le_bytes());
+        buf.extend_from_slice(&self.index_len.to_le_bytes());
+        buf.extend_from_slice(&self.index_modified.to_le_bytes());
+        let entry_point = self.graph.entry_point.unwrap_or(u32::MAX);
+        buf.extend_from_slice(&entry_point.to_le_bytes());
+
+        buf.extend_from_slice(&(self.repository_offsets.len() as u32).to_le_bytes());
+        for offset in &self.repository_offsets {
+            buf.extend_from_slice(&offset.to_le_bytes());
+        }
+
+        buf.extend_from_slice(&(self.offsets.len() as u32).to_le_bytes());
+        for ((offset, key), levels) in self.offsets.iter().zip(&self.keys).zip(&self.graph.links) {
+            buf.extend_from_slice(&offset.to_le_bytes());
+            buf.extend_from_slice(&key.to_le_bytes());
+            buf.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+            for neighbors in levels {
+                buf.extend_from_slice(&(neighbors.len() as u32).to_le_bytes());
+                for neighbor in neighbors {
+                    buf.extend_from_slice(&neighbor.to_le_bytes());
+                }
+            }
+        }
+        buf
+    }
+
+    fn decode(data: &[u8]) -> orfail::Result<Self> {
+        let mut reader = ByteReader { data, pos: 0 };
+        (reader.bytes(MAGIC.len()).or_fail()? == MAGIC)
+            .or_fail_with(|()| "bad magic bytes".to_owned())?;
+        let version = reader.u32().or_fail()?;
+        (version == VERSION).or_fail_with(|()| format!("unsupported version {version}"))?;
+
+        let m = reader.u32().or_fail()? as usize;
+        let rng_state = reader.u64().or_fail()?;
+        let index_len = reader.u64().or_fail()?;
+        let index_modified = reader.u64().or_fail()?;
+        let entry_point = Some(reader.u32().or_fail()?).filter(|&n| n != u32::MAX);
+
+        let repository_count = reader.u32().or_fail()? as usize;
+        let repository_offsets = (0..repository_count)
+            .map(|_| reader.u64())
+            .collect::<orfail::Result<Vec<_>>>()
+            .or_fail()?;
+
+        let node_count = reader.u32().or_fail()? as usize;
+        let mut offsets = Vec::with_capacity(node_count);
+        let mut keys = Vec::with_capacity(node_count);
+        let mut links = Vec::with_capacity(node_count);
+        for _ in 0..node_count {
+            offsets.push(reader.u64().or_fail()?);
+            keys.push(reader.u64().or_fail()?);
+            let level_count = reader.u32().or_fail()? as usize;
+            let mut levels = Vec::with_capacity(level_count);
+            for _ in 0..level_count {
+                let neighbor_count = reader.u32().or_fail()? as usize;
+                let neighbors = (0..neighbor_count)
+                    .map(|_| reader.u32())
+                    .collect::<orfail::Result<Vec<_>>>()
+                    .or_fail()?;
+                (neighbors.iter().all(|&n| (n as usize) < node_count))
+                    .or_fail_with(|()| "neighbour out of range".to_owned())?;
+                levels.push(neighbors);
+            }
+            links.push(levels);
+        }
+        (reader.pos == data.len()).or_fail_with(|()| "trailing bytes".to_owned())?;
+        if let Some(entry_point) = entry_point {
+            (links
+                .get(entry_point as usize)
+                .is_some_and(|l: &Vec<_>| !l.is_empty()))
+            .or_fail_with(|()| "invalid entry point".to_owned())?;
+        }
+
+        Ok(Self {
+            graph: Graph {
+                m: m.max(2),
+                rng_state,
+                entry_point,
+                links,
+            },
+            index_len,
+            index_modified,
+            repository_offsets,
+            offsets,
+            keys,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Graph {
+    m: usize,
+    rng_state: u64,
+    entry_point: Option<u32>,
+
+    // Neighbours of each node on each of its levels
+    links: Vec<Vec<Vec<u32>>>,
+}
+
+impl Graph {
+    /// Moves the nodes to their new positions (`mapping[old] = Some(new)`) and inserts the rest.
+    fn remap(&mut self, vectors: &Vectors, mapping: &[Option<u32>]) -> orfail::Result<()> {
+        let old_links = std::mem::take(&mut self.links);
+        let old_entry_point = self.entry_point.take();
+        self.links = vec![Vec::new(); vectors.offsets.len()];
+
+        for (old, new) in mapping.iter().enumerate() {
+            let Some(new) = *new else {
+                continue;
+            };
+            let mut levels = Vec::with_capacity(old_links[old].len());
+            for (level, old_neighbors) in old_links[old].iter().enumerate() {
+                let mut neighbors = old_neighbors
+                    .iter()
+                    .filter_map(|&n| mapping[n as usize])
+                    .collect::<Vec<_>>();
+                if neighbors.len() < old_neighbors.len() {
+                    // Repair the list using the neighbours of the removed neighbours
+                    for &removed in old_neighbors
+                        .iter()
+                        .filter(|&&n| mapping[n as usize].is_none())
+                    {
+                        let candidates = old_links[removed as usize]
+                            .get(level)
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|&n| mapping[n as usize]);
+                        for candidate in candidates {
+                            if candidate != new && !neighbors.contains(&candidate) {
+                                neighbors.push(candidate);
+                            }
+                        }
+                    }
+                    let max = self.max_links(level);
+                    if neighbors.len() > max {
+                        self.select_nearest(vectors, new, &mut neighbors, max)
+                            .or_fail()?;
+                    }
+                }
+                levels.push(neighbors);
+            }
+            self.links[new as usize] = levels;
+        }
+
+        self.entry_point = old_entry_point
+            .and_then(|n| mapping[n as usize])
+            .or_else(|| {
+                let mut nodes = (0..self.links.len()).filter(|&i| !self.links[i].is_empty());
+                let first = nodes.next()?;
+                Some(nodes.fold(first, |a, b| {
+                    if self.links[b].len() > self.links[a].len() {
+                        b
+                    } else {
+                        a
+                    }
+                }) as u32)
+            });
+
+        // New nodes and nodes that have lost all their neighbours are (re-)inserted
+        for node in 0..self.links.len() as u32 {
+            let levels = &self.links[node as usize];
+            let isolated = levels.first().is_none_or(|n| n.is_empty());
+            if isolated && self.entry_point != Some(node) {
+                self.insert(vectors, node).or_fail()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, vectors: &Vectors, node: u32) -> orfail::Result<()> {
+        let level = self.random_level();
+        self.links[node as usize] = vec![Vec::new(); level + 1];
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            return Ok(());
+        };
+
+        let query = vectors.unit_vector(node).or_fail()?;
+        let top = self.links[entry_point as usize].len() - 1;
+        let mut nearest = vec![Scored {
+            similarity: vectors.similarity(&query, entry_point).or_fail()?,
+            node: entry_point,
+        }];
+        for level in (level + 1..=top).rev() {
+            nearest = self
+                .search_layer(vectors, &query, nearest, 1, level)
+                .or_fail()?;
+        }
+        for level in (0..=level.min(top)).rev() {
+            nearest = self
+                .search_layer(vectors, &query, nearest, EF_CONSTRUCTION, level)
+                .or_fail()?;
+            let neighbors = nearest
+                .iter()
+                .filter(|s| s.node != node)
+                .take(self.m)
+                .map(|s| s.node)
+                .collect::<Vec<_>>();
+            let max = self.max_links(level);
+            for &neighbor in &neighbors {
+                let Some(back_links) = self.links[neighbor as usize].get_mut(level) else {
+                    continue;
+                };
+                back_links.push(node);
+                if back_links.len() > max {
+                    let mut back_links = std::mem::take(back_links);
+                    self.select_nearest(vectors, neighbor, &mut back_links, max)
+                        .or_fail()?;
+                    self.links[neighbor as usize][level] = back_links;
+                }
+            }
+            self.links[node as usize][level] = neighbors;
+        }
+
+        if level > top {
+            self.entry_point = Some(node);
+        }
+        Ok(())
+    }
+
+    /// Returns the nodes nearest to the (unit) query in descending order of similarity.
+    fn search(&self, vectors: &Vectors, query: &[f32], ef: usize) -> orfail::Result<Vec<Scored>> {
+        let Some(entry_point) = self.entry_point else {
+            return Ok(Vec::new());
+        };
+        let mut nearest = vec![Scored {
+            similarity: vectors.similarity(query, entry_point).or_fail()?,
+            node: entry_point,
+        }];
+        for level in (1..self.links[entry_point as usize].len()).rev() {
+            nearest = self
+                .search_layer(vectors, query, nearest, 1, level)
+                .or_fail()?;
+        }
+        self.search_layer(vectors, query, nearest, ef, 0).or_fail()
+    }
+
+    fn search_layer(
+        &self,
+        vectors: &Vectors,
+        query: &[f32],
+        entry_points: Vec<Scored>,
+        ef: usize,
+        level: usize,
+    ) -> orfail::Result<Vec<Scored>> {
+        let mut visited = entry_points.iter().map(|s| s.node).collect::<HashSet<_>>();
+        let mut candidates = entry_points.iter().copied().collect::<BinaryHeap<_>>();
+        let mut results = entry_points
+            .into_iter()
+            .map(Reverse)
+            .collect::<BinaryHeap<_>>();
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(candidate) = candidates.pop() {
+            let Reverse(worst) = *results.peek().expect("infallible");
+            if candidate < worst && results.len() >= ef {
+                break;
+            }
+
+            let neighbors = self.links[candidate.node as usize]
+                .get(level)
+                .map_or(&[][..], |n| n);
+            for &neighbor in neighbors {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let scored = Scored {
+                    similarity: vectors.similarity(query, neighbor).or_fail()?,
+                    node: neighbor,
+                };
+                if results.len() < ef || results.peek().is_some_and(|Reverse(w)| scored > *w) {
+                    candidates.push(scored);
+                    results.push(Reverse(scored));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        let mut results = results.into_iter().map(|Reverse(s)| s).collect::<Vec<_>>();
+        results.sort_by(|a, b| b.cmp(a));
+        Ok(results)
+    }
+
+    /// Keeps the `max` nodes in `nodes` that are nearest to `node`.
+    fn select_nearest(
+        &self,
+        vectors: &Vectors,
+        node: u32,
+        nodes: &mut Vec<u32>,
+        max: usize,
+    ) -> orfail::Result<()> {
+        let query = vectors.unit_vector(node).or_fail()?;
+        let mut scored = nodes
+            .iter()
+            .map(|&n| {
+                Ok(Scored {
+                    similarity: vectors.similarity(&query, n)?,
+                    node: n,
+                })
+            })
+            .collect::<orfail::Result<Vec<_>>>()
+            .or_fail()?;
+        scored.sort_by(|a, b| b.cmp(a));
+        *nodes = scored.into_iter().take(max).map(|s| s.node).collect();
+        Ok(())
+    }
+
+    fn max_links(&self, level: usize) -> usize {
+        if level == 0 { self.m * 2 } else { self.m }
+    }
+
+    fn random_level(&mut self) -> usize {
+        // SplitMix64, so that the graph only depends on the insertion order
+        self.rng_state = self.rng_state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        // Uniform in (0, 1]
+        let u = ((z >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let level = (-u.ln() / (self.m as f64).ln()).floor() as usize;
+        level.min(MAX_LEVEL)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Scored {
+    similarity: f64,
+    node: u32,
+}
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Ties are broken in favour of the smaller node for determinism
+        self.similarity
+            .total_cmp(&other.similarity)
+            .then_with(|| other.node.cmp(&self.node))
+    }
+}
+
+/// Accessor to the chunk vectors in a binary index file by node.
+#[derive(Debug)]
+struct Vectors<'a> {
+    data: &'a [u8],
+    offsets: &'a [u64],
+    buf: RefCell<Vec<f32>>,
+}
+
+impl<'a> Vectors<'a> {
+    fn new(data: &'a [u8], offsets: &'a [u64]) -> Self {
+        Self {
+            data,
+            offsets,
+            buf: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn with_vector<F, T>(&self, node: u32, f: F) -> orfail::Result<T>
+    where
+        F: FnOnce(&[f32], bool) -> T,
+    {
+        let offset = *self.offsets.get(node as usize).or_fail()? as usize;
+        let (record, _) = Record::parse(self.data, offset).or_fail()?.or_fail()?;
+        let mut buf = self.buf.borrow_mut();
+        Ok(f(record.vector(&mut buf), record.normalized))
+    }
+
+    fn unit_vector(&self, node: u32) -> orfail::Result<Vec<f32>> {
+        self.with_vector(node, |v, _| {
+            unit_query_vector(&Embedding(v.iter().map(|&x| f64::from(x)).collect()))
+        })
+    }
+
+    /// Returns the cosine similarity between the unit query and the vector of the node.
+    fn similarity(&self, query: &[f32], node: u32) -> orfail::Result<f64> {
+        self.with_vector(node, |v, normalized| {
+            unit_cosine_similarity(query, v, normalized)
+        })
+    }
+}
+
+/// Returns the modification time of a file in nanoseconds since the Unix epoch (0 if unknown).
+pub fn modified_nanos(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+fn chunk_key(repository_path: &Path, chunk: &ChunkEntry) -> u64 {
+    let hash = chunk.hash.map(|h| h.to_string()).unwrap_or_default();
+    let key = format!(
+        "{}\0{}\0{}\0{hash}",
+        repository_path.display(),
+        chunk.path.display(),
+        chunk.line
+    );
+    ContentHash::of(&key).0
+}
+
+#[derive(Debug)]
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl ByteReader<'_> {
+    fn bytes(&mut self, n: usize) -> orfail::Result<&[u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .or_fail_with(|()| "unexpected end of file".to_owned())?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> orfail::Result<u32> {
+        let b = self.bytes(4).or_fail()?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> orfail::Result<u64> {
+        let b = self.bytes(8).or_fail()?;
+        Ok(u64::from_le_bytes([
+            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TestRng, repository_entry, temp_path};
+
+    fn random_vectors(count: usize, dimension: usize, seed: u64) -> Vec<Vec<f64>> {
+        let mut rng = TestRng::new(seed);
+        (0..count)
+            .map(|_| (0..dimension).map(|_| rng.next_f64() - 0.5).collect())
+            .collect()
+    }
+
+    fn write_index_file(name: &str, vectors: &[(usize, &[f64])]) -> IndexFile {
+        let index_file =
+            IndexFile::create_new(temp_path(name), IndexFormat::Binary).expect("create");
+        index_file
+            .append_repository(&repository_entry())
+            .expect("append");
+        for (id, vector) in vectors {
+            index_file
+                .append_chunk(&ChunkEntry {
+                    path: PathBuf::from(format!("{id}.rs")),
+                    line: 0,
+                    line_count: None,
+                    columns: None,
+                    hash: Some(ContentHash(*id as u64)),
+                    embedding_hash: None,
+                    terms: None,
+                    embedding: Embedding(vector.to_vec()),
+                })
+                .expect("append");
+        }
+        index_file
+    }
+
+    fn search_paths(hnsw: &HnswIndex, index_file: &IndexFile, query: &[f64]) -> Vec<String> {
+        hnsw.search(
+            index_file,
+            &Embedding(query.to_vec()),
+            10,
+            -1.0,
+            &GlobPathFilter::default(),
+            DEFAULT_EF_SEARCH,
+        )
+        .expect("search")
+        .into_iter()
+        .map(|m| m.file_path.display().to_string())
+        .collect()
+    }
+
+    fn exact_paths(index_file: &IndexFile, query: &[f64]) -> Vec<String> {
+        index_file
+            .search(
+                &Embedding(query.to_vec()),
+                10,
+                -1.0,
+                &GlobPathFilter::default(),
+                1,
+            )
+            .expect("search")
+            .into_iter()
+            .map(|m| m.file_path.display().to_string())
+            .collect()
+    }
+
+    fn recall(hnsw: &HnswIndex, index_file: &IndexFile, queries: &[Vec<f64>]) -> f64 {
+        let mut hits = 0;
+        for query in queries {
+            let expected = exact_paths(index_file, query);
+            let actual = search_paths(hnsw, index_file, query);
+            hits += actual.iter().filter(|p| expected.contains(p)).count();
+        }
+        hits as f64 / (queries.len() * 10) as f64
+    }
+
+    #[test]
+    fn default_option_values_match_constants() {
+        assert_eq!(DEFAULT_HNSW_M_STR.parse(), Ok(DEFAULT_HNSW_M));
+        assert_eq!(DEFAULT_EF_SEARCH_STR.parse(), Ok(DEFAULT_EF_SEARCH));
+    }
+
+    #[test]
+    fn approximate_search_has_high_recall() {
+        let vectors = random_vectors(600, 16, 42);
+        let entries = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (i, v.as_slice()))
+            .collect::<Vec<_>>();
+        let index_file = write_index_file("hnsw-recall", &entries);
+
+        let mut hnsw = HnswIndex::new(DEFAULT_HNSW_M);
+        hnsw.update(&index_file).expect("update");
+        assert_eq!(hnsw.len(), 600);
+        assert!(hnsw.is_up_to_date(&index_file).expect("check"));
+
+        let queries = random_vectors(20, 16, 7);
+        assert!(recall(&hnsw, &index_file, &queries) >= 0.9);
+
+        // Save and load
+        let path = HnswIndex::sidecar_path(&index_file.path);
+        hnsw.save(&path).expect("save");
+        let loaded = HnswIndex::load(&path).expect("load").expect("exists");
+        assert_eq!(loaded.encode(), hnsw.encode());
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&index_file.path);
+    }
+
+    #[test]
+    fn incremental_update_keeps_graph_consistent() {
+        let vectors = random_vectors(500, 16, 1);
+        let entries = vectors
+            .iter()
+            .enumerate()
+            .take(400)
+            .map(|(i, v)| (i, v.as_slice()))
+            .collect::<Vec<_>>();
+        let index_file = write_index_file("hnsw-incremental", &entries);
+        let mut hnsw = HnswIndex::new(8);
+        hnsw.update(&index_file).expect("update");
+
+        // Remove every third chunk and add new ones in the middle
+        let entries = vectors
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 3 != 0)
+            .map(|(i, v)| (i, v.as_slice()))
+            .collect::<Vec<_>>();
+        let _ = std::fs::remove_file(&index_file.path);
+        let index_file = write_index_file("hnsw-incremental", &entries);
+        assert!(!hnsw.is_up_to_date(&index_file).expect("check"));
+        hnsw.update(&index_file).expect("update");
+        assert!(hnsw.is_up_to_date(&index_file).expect("check"));
+        assert_eq!(hnsw.len(), entries.len());
+
+        let node_count = hnsw.len() as u32;
+        for levels in &hnsw.graph.links {
+            assert!(!levels.is_empty());
+            for neighbors in levels {
+                assert!(neighbors.iter().all(|&n| n < node_count));
+            }
+        }
+
+        // The updated graph is about as accurate as one built from scratch
+        let queries = random_vectors(20, 16, 9);
+        let mut fresh = HnswIndex::new(8);
+        fresh.update(&index_file).expect("update");
+        let updated_recall = recall(&hnsw, &index_file, &queries);
+        assert!(updated_recall >= 0.9);
+        assert!(updated_recall >= recall(&fresh, &index_file, &queries) - 0.05);
+
+        let _ = std::fs::remove_file(&index_file.path);
+    }
+
+    #[test]
+    fn filtered_search_is_widened_until_enough_chunks_match() {
+        let vectors = random_vectors(600, 16, 7);
+        let entries = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (i, v.as_slice()))
+            .collect::<Vec<_>>();
+        let index_file = write_index_file("hnsw-filtered", &entries);
+        let mut hnsw = HnswIndex::new(DEFAULT_HNSW_M);
+        hnsw.update(&index_file).expect("update");
+
+        let filter = GlobPathFilter {
+            include_files: vec![crate::glob::GlobPathPattern::new("*5.rs")],
+            exclude_files: Vec::new(),
+        };
+        let query = Embedding(random_vectors(1, 16, 3).remove(0));
+        let matched = hnsw
+            .search(&index_file, &query, 10, -1.0, &filter, 10)
+            .expect("search");
+        assert_eq!(matched.len(), 10);
+        assert!(
+            matched
+                .iter()
+                .all(|c| c.file_path.to_string_lossy().ends_with("5.rs"))
+        );
+
+        let _ = std::fs::remove_file(&index_file.path);
+    }
+
+    #[test]
+    fn modified_index_file_is_detected() {
+        let index_file = write_index_file("hnsw-modified", &[(0, &[1.0, 0.0])]);
+        let mut hnsw = HnswIndex::new(8);
+        hnsw.update(&index_file).expect("update");
+        assert!(hnsw.is_up_to_date(&index_file).expect("check"));
+
+        // Same length, but rewritten later
+        let file = std::fs::File::options()
+            .append(true)
+            .open(&index_file.path)
+            .expect("open");
+        file.set_modified(std::time::UNIX_EPOCH).expect("set mtime");
+        assert!(!hnsw.is_up_to_date(&index_file).expect("check"));
+
+        let _ = std::fs::remove_file(&index_file.path);
+    }
+
+    #[test]
+    fn mixed_dimensions_are_rejected() {
+        let index_file = write_index_file(
+            "hnsw-mixed-dimensions",
+            &[(0, &[1.0, 0.0]), (1, &[0.0, 1.0, 0.0])],
+        );
+        let mut hnsw = HnswIndex::new(8);
+        assert!(hnsw.update(&index_file).is_err());
+
+        let _ = std::fs::remove_file(&index_file.path);
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use orfail::OrFail;
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Returns the value of the first header with the given (case-insensitive) name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A failure to exchange a request and a response with the server (reported by curl).
+#[derive(Debug)]
+pub struct TransportError {
+    pub curl_exit_code: Option<i32>,
+    pub message: String,
+}
+
+impl TransportError {
+    /// Returns `true` if the failure is likely to be temporary (e.g., connection reset or timeout).
+    pub fn is_transient(&self) -> bool {
+        // See https://curl.se/libcurl/c/libcurl-errors.html
+        matches!(
+            self.curl_exit_code,
+            Some(5 | 6 | 7 | 16 | 18 | 28 | 35 | 52 | 55 | 56 | 92)
+        )
+    }
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.curl_exit_code {
+            Some(code) => write!(
+                f,
+                "curl command failed (exit code {code}): {}",
+                self.message
+            ),
+            None => write!(f, "curl command failed: {}", self.message),
+        }
+    }
+}
+
+/// Sends a POST request using the `curl` command.
+///
+/// The request body is passed through stdin and the headers through a private temporary file,
+/// so neither shows up in the process arguments (e.g., in `ps` output) nor hits argv size limits.
+pub fn post(
+    url: &str,
+    headers: &[String],
+    body: &str,
+) -> orfail::Result<Result<HttpResponse, TransportError>> {
+    let header_file = TempFile::create("request-headers").or_fail()?;
+    {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(header_file.path())
+            .or_fail()?;
+        for header in headers {
+            writeln!(file, "{header}").or_fail()?;
+        }
+    }
+    let dump_file = TempFile::create("response-headers").or_fail()?;
+
+    let mut child = Command::new("curl")
+        .arg(url)
+        .arg("--request")
+        .arg("POST")
+        .arg("--header")
+        .arg(format!("@{}", header_file.path().display()))
+        .arg("--data-binary")
+        .arg("@-")
+        .arg("--dump-header")
+        .arg(dump_file.path())
+        .arg("--silent")
+        .arg("--show-error")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .or_fail_with(|e| format!("Failed to execute curl: {e}"))?;
+
+    let mut stdin = child.stdin.take().or_fail()?;
+    let output = std::thread::scope(|s| {
+        let writer = s.spawn(move || stdin.write_all(body.as_bytes()));
+        let output = child.wait_with_output();
+        let written = writer.join().expect("infallible");
+        output.and_then(|output| written.map(|()| output))
+    })
+    .or_fail()?;
+
+    if !output.status.success() {
+        return Ok(Err(TransportError {
+            curl_exit_code: output.status.code(),
+            message: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        }));
+    }
+
+    let dumped_headers = std::fs::read_to_string(dump_file.path()).or_fail()?;
+    let (status, headers) = parse_response_headers(&dumped_headers).or_fail()?;
+    let body = String::from_utf8(output.stdout)
+        .or_fail_with(|e| format!("Failed to parse curl response as UTF-8: {e}"))?;
+    Ok(Ok(HttpResponse {
+        status,
+        headers,
+        body,
+    }))
+}
+
+/// Parses the headers dumped by curl.
+///
+/// The dump may contain several header blocks (e.g., for `100 Continue` or proxy responses),
+/// in which case the last one belongs to the final response.
+fn parse_response_headers(text: &str) -> orfail::Result<(u16, Vec<(String, String)>)> {
+    let block = text
+        .split("\r\n\r\n")
+        .filter(|b| b.starts_with("HTTP/"))
+        .last()
+        .or_fail_with(|()| format!("No HTTP response headers: {text:?}"))?;
+
+    let mut lines = block.lines();
+    let status_line = lines.next().or_fail()?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .or_fail_with(|()| format!("Invalid HTTP status line: {status_line:?}"))?;
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned()))
+        .collect();
+    Ok((status, headers))
+}
+
+/// A file in the temporary directory that is only readable by the current user
+/// and is removed when dropped.
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn create(name: &str) -> orfail::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "dokosa-{}-{}-{name}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&path)
+            .or_fail_with(|e| format!("Failed to create {}: {e}", path.display()))?;
+        Ok(Self { path })
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_final_response_headers() {
+        let text = concat!(
+            "HTTP/1.1 100 Continue\r\n\r\n",
+            "HTTP/1.1 429 Too Many Requests\r\n",
+            "Content-Type: application/json\r\n",
+            "retry-after: 3\r\n\r\n"
+        );
+        let (status, headers) = parse_response_headers(text).expect("parse");
+        assert_eq!(status, 429);
+        assert_eq!(
+            headers,
+            [
+                ("Content-Type".to_owned(), "application/json".to_owned()),
+                ("retry-after".to_owned(), "3".to_owned())
+            ]
+        );
+    }
+}
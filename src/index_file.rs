@@ -1,19 +1,90 @@
 use std::{
-    io::{BufRead, BufWriter, Write},
+    cell::Cell,
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap, HashMap, hash_map::Entry},
+    io::{BufRead, BufWriter, Read, Write},
     num::NonZeroUsize,
+    ops::Range,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
 use orfail::OrFail;
 
 use crate::{
-    embedder::Embedding,
+    chunker::{ChunkHeaderField, ChunkStrategy, ContentHash, extract_chunk_text},
+    embedder::{Embedding, EmbeddingProviderKind},
     glob::{GlobPathFilter, GlobPathPattern},
+    mmap::MappedFile,
+    vector_encoding::VectorEncoding,
 };
 
+/// Magic bytes at the beginning of a binary index file.
+const BINARY_MAGIC: &[u8; 8] = b"DOKOSAIX";
+
+const BINARY_VERSION: u32 = 1;
+
+/// Size of the header at the beginning of a binary index file (i.e., the offset of the first record).
+pub const BINARY_HEADER_SIZE: usize = 16;
+
+const RECORD_HEADER_SIZE: usize = 12;
+
+pub const RECORD_KIND_REPOSITORY: u8 = 1;
+
+pub const RECORD_KIND_CHUNK: u8 = 2;
+
+/// Record flag indicating that the vector was normalized to unit length when written.
+///
+/// It is only set for `f32` vectors, as quantized vectors are no longer exactly unit length.
+const RECORD_FLAG_NORMALIZED: u16 = 1;
+
+/// On-disk format of an index file.
+///
+/// The binary format starts with a 16-byte header (the magic bytes `DOKOSAIX`,
+/// a little-endian `u32` version and a reserved `u32`), followed by records.
+/// Each record consists of a 12-byte header (`u8` kind, `u8` vector encoding, `u16` flags,
+/// `u32` metadata length and `u32` vector length), the JSON metadata padded to a multiple of
+/// four bytes, and the encoded vector (see [`VectorEncoding`]).
+/// As all records are 4-byte aligned, `f32` vectors can be used directly from a memory-mapped file.
+///
+/// The JSON Lines format is the original one, which is kept readable and writable
+/// for existing index files and as an export format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexFormat {
+    JsonLines,
+    Binary,
+}
+
+impl std::fmt::Display for IndexFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IndexFormat::JsonLines => write!(f, "jsonl"),
+            IndexFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+impl std::str::FromStr for IndexFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(IndexFormat::JsonLines),
+            "binary" => Ok(IndexFormat::Binary),
+            _ => Err(format!(
+                "unknown index format: expected 'jsonl' or 'binary', found '{s}'"
+            )),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct IndexFile {
     pub path: PathBuf,
+    pub format: IndexFormat,
+
+    // Encoding of the vectors of the chunks following the last appended repository
+    vector_encoding: Cell<VectorEncoding>,
 }
 
 impl IndexFile {
@@ -22,37 +93,121 @@ impl IndexFile {
         if path.exists() {
             Self::load(path).or_fail().map(|this| (false, this))
         } else {
-            Self::create_new(path).or_fail().map(|this| (true, this))
+            Self::create_new(path, IndexFormat::Binary)
+                .or_fail()
+                .map(|this| (true, this))
         }
     }
 
     pub fn load<P: AsRef<Path>>(path: P) -> orfail::Result<Self> {
         let path = path.as_ref().to_path_buf();
         path.exists().or_fail()?;
-        Ok(Self { path })
+
+        let mut header = Vec::with_capacity(BINARY_HEADER_SIZE);
+        std::fs::File::open(&path)
+            .or_fail()?
+            .take(BINARY_HEADER_SIZE as u64)
+            .read_to_end(&mut header)
+            .or_fail()?;
+        let format = if header.starts_with(BINARY_MAGIC) {
+            (header.len() == BINARY_HEADER_SIZE)
+                .or_fail_with(|()| format!("Truncated index file: {}", path.display()))?;
+            let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+            (version == BINARY_VERSION).or_fail_with(|()| {
+                format!(
+                    "Unsupported index file version {version}: {}",
+                    path.display()
+                )
+            })?;
+            IndexFormat::Binary
+        } else {
+            IndexFormat::JsonLines
+        };
+
+        Ok(Self::new(path, format))
     }
 
-    pub fn create_new<P: AsRef<Path>>(path: P) -> orfail::Result<Self> {
+    pub fn create_new<P: AsRef<Path>>(path: P, format: IndexFormat) -> orfail::Result<Self> {
         let path = path.as_ref().to_path_buf();
-        std::fs::File::create_new(&path).or_fail()?;
-        Ok(Self { path })
+        let mut file = std::fs::File::create_new(&path).or_fail()?;
+        if format == IndexFormat::Binary {
+            let mut header = Vec::with_capacity(BINARY_HEADER_SIZE);
+            header.extend_from_slice(BINARY_MAGIC);
+            header.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+            header.extend_from_slice(&0u32.to_le_bytes());
+            file.write_all(&header).or_fail()?;
+        }
+        Ok(Self::new(path, format))
+    }
+
+    fn new(path: PathBuf, format: IndexFormat) -> Self {
+        Self {
+            path,
+            format,
+            vector_encoding: Cell::new(VectorEncoding::default()),
+        }
     }
 
     pub fn append_repository(&self, repo: &RepositoryEntry) -> orfail::Result<()> {
-        self.append(repo).or_fail()
+        self.vector_encoding
+            .set(repo.vector_encoding.unwrap_or_default());
+        match self.format {
+            IndexFormat::JsonLines => self.append_json_line(repo).or_fail(),
+            IndexFormat::Binary => {
+                let metadata = nojson::Json(repo).to_string();
+                let record = encode_record(
+                    RECORD_KIND_REPOSITORY,
+                    VectorEncoding::default(),
+                    0,
+                    &metadata,
+                    &[],
+                );
+                self.append_bytes(&record).or_fail()
+            }
+        }
     }
 
+    /// Appends a chunk of the last appended repository.
+    ///
+    /// In binary index files, the embedding is normalized to unit length (which does not affect
+    /// cosine similarity) and encoded with the repository's vector encoding.
     pub fn append_chunk(&self, chunk: &ChunkEntry) -> orfail::Result<()> {
-        self.append(chunk).or_fail()
+        match self.format {
+            IndexFormat::JsonLines => self.append_json_line(chunk).or_fail(),
+            IndexFormat::Binary => {
+                let metadata = nojson::Json(ChunkMetadata(chunk)).to_string();
+                let vector = &chunk.embedding.0;
+                let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+                let unit_vector = if norm > 0.0 {
+                    vector.iter().map(|x| x / norm).collect()
+                } else {
+                    vector.clone()
+                };
+                let encoding = self.vector_encoding.get();
+                let flags = if encoding == VectorEncoding::F32 {
+                    RECORD_FLAG_NORMALIZED
+                } else {
+                    0
+                };
+                let record =
+                    encode_record(RECORD_KIND_CHUNK, encoding, flags, &metadata, &unit_vector);
+                self.append_bytes(&record).or_fail()
+            }
+        }
+    }
+
+    fn append_json_line<T: nojson::DisplayJson>(&self, entry: &T) -> orfail::Result<()> {
+        self.append_bytes(format!("{}\n", nojson::Json(entry)).as_bytes())
+            .or_fail()
     }
 
-    fn append<T: nojson::DisplayJson>(&self, entry: &T) -> orfail::Result<()> {
+    fn append_bytes(&self, bytes: &[u8]) -> orfail::Result<()> {
         let file = std::fs::OpenOptions::new()
             .append(true)
             .open(&self.path)
             .or_fail()?;
         let mut writer = BufWriter::new(file);
-        writeln!(writer, "{}", nojson::Json(entry)).or_fail()?;
+        writer.write_all(bytes).or_fail()?;
         writer.flush().or_fail()?;
         Ok(())
     }
@@ -60,161 +215,951 @@ impl IndexFile {
     pub fn entries(&self) -> impl Iterator<Item = orfail::Result<IndexFileEntry>> {
         Entries {
             path: self.path.clone(),
-            lines: None,
+            state: EntriesState::Unopened(self.format),
+            skip_chunks: false,
         }
     }
 
     pub fn repositories(&self) -> impl Iterator<Item = orfail::Result<RepositoryEntry>> {
-        self.entries().filter_map(|x| match x {
+        let entries = Entries {
+            path: self.path.clone(),
+            state: EntriesState::Unopened(self.format),
+            skip_chunks: true,
+        };
+        entries.filter_map(|x| match x {
             Err(e) => Some(Err(e)),
             Ok(IndexFileEntry::Repository(x)) => Some(Ok(x)),
             _ => None,
         })
     }
 
+    /// Returns the `count` chunks most similar to the query.
+    ///
+    /// Binary index files are split into segments (at repository boundaries, and further
+    /// for large repositories) that are scanned by up to `threads` worker threads.
+    /// The results are the same regardless of the number of threads.
     pub fn search(
         &self,
         query: &Embedding,
         count: usize,
         similarity_threshold: f64,
         filter: &GlobPathFilter,
+        threads: usize,
     ) -> orfail::Result<Vec<MatchedChunk>> {
-        let mut candidates = Vec::new();
-        let mut lowest_similarity = similarity_threshold.next_down();
-        let mut repository = None;
-
-        // Collect all chunk entries with their similarity scores
-        for entry_result in self.entries() {
-            let entry = entry_result.or_fail()?;
-            match entry {
-                IndexFileEntry::Repository(repo) => {
-                    repository = Some(repo);
-                }
-                IndexFileEntry::Chunk(chunk) => {
-                    let repository = repository.as_ref().or_fail()?;
-                    if !filter.matches(repository.path.join(&chunk.path)) {
-                        continue;
+        let query_vector = unit_query_vector(query);
+        let top_ks = self
+            .scan_chunks(
+                threads,
+                || TopK::new(count),
+                |top_k, repository, chunk| {
+                    let Some(similarity) =
+                        chunk_similarity(&query_vector, repository, &chunk, filter).or_fail()?
+                    else {
+                        return Ok(());
+                    };
+                    if similarity < similarity_threshold
+                        || !top_k.accepts(similarity, chunk.ordinal)
+                    {
+                        return Ok(());
+                    }
+
+                    // Only the chunks that can be in the results need their metadata
+                    let metadata = chunk.metadata().or_fail()?;
+                    if !filter.matches(repository.path.join(&metadata.path)) {
+                        return Ok(());
+                    }
+                    top_k.push(
+                        chunk.ordinal,
+                        MatchedChunk {
+                            repository_path: repository.path.clone(),
+                            line_count: metadata.line_count.unwrap_or(repository.chunk_window_size),
+                            columns: metadata.columns,
+                            file_path: metadata.path,
+                            line: metadata.line,
+                            hash: metadata.hash,
+                            similarity,
+                            vector: chunk.unit_vector(),
+                        },
+                    );
+                    Ok(())
+                },
+            )
+            .or_fail()?;
+
+        let mut merged = TopK::new(count);
+        for top_k in top_ks {
+            merged.merge(top_k);
+        }
+        Ok(merged.into_sorted_vec())
+    }
+
+    /// Returns the `count` files (or repositories) with the chunks most similar to the query.
+    ///
+    /// Unlike [`IndexFile::search()`], all chunks above the similarity threshold contribute
+    /// to the mean similarity and the hit count of their group.
+    pub fn search_groups(
+        &self,
+        query: &Embedding,
+        count: usize,
+        similarity_threshold: f64,
+        filter: &GlobPathFilter,
+        group_by: GroupBy,
+        threads: usize,
+    ) -> orfail::Result<Vec<MatchedGroup>> {
+        let query_vector = unit_query_vector(query);
+        let groups = self
+            .scan_chunks(
+                threads,
+                || ChunkGroups::new(group_by),
+                |groups, repository, chunk| {
+                    let Some(similarity) =
+                        chunk_similarity(&query_vector, repository, &chunk, filter).or_fail()?
+                    else {
+                        return Ok(());
+                    };
+                    if similarity < similarity_threshold {
+                        return Ok(());
+                    }
+
+                    let metadata = chunk.metadata().or_fail()?;
+                    if !filter.matches(repository.path.join(&metadata.path)) {
+                        return Ok(());
                     }
-                    let similarity = self.cosine_similarity(query, &chunk.embedding);
-                    if similarity > lowest_similarity {
-                        candidates.push(MatchedChunk {
+                    groups.add(
+                        chunk.ordinal,
+                        MatchedChunk {
                             repository_path: repository.path.clone(),
-                            chunk_window_size: repository.chunk_window_size,
-                            file_path: chunk.path,
-                            line: chunk.line,
+                            line_count: metadata.line_count.unwrap_or(repository.chunk_window_size),
+                            columns: metadata.columns,
+                            file_path: metadata.path,
+                            line: metadata.line,
+                            hash: metadata.hash,
                             similarity,
-                        });
+                            vector: Vec::new(),
+                        },
+                    );
+                    Ok(())
+                },
+            )
+            .or_fail()?;
+
+        let mut merged = ChunkGroups::new(group_by);
+        for groups in groups {
+            merged.merge(groups);
+        }
+        Ok(merged.into_sorted_vec(count))
+    }
 
-                        candidates.sort_by(|a, b| {
-                            b.similarity
-                                .partial_cmp(&a.similarity)
-                                .unwrap_or(std::cmp::Ordering::Equal)
-                        });
+    /// Scans all chunks, accumulating them into a state created by `init` for each worker thread.
+    ///
+    /// The workers claim the segments of binary index files one by one as they find them,
+    /// so scanning starts without walking the whole file first.
+    /// JSON Lines files are always scanned sequentially by the current thread.
+    fn scan_chunks<T, I, F>(&self, threads: usize, init: I, f: F) -> orfail::Result<Vec<T>>
+    where
+        T: Send,
+        I: Fn() -> T + Sync,
+        F: Fn(&mut T, &RepositoryEntry, ChunkRecord<'_>) -> orfail::Result<()> + Sync,
+    {
+        if self.format == IndexFormat::JsonLines || threads <= 1 {
+            let mut state = init();
+            self.for_each_chunk(|repository, chunk| f(&mut state, repository, chunk))
+                .or_fail()?;
+            return Ok(vec![state]);
+        }
 
-                        if candidates.len() > count {
-                            let lowest = candidates.pop().expect("infallible");
-                            lowest_similarity = lowest.similarity;
+        let file = MappedFile::open(&self.path).or_fail()?;
+        let data = file.as_bytes();
+        let cursor = Mutex::new(SegmentCursor::new());
+        std::thread::scope(|s| {
+            let workers = (0..threads)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut state = init();
+                        let mut vector = Vec::new();
+                        loop {
+                            let next = cursor
+                                .lock()
+                                .expect("segment cursor poisoned")
+                                .next_segment(data);
+                            let Some(segment) = next.or_fail()? else {
+                                break;
+                            };
+                            segment
+                                .for_each_chunk(data, &mut vector, |chunk| {
+                                    f(&mut state, &segment.repository, chunk)
+                                })
+                                .or_fail()?;
+                        }
+                        Ok(state)
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("search worker panicked"))
+                .collect::<orfail::Result<Vec<_>>>()
+        })
+    }
+
+    /// Calls `f` for each chunk in the index file together with the repository it belongs to.
+    ///
+    /// Binary index files are scanned in place via a memory mapping, and chunk metadata is
+    /// only parsed on demand.
+    pub fn for_each_chunk<F>(&self, mut f: F) -> orfail::Result<()>
+    where
+        F: FnMut(&RepositoryEntry, ChunkRecord<'_>) -> orfail::Result<()>,
+    {
+        let mut vector = Vec::new();
+        match self.format {
+            IndexFormat::JsonLines => {
+                let mut repository = None;
+                let mut ordinal = 0;
+                for entry in self.entries() {
+                    match entry.or_fail()? {
+                        IndexFileEntry::Repository(repo) => repository = Some(repo),
+                        IndexFileEntry::Chunk(chunk) => {
+                            let repository = repository.as_ref().or_fail()?;
+                            vector.clear();
+                            vector.extend(chunk.embedding.0.iter().map(|&x| x as f32));
+                            let record = ChunkRecord {
+                                ordinal,
+                                metadata: ChunkRecordMetadata::Parsed(&chunk),
+                                vector: &vector,
+                                normalized: false,
+                            };
+                            f(repository, record).or_fail()?;
+                            ordinal += 1;
                         }
                     }
                 }
             }
+            IndexFormat::Binary => {
+                let file = MappedFile::open(&self.path).or_fail()?;
+                let data = file.as_bytes();
+                let mut cursor = SegmentCursor::new();
+                while let Some(segment) = cursor.next_segment(data).or_fail()? {
+                    segment
+                        .for_each_chunk(data, &mut vector, |chunk| f(&segment.repository, chunk))
+                        .or_fail()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the cosine similarity between a unit query vector and a chunk, checking their dimensions.
+///
+/// Chunks of a different dimension are skipped (`None`) if the filter excludes them,
+/// so that repositories indexed with other models can be left out of a search.
+fn chunk_similarity(
+    query: &[f32],
+    repository: &RepositoryEntry,
+    chunk: &ChunkRecord<'_>,
+    filter: &GlobPathFilter,
+) -> orfail::Result<Option<f64>> {
+    if chunk.vector.len() != query.len() {
+        let path = chunk.metadata().or_fail()?.path;
+        if !filter.matches(repository.path.join(&path)) {
+            return Ok(None);
+        }
+        return Err(orfail::Failure::new(format!(
+            "Embedding dimension mismatch: query has {} but {} in {} has {}",
+            query.len(),
+            path.display(),
+            repository.path.display(),
+            chunk.vector.len()
+        )));
+    }
+    Ok(Some(chunk.similarity(query)))
+}
+
+/// Maximum number of chunks in a [`Segment`].
+const SEGMENT_MAX_CHUNKS: usize = 4096;
+
+/// A contiguous range of chunk records of a repository in a binary index file.
+#[derive(Debug)]
+struct Segment {
+    repository: Arc<RepositoryEntry>,
+    start: usize,
+    end: usize,
+    first_ordinal: usize,
+}
+
+impl Segment {
+    fn for_each_chunk<F>(&self, data: &[u8], vector: &mut Vec<f32>, mut f: F) -> orfail::Result<()>
+    where
+        F: FnMut(ChunkRecord<'_>) -> orfail::Result<()>,
+    {
+        let mut offset = self.start;
+        let mut ordinal = self.first_ordinal;
+        while offset < self.end {
+            let (record, next) = Record::parse(data, offset).or_fail()?.or_fail()?;
+            let chunk = ChunkRecord {
+                ordinal,
+                metadata: ChunkRecordMetadata::Raw(record.metadata),
+                vector: record.vector(vector),
+                normalized: record.normalized,
+            };
+            f(chunk).or_fail()?;
+            offset = next;
+            ordinal += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Position in a binary index file from which the next [`Segment`] starts.
+#[derive(Debug)]
+struct SegmentCursor {
+    offset: usize,
+    ordinal: usize,
+    repository: Option<Arc<RepositoryEntry>>,
+}
+
+impl SegmentCursor {
+    fn new() -> Self {
+        Self {
+            offset: BINARY_HEADER_SIZE,
+            ordinal: 0,
+            repository: None,
+        }
+    }
+
+    /// Returns the next segment, parsing the repository records on the way.
+    ///
+    /// Only the record headers of the chunks are read, so this is cheap compared to scanning them.
+    fn next_segment(&mut self, data: &[u8]) -> orfail::Result<Option<Segment>> {
+        let mut segment: Option<Segment> = None;
+        while let Some((record, next)) = Record::parse(data, self.offset).or_fail()? {
+            if record.kind == RECORD_KIND_REPOSITORY {
+                if segment.is_some() {
+                    break;
+                }
+                self.repository = Some(Arc::new(record.parse_repository().or_fail()?));
+            } else {
+                let repository = self
+                    .repository
+                    .as_ref()
+                    .or_fail_with(|()| "Chunk record without repository".to_owned())?;
+                let segment = segment.get_or_insert_with(|| Segment {
+                    repository: Arc::clone(repository),
+                    start: self.offset,
+                    end: self.offset,
+                    first_ordinal: self.ordinal,
+                });
+                segment.end = next;
+                self.ordinal += 1;
+                if self.ordinal - segment.first_ordinal == SEGMENT_MAX_CHUNKS {
+                    self.offset = next;
+                    break;
+                }
+            }
+            self.offset = next;
+        }
+        Ok(segment)
+    }
+}
+
+/// Converts a query embedding into a unit vector to be passed to [`unit_cosine_similarity()`].
+pub fn unit_query_vector(query: &Embedding) -> Vec<f32> {
+    let norm = query.0.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm == 0.0 {
+        return vec![0.0; query.0.len()];
+    }
+    query.0.iter().map(|x| (x / norm) as f32).collect()
+}
+
+/// Returns the cosine similarity between a unit query vector and a vector.
+///
+/// If `normalized` is `true` (i.e., the vector was stored as a unit vector), only the dot product is computed.
+pub fn unit_cosine_similarity(query: &[f32], vector: &[f32], normalized: bool) -> f64 {
+    let dot_product = query
+        .iter()
+        .zip(vector)
+        .map(|(&x, &y)| f64::from(x) * f64::from(y))
+        .sum::<f64>();
+    if normalized {
+        return dot_product;
+    }
+
+    let norm = vector
+        .iter()
+        .map(|&x| f64::from(x) * f64::from(x))
+        .sum::<f64>()
+        .sqrt();
+    if norm == 0.0 {
+        return 0.0;
+    }
+    dot_product / norm
+}
+
+/// Bounded selection of the best matches.
+///
+/// Ties are broken in favour of the chunk that comes first in the index file.
+#[derive(Debug)]
+struct TopK {
+    count: usize,
+    heap: BinaryHeap<Reverse<Candidate>>,
+}
+
+impl TopK {
+    fn new(count: usize) -> Self {
+        Self {
+            count,
+            heap: BinaryHeap::with_capacity(count + 1),
+        }
+    }
+
+    fn accepts(&self, similarity: f64, ordinal: usize) -> bool {
+        if self.heap.len() < self.count {
+            return true;
+        }
+        self.heap.peek().is_some_and(|Reverse(worst)| {
+            Candidate::rank(similarity, ordinal, worst.similarity(), worst.ordinal).is_gt()
+        })
+    }
+
+    fn push(&mut self, ordinal: usize, chunk: MatchedChunk) {
+        self.heap.push(Reverse(Candidate { ordinal, chunk }));
+        if self.heap.len() > self.count {
+            self.heap.pop();
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        for Reverse(candidate) in other.heap {
+            if self.accepts(candidate.similarity(), candidate.ordinal) {
+                self.push(candidate.ordinal, candidate.chunk);
+            }
+        }
+    }
+
+    fn into_sorted_vec(self) -> Vec<MatchedChunk> {
+        // Ascending order of `Reverse<_>` is descending order of candidates
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(c)| c.chunk)
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct Candidate {
+    ordinal: usize,
+    chunk: MatchedChunk,
+}
+
+impl Candidate {
+    fn similarity(&self) -> f64 {
+        self.chunk.similarity
+    }
+
+    fn rank(
+        similarity: f64,
+        ordinal: usize,
+        other_similarity: f64,
+        other_ordinal: usize,
+    ) -> Ordering {
+        similarity
+            .total_cmp(&other_similarity)
+            .then_with(|| other_ordinal.cmp(&ordinal))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        Self::rank(
+            self.similarity(),
+            self.ordinal,
+            other.similarity(),
+            other.ordinal,
+        )
+    }
+}
+
+/// Aggregation of matched chunks by file or repository.
+#[derive(Debug)]
+struct ChunkGroups {
+    group_by: GroupBy,
+    groups: HashMap<(PathBuf, Option<PathBuf>), ChunkGroup>,
+}
+
+#[derive(Debug)]
+struct ChunkGroup {
+    best: Candidate,
+    similarity_sum: f64,
+    hit_count: usize,
+}
+
+impl ChunkGroups {
+    fn new(group_by: GroupBy) -> Self {
+        Self {
+            group_by,
+            groups: HashMap::new(),
         }
+    }
 
-        Ok(candidates)
+    fn key(&self, chunk: &MatchedChunk) -> (PathBuf, Option<PathBuf>) {
+        let file_path = match self.group_by {
+            GroupBy::File => Some(chunk.file_path.clone()),
+            GroupBy::Repository => None,
+        };
+        (chunk.repository_path.clone(), file_path)
     }
 
-    fn cosine_similarity(&self, a: &Embedding, b: &Embedding) -> f64 {
-        let a_vec = &a.0;
-        let b_vec = &b.0;
+    fn add(&mut self, ordinal: usize, chunk: MatchedChunk) {
+        let group = ChunkGroup {
+            similarity_sum: chunk.similarity,
+            hit_count: 1,
+            best: Candidate { ordinal, chunk },
+        };
+        self.add_group(group);
+    }
 
-        if a_vec.len() != b_vec.len() {
-            return 0.0;
+    fn add_group(&mut self, group: ChunkGroup) {
+        match self.groups.entry(self.key(&group.best.chunk)) {
+            Entry::Vacant(e) => {
+                e.insert(group);
+            }
+            Entry::Occupied(mut e) => {
+                let existing = e.get_mut();
+                existing.similarity_sum += group.similarity_sum;
+                existing.hit_count += group.hit_count;
+                if group.best > existing.best {
+                    existing.best = group.best;
+                }
+            }
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        for group in other.groups.into_values() {
+            self.add_group(group);
         }
+    }
+
+    fn into_sorted_vec(self, count: usize) -> Vec<MatchedGroup> {
+        let mut groups = self.groups.into_values().collect::<Vec<_>>();
+        groups.sort_by(|a, b| b.best.cmp(&a.best));
+        groups
+            .into_iter()
+            .take(count)
+            .map(|group| MatchedGroup {
+                mean_similarity: group.similarity_sum / group.hit_count as f64,
+                hit_count: group.hit_count,
+                best: group.best.chunk,
+            })
+            .collect()
+    }
+}
+
+/// A chunk of an index file, whose vector is borrowed from the file content where possible.
+#[derive(Debug)]
+pub struct ChunkRecord<'a> {
+    /// Position of the chunk among all chunks in the index file.
+    pub ordinal: usize,
+    metadata: ChunkRecordMetadata<'a>,
+    pub vector: &'a [f32],
+    normalized: bool,
+}
 
-        let dot_product: f64 = a_vec.iter().zip(b_vec.iter()).map(|(x, y)| x * y).sum();
-        let norm_a: f64 = a_vec.iter().map(|x| x * x).sum::<f64>().sqrt();
-        let norm_b: f64 = b_vec.iter().map(|x| x * x).sum::<f64>().sqrt();
+impl ChunkRecord<'_> {
+    /// Returns the cosine similarity to a query converted by [`unit_query_vector()`].
+    pub fn similarity(&self, query: &[f32]) -> f64 {
+        unit_cosine_similarity(query, self.vector, self.normalized)
+    }
 
-        if norm_a == 0.0 || norm_b == 0.0 {
-            return 0.0;
+    /// Returns the vector scaled to unit length.
+    pub fn unit_vector(&self) -> Vec<f32> {
+        if self.normalized {
+            return self.vector.to_vec();
         }
+        let norm = self
+            .vector
+            .iter()
+            .map(|&x| f64::from(x) * f64::from(x))
+            .sum::<f64>()
+            .sqrt();
+        if norm == 0.0 {
+            return self.vector.to_vec();
+        }
+        self.vector
+            .iter()
+            .map(|&x| (f64::from(x) / norm) as f32)
+            .collect()
+    }
 
-        dot_product / (norm_a * norm_b)
+    /// Returns the chunk entry without its embedding.
+    pub fn metadata(&self) -> orfail::Result<ChunkEntry> {
+        match self.metadata {
+            ChunkRecordMetadata::Parsed(chunk) => Ok(ChunkEntry {
+                embedding: Embedding(Vec::new()),
+                ..chunk.clone()
+            }),
+            ChunkRecordMetadata::Raw(text) => {
+                let nojson::Json(ChunkMetadata(chunk)) = text
+                    .parse::<nojson::Json<ChunkMetadata<ChunkEntry>>>()
+                    .or_fail()?;
+                Ok(chunk)
+            }
+        }
     }
 }
 
+#[derive(Debug)]
+enum ChunkRecordMetadata<'a> {
+    Parsed(&'a ChunkEntry),
+    Raw(&'a str),
+}
+
 #[derive(Debug, Clone)]
 pub struct MatchedChunk {
     pub repository_path: PathBuf,
-    pub chunk_window_size: NonZeroUsize,
+
+    /// Number of lines of the chunk (or of the merged line range, see [`merge_overlapping()`]).
+    ///
+    /// [`merge_overlapping()`]: crate::rerank::merge_overlapping
+    pub line_count: NonZeroUsize,
     pub file_path: PathBuf,
     pub line: usize,
+
+    /// Byte range of the chunk within its line, if the chunk is a piece of a line.
+    pub columns: Option<Range<usize>>,
+
+    /// Hash of the chunk text at indexing time (`None` for merged line ranges and old index entries).
+    pub hash: Option<ContentHash>,
     pub similarity: f64,
+
+    /// Unit vector of the chunk embedding.
+    pub vector: Vec<f32>,
 }
 
 impl MatchedChunk {
     pub fn relative_file_path(&self, current_dir: &Path) -> PathBuf {
-        let full_path = self.repository_path.join(&self.file_path);
+        relative_path(&self.repository_path.join(&self.file_path), current_dir)
+    }
 
-        if let Ok(relative_path) = full_path.strip_prefix(current_dir) {
-            // The file is within the current directory tree, return the direct relative path
-            return relative_path.to_path_buf();
+    pub fn relative_repository_path(&self, current_dir: &Path) -> PathBuf {
+        let path = relative_path(&self.repository_path, current_dir);
+        if path.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            path
         }
+    }
 
-        // The file is outside the current directory tree, compute path via common ancestor
-        let common_len = current_dir
-            .components()
-            .zip(full_path.components())
-            .take_while(|(a, b)| a == b)
-            .count();
-
-        if common_len == 0 {
-            // No common ancestor
-            return full_path;
-        }
+    /// Returns the key identifying the span of the chunk, which tells apart the pieces of a long line
+    /// (see [`Chunker::with_limits()`](crate::chunker::Chunker::with_limits)).
+    pub fn span_key(&self) -> (PathBuf, PathBuf, usize, NonZeroUsize, Option<Range<usize>>) {
+        (
+            self.repository_path.clone(),
+            self.file_path.clone(),
+            self.line,
+            self.line_count,
+            self.columns.clone(),
+        )
+    }
 
-        current_dir
-            .components()
-            .skip(common_len)
-            .map(|_| std::path::Component::ParentDir)
-            .chain(full_path.components().skip(common_len))
-            .collect()
+    /// Returns whether the text differs from the indexed one (i.e., the file changed since indexing).
+    pub fn is_stale(&self, text: &str) -> bool {
+        self.hash.is_some_and(|hash| ContentHash::of(text) != hash)
     }
 
-    pub fn chunk_text(&self) -> orfail::Result<String> {
+    /// Reads the text of the chunk from its file.
+    ///
+    /// Returns `None` if the file has been removed or no longer has the lines of the chunk.
+    pub fn chunk_text(&self) -> orfail::Result<Option<String>> {
         let full_path = self.repository_path.join(&self.file_path);
-        let text = std::fs::read_to_string(&full_path)
-            .or_fail_with(|e| format!("{e}: {}", full_path.display()))?;
-        Ok(text
-            .lines()
-            .skip(self.line)
-            .take(self.chunk_window_size.get())
-            .collect::<Vec<_>>()
-            .join("\n"))
+        let text = match std::fs::read_to_string(&full_path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(orfail::Failure::new(format!(
+                    "{e}: {}",
+                    full_path.display()
+                )));
+            }
+        };
+        Ok(extract_chunk_text(
+            &text,
+            self.line,
+            self.line_count.get(),
+            self.columns.as_ref(),
+        ))
     }
 }
 
+fn relative_path(full_path: &Path, current_dir: &Path) -> PathBuf {
+    if let Ok(relative_path) = full_path.strip_prefix(current_dir) {
+        // The path is within the current directory tree, return the direct relative path
+        return relative_path.to_path_buf();
+    }
+
+    // The path is outside the current directory tree, compute path via common ancestor
+    let common_len = current_dir
+        .components()
+        .zip(full_path.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    if common_len == 0 {
+        // No common ancestor
+        return full_path.to_path_buf();
+    }
+
+    current_dir
+        .components()
+        .skip(common_len)
+        .map(|_| std::path::Component::ParentDir)
+        .chain(full_path.components().skip(common_len))
+        .collect()
+}
+
+/// How search results are grouped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    File,
+    Repository,
+}
+
+impl std::str::FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(GroupBy::File),
+            "repository" => Ok(GroupBy::Repository),
+            _ => Err(format!(
+                "unknown grouping: expected 'file' or 'repository', found '{s}'"
+            )),
+        }
+    }
+}
+
+/// A file or repository containing matched chunks.
+#[derive(Debug, Clone)]
+pub struct MatchedGroup {
+    /// The best matching chunk of the group, whose similarity is the maximum of the group.
+    pub best: MatchedChunk,
+    pub mean_similarity: f64,
+
+    /// Number of matched chunks in the group.
+    pub hit_count: usize,
+}
+
+/// Groups ranked chunks, keeping the `count` groups with the best chunks.
+pub fn group_matched_chunks(
+    chunks: Vec<MatchedChunk>,
+    group_by: GroupBy,
+    count: usize,
+) -> Vec<MatchedGroup> {
+    let mut groups = ChunkGroups::new(group_by);
+    for (rank, chunk) in chunks.into_iter().enumerate() {
+        groups.add(rank, chunk);
+    }
+    groups.into_sorted_vec(count)
+}
+
 #[derive(Debug)]
 struct Entries {
     path: PathBuf,
-    lines: Option<std::io::Lines<std::io::BufReader<std::fs::File>>>,
+    state: EntriesState,
+
+    // Chunk records in binary index files can be skipped without decoding
+    skip_chunks: bool,
+}
+
+#[derive(Debug)]
+enum EntriesState {
+    Unopened(IndexFormat),
+    JsonLines(std::io::BufReader<std::fs::File>),
+    Binary { file: MappedFile, offset: usize },
 }
 
 impl Entries {
     fn next_entry(&mut self) -> orfail::Result<Option<IndexFileEntry>> {
-        let Some(lines) = &mut self.lines else {
-            let file = std::fs::File::open(&self.path).or_fail()?;
-            let reader = std::io::BufReader::new(file);
-            self.lines = Some(reader.lines());
-            return self.next_entry();
-        };
+        match &mut self.state {
+            EntriesState::Unopened(IndexFormat::JsonLines) => {
+                let file = std::fs::File::open(&self.path).or_fail()?;
+                self.state = EntriesState::JsonLines(std::io::BufReader::new(file));
+                self.next_entry()
+            }
+            EntriesState::Unopened(IndexFormat::Binary) => {
+                let file = MappedFile::open(&self.path).or_fail()?;
+                self.state = EntriesState::Binary {
+                    file,
+                    offset: BINARY_HEADER_SIZE,
+                };
+                self.next_entry()
+            }
+            EntriesState::JsonLines(reader) => {
+                let mut line = String::new();
+                if reader.read_line(&mut line).or_fail()? == 0 {
+                    return Ok(None);
+                }
+                let entry: IndexFileEntry = line
+                    .trim_end_matches(['\r', '\n'])
+                    .parse()
+                    .map(|nojson::Json(x)| x)
+                    .or_fail()?;
+                Ok(Some(entry))
+            }
+            EntriesState::Binary { file, offset } => loop {
+                let Some((record, next)) = Record::parse(file.as_bytes(), *offset).or_fail()?
+                else {
+                    return Ok(None);
+                };
+                *offset = next;
+                if self.skip_chunks && record.kind == RECORD_KIND_CHUNK {
+                    continue;
+                }
+                return record.to_entry().or_fail().map(Some);
+            },
+        }
+    }
+}
+
+fn encode_record(
+    kind: u8,
+    encoding: VectorEncoding,
+    flags: u16,
+    metadata: &str,
+    vector: &[f64],
+) -> Vec<u8> {
+    let metadata_size = metadata.len().next_multiple_of(4);
+    let mut record =
+        Vec::with_capacity(RECORD_HEADER_SIZE + metadata_size + encoding.block_size(vector.len()));
+    record.push(kind);
+    record.push(encoding.to_u8());
+    record.extend_from_slice(&flags.to_le_bytes());
+    record.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(vector.len() as u32).to_le_bytes());
+    record.extend_from_slice(metadata.as_bytes());
+    record.resize(RECORD_HEADER_SIZE + metadata_size, 0);
+    encoding.encode(vector, &mut record);
+    record
+}
+
+/// A record in a binary index file, borrowing from the file content.
+#[derive(Debug)]
+pub struct Record<'a> {
+    pub kind: u8,
+    pub encoding: VectorEncoding,
+    pub normalized: bool,
+    pub metadata: &'a str,
+    pub vector_len: usize,
+    pub vector_bytes: &'a [u8],
+}
 
-        let Some(line) = lines.next().transpose().or_fail()? else {
+impl<'a> Record<'a> {
+    /// Parses the record at `offset`, returning it with the offset of the next record.
+    pub fn parse(data: &'a [u8], offset: usize) -> orfail::Result<Option<(Self, usize)>> {
+        if offset == data.len() {
             return Ok(None);
+        }
+        let header = data
+            .get(offset..offset + RECORD_HEADER_SIZE)
+            .or_fail_with(|()| "Truncated index file record".to_owned())?;
+
+        let kind = header[0];
+        matches!(kind, RECORD_KIND_REPOSITORY | RECORD_KIND_CHUNK)
+            .or_fail_with(|()| format!("Unknown index file record kind: {kind}"))?;
+        let encoding = VectorEncoding::from_u8(header[1])
+            .or_fail_with(|()| format!("Unknown vector encoding: {}", header[1]))?;
+        let flags = u16::from_le_bytes([header[2], header[3]]);
+        let metadata_len =
+            u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let vector_len =
+            u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+
+        let metadata_start = offset + RECORD_HEADER_SIZE;
+        let vector_start = metadata_start + metadata_len.next_multiple_of(4);
+        let end = vector_start + encoding.block_size(vector_len);
+        (end <= data.len()).or_fail_with(|()| "Truncated index file record".to_owned())?;
+
+        let metadata =
+            std::str::from_utf8(&data[metadata_start..metadata_start + metadata_len]).or_fail()?;
+        let record = Self {
+            kind,
+            encoding,
+            // Older versions also set the flag for quantized vectors
+            normalized: flags & RECORD_FLAG_NORMALIZED != 0 && encoding == VectorEncoding::F32,
+            metadata,
+            vector_len,
+            vector_bytes: &data[vector_start..end],
         };
+        Ok(Some((record, end)))
+    }
+
+    pub fn parse_repository(&self) -> orfail::Result<RepositoryEntry> {
+        let nojson::Json(repo) = self
+            .metadata
+            .parse::<nojson::Json<RepositoryEntry>>()
+            .or_fail()?;
+        Ok(repo)
+    }
 
-        let entry: IndexFileEntry = line.parse().map(|nojson::Json(x)| x).or_fail()?;
-        Ok(Some(entry))
+    /// Returns the vector of this record.
+    ///
+    /// `f32` vectors are borrowed directly from the file content,
+    /// while other encodings are decoded into `buf`.
+    pub fn vector<'b>(&self, buf: &'b mut Vec<f32>) -> &'b [f32]
+    where
+        'a: 'b,
+    {
+        let bytes = self.vector_bytes;
+        if self.encoding == VectorEncoding::F32
+            && cfg!(target_endian = "little")
+            && bytes.as_ptr().align_offset(std::mem::align_of::<f32>()) == 0
+        {
+            // SAFETY: The bytes are properly aligned and any bit pattern is a valid `f32`.
+            return unsafe {
+                std::slice::from_raw_parts(bytes.as_ptr().cast::<f32>(), self.vector_len)
+            };
+        }
+
+        buf.clear();
+        self.encoding
+            .decode_into(self.vector_bytes, self.vector_len, buf);
+        buf
+    }
+
+    /// Parses the metadata of a chunk record into a chunk entry without its embedding.
+    pub fn parse_chunk_metadata(&self) -> orfail::Result<ChunkEntry> {
+        let nojson::Json(ChunkMetadata(chunk)) = self
+            .metadata
+            .parse::<nojson::Json<ChunkMetadata<ChunkEntry>>>()
+            .or_fail()?;
+        Ok(chunk)
+    }
+
+    fn to_entry(&self) -> orfail::Result<IndexFileEntry> {
+        if self.kind == RECORD_KIND_REPOSITORY {
+            return self.parse_repository().map(IndexFileEntry::Repository);
+        }
+
+        let mut chunk = self.parse_chunk_metadata().or_fail()?;
+        let mut vector = Vec::new();
+        let vector = self.vector(&mut vector);
+        chunk.embedding = Embedding(vector.iter().map(|&x| f64::from(x)).collect());
+        Ok(IndexFileEntry::Chunk(chunk))
     }
 }
 
@@ -266,8 +1211,18 @@ pub struct RepositoryEntry {
     pub commit: String,
     pub chunk_window_size: NonZeroUsize,
     pub chunk_step_size: NonZeroUsize,
+    pub chunk_strategy: ChunkStrategy,
+    pub chunk_max_chars: Option<NonZeroUsize>,
+    pub chunk_max_tokens: Option<NonZeroUsize>,
+    pub chunk_header: Vec<ChunkHeaderField>,
     pub include_files: Vec<GlobPathPattern>,
     pub exclude_files: Vec<GlobPathPattern>,
+    pub embedding_provider: Option<EmbeddingProviderKind>,
+    pub embedding_model: Option<String>,
+    pub embedding_dimensions: Option<usize>,
+    pub embedding_endpoint: Option<String>,
+    pub vector_dimension: Option<usize>,
+    pub vector_encoding: Option<VectorEncoding>,
 }
 
 impl nojson::DisplayJson for RepositoryEntry {
@@ -278,8 +1233,37 @@ impl nojson::DisplayJson for RepositoryEntry {
             f.member("commit", &self.commit)?;
             f.member("chunk_window_size", self.chunk_window_size)?;
             f.member("chunk_step_size", self.chunk_step_size)?;
+            f.member("chunk_strategy", self.chunk_strategy)?;
+            if let Some(max_chars) = self.chunk_max_chars {
+                f.member("chunk_max_chars", max_chars)?;
+            }
+            if let Some(max_tokens) = self.chunk_max_tokens {
+                f.member("chunk_max_tokens", max_tokens)?;
+            }
+            if !self.chunk_header.is_empty() {
+                f.member("chunk_header", &self.chunk_header)?;
+            }
             f.member("include_files", &self.include_files)?;
-            f.member("exclude_files", &self.exclude_files)
+            f.member("exclude_files", &self.exclude_files)?;
+            if let Some(provider) = self.embedding_provider {
+                f.member("embedding_provider", provider.to_string())?;
+            }
+            if let Some(model) = &self.embedding_model {
+                f.member("embedding_model", model)?;
+            }
+            if let Some(dimensions) = self.embedding_dimensions {
+                f.member("embedding_dimensions", dimensions)?;
+            }
+            if let Some(endpoint) = &self.embedding_endpoint {
+                f.member("embedding_endpoint", endpoint)?;
+            }
+            if let Some(dimension) = self.vector_dimension {
+                f.member("vector_dimension", dimension)?;
+            }
+            if let Some(encoding) = self.vector_encoding {
+                f.member("vector_encoding", encoding)?;
+            }
+            Ok(())
         })
     }
 }
@@ -297,7 +1281,18 @@ impl<'text> nojson::FromRawJsonValue<'text> for RepositoryEntry {
                 include_files,
                 exclude_files,
             ],
-            [],
+            [
+                chunk_strategy,
+                chunk_max_chars,
+                chunk_max_tokens,
+                chunk_header,
+                embedding_provider,
+                embedding_model,
+                embedding_dimensions,
+                embedding_endpoint,
+                vector_dimension,
+                vector_encoding,
+            ],
         ) = value.to_fixed_object(
             [
                 "path",
@@ -307,7 +1302,18 @@ impl<'text> nojson::FromRawJsonValue<'text> for RepositoryEntry {
                 "include_files",
                 "exclude_files",
             ],
-            [],
+            [
+                "chunk_strategy",
+                "chunk_max_chars",
+                "chunk_max_tokens",
+                "chunk_header",
+                "embedding_provider",
+                "embedding_model",
+                "embedding_dimensions",
+                "embedding_endpoint",
+                "vector_dimension",
+                "vector_encoding",
+            ],
         )?;
 
         Ok(Self {
@@ -315,8 +1321,31 @@ impl<'text> nojson::FromRawJsonValue<'text> for RepositoryEntry {
             commit: commit.try_to()?,
             chunk_window_size: chunk_window_size.try_to()?,
             chunk_step_size: chunk_step_size.try_to()?,
+            // Repositories indexed by older versions were split into line windows
+            chunk_strategy: chunk_strategy
+                .map(|v| v.try_to())
+                .transpose()?
+                .unwrap_or_default(),
+            chunk_max_chars: chunk_max_chars.map(|v| v.try_to()).transpose()?,
+            chunk_max_tokens: chunk_max_tokens.map(|v| v.try_to()).transpose()?,
+            chunk_header: chunk_header
+                .map(|v| v.try_to())
+                .transpose()?
+                .unwrap_or_default(),
             include_files: include_files.try_to()?,
             exclude_files: exclude_files.try_to()?,
+            embedding_provider: embedding_provider
+                .map(|v| {
+                    v.to_unquoted_string_str()?
+                        .parse()
+                        .map_err(|e| nojson::JsonParseError::invalid_value(v, e))
+                })
+                .transpose()?,
+            embedding_model: embedding_model.map(|v| v.try_to()).transpose()?,
+            embedding_dimensions: embedding_dimensions.map(|v| v.try_to()).transpose()?,
+            embedding_endpoint: embedding_endpoint.map(|v| v.try_to()).transpose()?,
+            vector_dimension: vector_dimension.map(|v| v.try_to()).transpose()?,
+            vector_encoding: vector_encoding.map(|v| v.try_to()).transpose()?,
         })
     }
 }
@@ -325,15 +1354,53 @@ impl<'text> nojson::FromRawJsonValue<'text> for RepositoryEntry {
 pub struct ChunkEntry {
     pub path: PathBuf,
     pub line: usize,
+
+    /// Number of lines of the chunk (`None` for entries indexed by older versions,
+    /// which span the chunk window size of their repository).
+    pub line_count: Option<NonZeroUsize>,
+
+    /// Byte range of the chunk within its line, if the chunk is a piece of a line
+    /// (see [`Chunker::with_limits()`](crate::chunker::Chunker::with_limits)).
+    pub columns: Option<Range<usize>>,
+    pub hash: Option<ContentHash>,
+
+    /// Hash of the embedded text if it differs from the chunk text, i.e., the chunk has a header
+    /// (see [`Chunk::embedding_text()`](crate::chunker::Chunk::embedding_text)).
+    pub embedding_hash: Option<ContentHash>,
+
+    /// Number of occurrences of each term in the chunk text (see [`term_counts()`](crate::lexical_search::term_counts)).
+    pub terms: Option<BTreeMap<String, u32>>,
     pub embedding: Embedding,
 }
 
+impl ChunkEntry {
+    /// Returns the hash of the embedded text (`None` for entries indexed by older versions).
+    pub fn embedding_key(&self) -> Option<ContentHash> {
+        self.embedding_hash.or(self.hash)
+    }
+}
+
 impl nojson::DisplayJson for ChunkEntry {
     fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
         f.object(|f| {
             f.member("type", "chunk")?;
             f.member("path", &self.path)?;
             f.member("line", self.line)?;
+            if let Some(line_count) = self.line_count {
+                f.member("line_count", line_count)?;
+            }
+            if let Some(columns) = &self.columns {
+                f.member("columns", [columns.start, columns.end])?;
+            }
+            if let Some(hash) = self.hash {
+                f.member("hash", hash)?;
+            }
+            if let Some(hash) = self.embedding_hash {
+                f.member("embedding_hash", hash)?;
+            }
+            if let Some(terms) = &self.terms {
+                f.member("terms", terms)?;
+            }
             f.member("embedding", &self.embedding)
         })
     }
@@ -343,12 +1410,434 @@ impl<'text> nojson::FromRawJsonValue<'text> for ChunkEntry {
     fn from_raw_json_value(
         value: nojson::RawJsonValue<'text, '_>,
     ) -> Result<Self, nojson::JsonParseError> {
-        let ([path, line, embedding], []) =
-            value.to_fixed_object(["path", "line", "embedding"], [])?;
+        let ([path, line, embedding], [line_count, columns, hash, embedding_hash, terms]) = value
+            .to_fixed_object(
+            ["path", "line", "embedding"],
+            ["line_count", "columns", "hash", "embedding_hash", "terms"],
+        )?;
         Ok(Self {
             path: path.try_to()?,
             line: line.try_to()?,
+            line_count: line_count.map(|v| v.try_to()).transpose()?,
+            columns: parse_columns(columns)?,
+            hash: hash.map(|v| v.try_to()).transpose()?,
+            embedding_hash: embedding_hash.map(|v| v.try_to()).transpose()?,
+            terms: terms.map(|v| v.try_to()).transpose()?,
             embedding: embedding.try_to()?,
         })
     }
 }
+
+fn parse_columns(
+    value: Option<nojson::RawJsonValue<'_, '_>>,
+) -> Result<Option<Range<usize>>, nojson::JsonParseError> {
+    value
+        .map(|v| v.try_to().map(|[start, end]: [usize; 2]| start..end))
+        .transpose()
+}
+
+/// The JSON metadata of a chunk record in binary index files (i.e., a chunk entry without its embedding).
+#[derive(Debug)]
+struct ChunkMetadata<T>(T);
+
+impl nojson::DisplayJson for ChunkMetadata<&ChunkEntry> {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.object(|f| {
+            f.member("path", &self.0.path)?;
+            f.member("line", self.0.line)?;
+            if let Some(line_count) = self.0.line_count {
+                f.member("line_count", line_count)?;
+            }
+            if let Some(columns) = &self.0.columns {
+                f.member("columns", [columns.start, columns.end])?;
+            }
+            if let Some(hash) = self.0.hash {
+                f.member("hash", hash)?;
+            }
+            if let Some(hash) = self.0.embedding_hash {
+                f.member("embedding_hash", hash)?;
+            }
+            if let Some(terms) = &self.0.terms {
+                f.member("terms", terms)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<'text> nojson::FromRawJsonValue<'text> for ChunkMetadata<ChunkEntry> {
+    fn from_raw_json_value(
+        value: nojson::RawJsonValue<'text, '_>,
+    ) -> Result<Self, nojson::JsonParseError> {
+        let ([path, line], [line_count, columns, hash, embedding_hash, terms]) = value
+            .to_fixed_object(
+                ["path", "line"],
+                ["line_count", "columns", "hash", "embedding_hash", "terms"],
+            )?;
+        Ok(Self(ChunkEntry {
+            path: path.try_to()?,
+            line: line.try_to()?,
+            line_count: line_count.map(|v| v.try_to()).transpose()?,
+            columns: parse_columns(columns)?,
+            hash: hash.map(|v| v.try_to()).transpose()?,
+            embedding_hash: embedding_hash.map(|v| v.try_to()).transpose()?,
+            terms: terms.map(|v| v.try_to()).transpose()?,
+            embedding: Embedding(Vec::new()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, temp_path};
+
+    fn repository_entry(vector_encoding: Option<VectorEncoding>) -> RepositoryEntry {
+        RepositoryEntry {
+            vector_dimension: Some(3),
+            vector_encoding,
+            ..test_util::repository_entry()
+        }
+    }
+
+    fn chunk_entry(path: &str, embedding: &[f64]) -> ChunkEntry {
+        ChunkEntry {
+            path: PathBuf::from(path),
+            line: 7,
+            line_count: None,
+            columns: None,
+            hash: Some(ContentHash::of(path)),
+            embedding_hash: None,
+            terms: None,
+            embedding: Embedding(embedding.to_vec()),
+        }
+    }
+
+    fn read_chunks(index_file: &IndexFile) -> Vec<(PathBuf, Vec<f64>)> {
+        index_file
+            .entries()
+            .filter_map(|e| match e.expect("entry") {
+                IndexFileEntry::Chunk(c) => Some((c.path, c.embedding.0)),
+                IndexFileEntry::Repository(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn binary_index_file_roundtrip() {
+        let path = temp_path("binary-roundtrip");
+        let index_file = IndexFile::create_new(&path, IndexFormat::Binary).expect("create");
+        index_file
+            .append_repository(&repository_entry(None))
+            .expect("append");
+        index_file
+            .append_chunk(&chunk_entry("a.rs", &[0.5, -0.5, 0.5, 0.5]))
+            .expect("append");
+        index_file
+            .append_repository(&repository_entry(Some(VectorEncoding::Int8)))
+            .expect("append");
+        index_file
+            .append_chunk(&chunk_entry("b.rs", &[3.0, -4.0, 0.0]))
+            .expect("append");
+
+        let index_file = IndexFile::load(&path).expect("load");
+        assert_eq!(index_file.format, IndexFormat::Binary);
+        assert_eq!(std::fs::metadata(&path).expect("metadata").len() % 4, 0);
+
+        let repos = index_file
+            .repositories()
+            .collect::<orfail::Result<Vec<_>>>()
+            .expect("repositories");
+        assert_eq!(repos.len(), 2);
+        assert_eq!(repos[1].vector_encoding, Some(VectorEncoding::Int8));
+        let chunks = read_chunks(&index_file);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(
+            chunks[0],
+            (PathBuf::from("a.rs"), vec![0.5, -0.5, 0.5, 0.5])
+        );
+
+        // Vectors are stored as unit vectors
+        assert_eq!(chunks[1].0, PathBuf::from("b.rs"));
+        for (x, y) in chunks[1].1.iter().zip([0.6, -0.8, 0.0]) {
+            assert!((x - y).abs() < 0.01, "{x} vs {y}");
+        }
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn json_lines_index_file_is_still_readable() {
+        let path = temp_path("jsonl");
+        let index_file = IndexFile::create_new(&path, IndexFormat::JsonLines).expect("create");
+        index_file
+            .append_repository(&repository_entry(None))
+            .expect("append");
+        index_file
+            .append_chunk(&chunk_entry("a.rs", &[0.1, 0.2, 0.3]))
+            .expect("append");
+
+        let index_file = IndexFile::load(&path).expect("load");
+        assert_eq!(index_file.format, IndexFormat::JsonLines);
+        assert_eq!(
+            read_chunks(&index_file),
+            [(PathBuf::from("a.rs"), vec![0.1, 0.2, 0.3])]
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn excluded_repositories_may_have_other_dimensions() {
+        let path = temp_path("excluded-dimensions");
+        let index_file = IndexFile::create_new(&path, IndexFormat::Binary).expect("create");
+        index_file
+            .append_repository(&repository_entry(None))
+            .expect("append");
+        index_file
+            .append_chunk(&chunk_entry("a.rs", &[1.0, 0.0, 0.0]))
+            .expect("append");
+        index_file
+            .append_repository(&RepositoryEntry {
+                path: PathBuf::from("/other"),
+                vector_dimension: Some(2),
+                ..test_util::repository_entry()
+            })
+            .expect("append");
+        index_file
+            .append_chunk(&chunk_entry("b.rs", &[1.0, 0.0]))
+            .expect("append");
+
+        let query = Embedding(vec![1.0, 0.0, 0.0]);
+        assert!(
+            index_file
+                .search(&query, 10, 0.0, &GlobPathFilter::default(), 1)
+                .is_err()
+        );
+        let filter = GlobPathFilter {
+            include_files: Vec::new(),
+            exclude_files: vec![GlobPathPattern::new("/other/*")],
+        };
+        let matched = index_file
+            .search(&query, 10, 0.0, &filter, 1)
+            .expect("search");
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].file_path, PathBuf::from("a.rs"));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn quantized_vectors_are_renormalized() {
+        let vector = [0.3, -0.4, 0.5];
+        let query = unit_query_vector(&Embedding(vector.to_vec()));
+        for encoding in [
+            VectorEncoding::F32,
+            VectorEncoding::F16,
+            VectorEncoding::Int8,
+        ] {
+            let path = temp_path(&format!("normalized-{encoding}"));
+            let index_file = IndexFile::create_new(&path, IndexFormat::Binary).expect("create");
+            index_file
+                .append_repository(&repository_entry(Some(encoding)))
+                .expect("append");
+            index_file
+                .append_chunk(&chunk_entry("0.rs", &vector))
+                .expect("append");
+
+            index_file
+                .for_each_chunk(|_, chunk| {
+                    assert_eq!(chunk.normalized, encoding == VectorEncoding::F32);
+                    let similarity = chunk.similarity(&query);
+                    assert!((similarity - 1.0).abs() < 1e-4, "{encoding}: {similarity}");
+                    Ok(())
+                })
+                .expect("scan");
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn search_gives_same_results_for_all_formats() {
+        let vectors = [
+            [1.0, 0.0, 0.0],
+            [0.6, 0.8, 0.0],
+            [0.0, 0.0, 1.0],
+            [0.7, 0.7, 0.1],
+        ];
+        let query = Embedding(vec![1.0, 0.1, 0.0]);
+
+        let mut results = Vec::new();
+        for (name, format, encoding) in [
+            ("search-jsonl", IndexFormat::JsonLines, None),
+            ("search-f32", IndexFormat::Binary, Some(VectorEncoding::F32)),
+            ("search-f16", IndexFormat::Binary, Some(VectorEncoding::F16)),
+        ] {
+            let path = temp_path(name);
+            let index_file = IndexFile::create_new(&path, format).expect("create");
+            index_file
+                .append_repository(&repository_entry(encoding))
+                .expect("append");
+            for (i, vector) in vectors.iter().enumerate() {
+                index_file
+                    .append_chunk(&chunk_entry(&format!("{i}.rs"), vector))
+                    .expect("append");
+            }
+
+            let matched = index_file
+                .search(&query, 2, 0.5, &GlobPathFilter::default(), 2)
+                .expect("search");
+            results.push(
+                matched
+                    .into_iter()
+                    .map(|m| m.file_path.display().to_string())
+                    .collect::<Vec<_>>(),
+            );
+            let _ = std::fs::remove_file(&path);
+        }
+
+        assert_eq!(results[0], ["0.rs", "3.rs"]);
+        assert_eq!(results[1], results[0]);
+        assert_eq!(results[2], results[0]);
+    }
+
+    #[test]
+    fn top_k_prefers_earlier_chunks_on_ties() {
+        let path = temp_path("search-ties");
+        let index_file = IndexFile::create_new(&path, IndexFormat::Binary).expect("create");
+        index_file
+            .append_repository(&repository_entry(None))
+            .expect("append");
+        for (name, vector) in [
+            ("0.rs", [0.0, 1.0, 0.0]),
+            ("1.rs", [2.0, 0.0, 0.0]),
+            ("2.rs", [1.0, 0.0, 0.0]),
+            ("3.rs", [1.0, 1.0, 0.0]),
+            ("4.rs", [3.0, 0.0, 0.0]),
+        ] {
+            index_file
+                .append_chunk(&chunk_entry(name, &vector))
+                .expect("append");
+        }
+
+        let matched = index_file
+            .search(
+                &Embedding(vec![5.0, 0.0, 0.0]),
+                2,
+                0.0,
+                &GlobPathFilter::default(),
+                1,
+            )
+            .expect("search");
+        let paths = matched
+            .iter()
+            .map(|m| m.file_path.display().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["1.rs", "2.rs"]);
+        assert_eq!(matched[0].similarity, 1.0);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn parallel_search_matches_sequential_search() {
+        let path = temp_path("search-parallel");
+        let index_file = IndexFile::create_new(&path, IndexFormat::Binary).expect("create");
+        for repo in 0..6 {
+            let mut entry = repository_entry(None);
+            entry.path = PathBuf::from(format!("/repo{repo}"));
+            index_file.append_repository(&entry).expect("append");
+            for i in 0..40 {
+                // Many chunks share the same vector, so ties must be broken consistently
+                let x = ((repo * 7 + i) % 5) as f64;
+                index_file
+                    .append_chunk(&chunk_entry(&format!("{i}.rs"), &[1.0, x, 0.5]))
+                    .expect("append");
+            }
+        }
+
+        let query = Embedding(vec![1.0, 2.0, 0.0]);
+        let filter = GlobPathFilter::default();
+        let summarize = |matched: Vec<MatchedChunk>| {
+            matched
+                .into_iter()
+                .map(|m| {
+                    let path = m.repository_path.join(m.file_path);
+                    (path.display().to_string(), m.similarity)
+                })
+                .collect::<Vec<_>>()
+        };
+        let sequential = summarize(
+            index_file
+                .search(&query, 25, 0.1, &filter, 1)
+                .expect("search"),
+        );
+        assert_eq!(sequential.len(), 25);
+        for threads in [2, 3, 8] {
+            let parallel = summarize(
+                index_file
+                    .search(&query, 25, 0.1, &filter, threads)
+                    .expect("search"),
+            );
+            assert_eq!(parallel, sequential);
+        }
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn search_groups_aggregates_chunks() {
+        let path = temp_path("search-groups");
+        let index_file = IndexFile::create_new(&path, IndexFormat::Binary).expect("create");
+        index_file
+            .append_repository(&repository_entry(None))
+            .expect("append");
+        for (file, line, vector) in [
+            ("a.rs", 0, [0.0, 1.0, 0.0]),
+            ("a.rs", 3, [1.0, 1.0, 0.0]),
+            ("a.rs", 5, [1.0, 0.0, 0.0]),
+            ("b.rs", 2, [1.0, 0.2, 0.0]),
+            ("c.rs", 0, [0.0, 0.0, 1.0]),
+        ] {
+            let mut chunk = chunk_entry(file, &vector);
+            chunk.line = line;
+            index_file.append_chunk(&chunk).expect("append");
+        }
+
+        let query = Embedding(vec![1.0, 0.0, 0.0]);
+        let filter = GlobPathFilter::default();
+        for threads in [1, 2] {
+            let groups = index_file
+                .search_groups(&query, 10, 0.5, &filter, GroupBy::File, threads)
+                .expect("search");
+            let summary = groups
+                .iter()
+                .map(|g| (g.best.file_path.clone(), g.best.line, g.hit_count))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                summary,
+                [(PathBuf::from("a.rs"), 5, 2), (PathBuf::from("b.rs"), 2, 1)]
+            );
+            assert!((groups[0].best.similarity - 1.0).abs() < 1e-6);
+            let mean = (1.0 + std::f64::consts::FRAC_1_SQRT_2) / 2.0;
+            assert!((groups[0].mean_similarity - mean).abs() < 1e-6);
+
+            let groups = index_file
+                .search_groups(&query, 10, 0.5, &filter, GroupBy::Repository, threads)
+                .expect("search");
+            assert_eq!(groups.len(), 1);
+            assert_eq!(groups[0].hit_count, 3);
+            assert_eq!(groups[0].best.file_path, PathBuf::from("a.rs"));
+        }
+
+        let chunks = index_file
+            .search(&query, 10, 0.5, &filter, 1)
+            .expect("search");
+        let groups = group_matched_chunks(chunks, GroupBy::File, 1);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].best.line, 5);
+        assert_eq!(groups[0].hit_count, 2);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
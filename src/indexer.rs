@@ -0,0 +1,548 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    num::NonZeroUsize,
+    path::PathBuf,
+};
+
+use orfail::OrFail;
+
+use crate::{
+    chunker::{Chunk, ContentHash},
+    embedder::{Embedding, EmbeddingProvider},
+    embedding_cache::{EmbeddingCache, model_key},
+    index_file::{ChunkEntry, IndexFile},
+    lexical_search::term_counts,
+};
+
+/// Limits on the size of a single embedding request.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchLimits {
+    pub max_inputs: usize,
+    pub max_tokens: usize,
+}
+
+impl BatchLimits {
+    pub fn take(args: &mut noargs::RawArgs) -> noargs::Result<Self> {
+        let max_inputs: usize = noargs::opt("batch-max-inputs")
+            .ty("COUNT")
+            .doc("Maximum number of chunks sent in a single embedding request")
+            .env("DOKOSA_BATCH_MAX_INPUTS")
+            .default("2048")
+            .take(args)
+            .then(|a| a.value().parse())?;
+        let max_tokens: usize = noargs::opt("batch-max-tokens")
+            .ty("COUNT")
+            .doc("Maximum number of (estimated) tokens sent in a single embedding request")
+            .env("DOKOSA_BATCH_MAX_TOKENS")
+            .default("100000")
+            .take(args)
+            .then(|a| a.value().parse())?;
+        Ok(Self {
+            max_inputs: max_inputs.max(1),
+            max_tokens,
+        })
+    }
+}
+
+/// Number of bytes per token assumed by [`estimate_tokens()`].
+pub const ESTIMATED_BYTES_PER_TOKEN: usize = 3;
+
+/// Roughly estimates the number of tokens in a text.
+///
+/// This intentionally overestimates for typical source code (about 4 bytes per token)
+/// so that batches stay under the API limits without a real tokenizer.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(ESTIMATED_BYTES_PER_TOKEN)
+}
+
+/// Embeds texts in batches within the limits, returning one embedding per text in the same order.
+pub fn embed_in_batches(
+    embedder: &dyn EmbeddingProvider,
+    texts: &[String],
+    limits: BatchLimits,
+) -> orfail::Result<Vec<Embedding>> {
+    let mut embeddings = Vec::with_capacity(texts.len());
+    let mut start = 0;
+    while start < texts.len() {
+        let mut end = start + 1;
+        let mut tokens = estimate_tokens(&texts[start]);
+        while let Some(text) = texts.get(end) {
+            let text_tokens = estimate_tokens(text);
+            if end - start >= limits.max_inputs || tokens + text_tokens > limits.max_tokens {
+                break;
+            }
+            tokens += text_tokens;
+            end += 1;
+        }
+        let batch = embedder.embed(&texts[start..end]).or_fail()?;
+        (batch.len() == end - start).or_fail()?;
+        embeddings.extend(batch);
+        start = end;
+    }
+    Ok(embeddings)
+}
+
+/// Embeds the chunks of files in batches that may span multiple files,
+/// and appends the resulting chunk entries to an index file in the order the files were added.
+#[derive(Debug)]
+pub struct Indexer<'a> {
+    embedder: &'a dyn EmbeddingProvider,
+    limits: BatchLimits,
+    cache: Option<&'a mut EmbeddingCache>,
+    model_key: String,
+    files: VecDeque<PendingFile>,
+    first_file_id: usize,
+    batch: Vec<(usize, usize)>,
+    batch_tokens: usize,
+    pub failed_files: Vec<PathBuf>,
+
+    /// Number of chunks whose embeddings were taken from the previous versions of their files.
+    pub reused: usize,
+}
+
+impl<'a> Indexer<'a> {
+    pub fn new(embedder: &'a dyn EmbeddingProvider, limits: BatchLimits) -> Self {
+        Self {
+            embedder,
+            limits,
+            cache: None,
+            model_key: model_key(embedder),
+            files: VecDeque::new(),
+            first_file_id: 0,
+            batch: Vec::new(),
+            batch_tokens: 0,
+            failed_files: Vec::new(),
+            reused: 0,
+        }
+    }
+
+    /// Makes this indexer reuse cached embeddings of unchanged content and cache new ones.
+    pub fn with_cache(mut self, cache: Option<&'a mut EmbeddingCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn add_file(
+        &mut self,
+        index_file: &IndexFile,
+        path: PathBuf,
+        chunks: Vec<Chunk<String>>,
+    ) -> orfail::Result<()> {
+        self.add_updated_file(index_file, path, chunks, &HashMap::new())
+    }
+
+    /// Same as [`Indexer::add_file()`], but reuses the embeddings of the previous version of the file,
+    /// keyed by the hashes of their embedded texts (see [`ChunkEntry::embedding_key()`]).
+    ///
+    /// The previous embeddings take precedence over the cache, as they come from the same index.
+    pub fn add_updated_file(
+        &mut self,
+        index_file: &IndexFile,
+        path: PathBuf,
+        chunks: Vec<Chunk<String>>,
+        previous_embeddings: &HashMap<ContentHash, Embedding>,
+    ) -> orfail::Result<()> {
+        let file_id = self.first_file_id + self.files.len();
+        let chunk_count = chunks.len();
+        let hashes = chunks
+            .iter()
+            .map(|c| ContentHash::of(&c.data))
+            .collect::<Vec<_>>();
+        let terms = chunks
+            .iter()
+            .map(|c| term_counts(&c.data))
+            .collect::<Vec<_>>();
+
+        // Embeddings are cached by the embedded text, which may differ from the chunk data
+        let cache_keys = chunks
+            .iter()
+            .map(|c| ContentHash::of(&c.embedding_text()))
+            .collect::<Vec<_>>();
+        let mut embeddings = Vec::with_capacity(chunk_count);
+        for &key in &cache_keys {
+            let embedding = if let Some(embedding) = previous_embeddings.get(&key) {
+                self.reused += 1;
+                Some(embedding.clone())
+            } else if let Some(cache) = &mut self.cache {
+                cache.get(&self.model_key, key).or_fail()?
+            } else {
+                None
+            };
+            embeddings.push(embedding);
+        }
+        self.files.push_back(PendingFile {
+            path,
+            embeddings,
+            hashes,
+            cache_keys,
+            terms,
+            chunks,
+            failed: false,
+        });
+
+        for chunk_index in 0..chunk_count {
+            if self.files.back().or_fail()?.embeddings[chunk_index].is_some() {
+                continue;
+            }
+            let tokens =
+                estimate_tokens(&self.files.back().or_fail()?.chunks[chunk_index].embedding_text());
+            if !self.batch.is_empty()
+                && (self.batch.len() >= self.limits.max_inputs
+                    || self.batch_tokens + tokens > self.limits.max_tokens)
+            {
+                self.flush(index_file).or_fail()?;
+            }
+            self.batch.push((file_id, chunk_index));
+            self.batch_tokens += tokens;
+        }
+        self.write_completed_files(index_file).or_fail()?;
+        Ok(())
+    }
+
+    /// Embeds all pending chunks and writes the remaining files to the index file.
+    pub fn finish(&mut self, index_file: &IndexFile) -> orfail::Result<()> {
+        self.flush(index_file).or_fail()?;
+        self.write_completed_files(index_file).or_fail()?;
+        (self.files.is_empty() && self.batch.is_empty()).or_fail()?;
+        Ok(())
+    }
+
+    fn flush(&mut self, index_file: &IndexFile) -> orfail::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let batch = std::mem::take(&mut self.batch);
+        self.batch_tokens = 0;
+        let inputs = batch
+            .iter()
+            .map(|&(file_id, chunk_index)| {
+                let chunk = &mut self.files[file_id - self.first_file_id].chunks[chunk_index];
+                let text = chunk.embedding_text().into_owned();
+                chunk.data = String::new();
+                text
+            })
+            .collect::<Vec<_>>();
+
+        match self.embedder.embed(&inputs) {
+            Ok(embeddings) => {
+                (embeddings.len() == batch.len()).or_fail()?;
+                for ((file_id, chunk_index), embedding) in batch.into_iter().zip(embeddings) {
+                    let file = &mut self.files[file_id - self.first_file_id];
+                    if let Some(cache) = &mut self.cache {
+                        cache
+                            .insert(&self.model_key, file.cache_keys[chunk_index], &embedding)
+                            .or_fail()?;
+                    }
+                    file.embeddings[chunk_index] = Some(embedding);
+                }
+            }
+            Err(e) => {
+                eprintln!("  Failed to embed: {e}");
+                for (file_id, _) in batch {
+                    self.files[file_id - self.first_file_id].failed = true;
+                }
+            }
+        }
+
+        self.write_completed_files(index_file).or_fail()?;
+        Ok(())
+    }
+
+    fn write_completed_files(&mut self, index_file: &IndexFile) -> orfail::Result<()> {
+        while let Some(file) = self.files.front() {
+            if !file.failed && file.embeddings.iter().any(|e| e.is_none()) {
+                break;
+            }
+
+            // A failed file may still have chunks waiting in the current batch.
+            let file_id = self.first_file_id;
+            if file.failed && self.batch.iter().any(|&(id, _)| id == file_id) {
+                break;
+            }
+
+            let file = self.files.pop_front().or_fail()?;
+            self.first_file_id += 1;
+            if file.failed {
+                self.failed_files.push(file.path);
+                continue;
+            }
+            for ((((chunk, hash), cache_key), terms), embedding) in file
+                .chunks
+                .iter()
+                .zip(file.hashes)
+                .zip(file.cache_keys)
+                .zip(file.terms)
+                .zip(file.embeddings)
+            {
+                index_file
+                    .append_chunk(&ChunkEntry {
+                        path: file.path.clone(),
+                        line: chunk.line,
+                        line_count: NonZeroUsize::new(chunk.line_count),
+                        columns: chunk.columns.clone(),
+                        hash: Some(hash),
+                        embedding_hash: (cache_key != hash).then_some(cache_key),
+                        terms: Some(terms),
+                        embedding: embedding.or_fail()?,
+                    })
+                    .or_fail()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct PendingFile {
+    path: PathBuf,
+    chunks: Vec<Chunk<String>>,
+    hashes: Vec<ContentHash>,
+    cache_keys: Vec<ContentHash>,
+    terms: Vec<BTreeMap<String, u32>>,
+    embeddings: Vec<Option<Embedding>>,
+    failed: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{
+        embedder::EmbeddingProviderKind,
+        index_file::{IndexFileEntry, IndexFormat},
+        test_util::temp_path,
+    };
+
+    /// Embeds each text as a one-dimensional vector holding its length, recording batch sizes.
+    #[derive(Debug, Default)]
+    struct LengthEmbedder {
+        batches: RefCell<Vec<usize>>,
+        fail: bool,
+    }
+
+    impl EmbeddingProvider for LengthEmbedder {
+        fn kind(&self) -> EmbeddingProviderKind {
+            EmbeddingProviderKind::Lexical
+        }
+
+        fn model(&self) -> &str {
+            "length"
+        }
+
+        fn dimension(&self) -> Option<usize> {
+            Some(1)
+        }
+
+        fn embed(&self, input_texts: &[String]) -> orfail::Result<Vec<Embedding>> {
+            self.batches.borrow_mut().push(input_texts.len());
+            (!self.fail).or_fail()?;
+            Ok(input_texts
+                .iter()
+                .map(|t| Embedding(vec![t.len() as f64]))
+                .collect())
+        }
+    }
+
+    fn chunks(texts: &[&str]) -> Vec<Chunk<String>> {
+        texts
+            .iter()
+            .enumerate()
+            .map(|(i, t)| Chunk {
+                line: i,
+                line_count: 1,
+                columns: None,
+                header: None,
+                data: t.to_string(),
+            })
+            .collect()
+    }
+
+    fn temp_index_file(name: &str) -> IndexFile {
+        IndexFile::create_new(temp_path(name), IndexFormat::JsonLines).expect("create")
+    }
+
+    fn written_chunks(index_file: &IndexFile) -> Vec<(String, usize, f64)> {
+        index_file
+            .entries()
+            .map(|e| match e.expect("entry") {
+                IndexFileEntry::Chunk(c) => {
+                    (c.path.display().to_string(), c.line, c.embedding.0[0])
+                }
+                IndexFileEntry::Repository(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batches_span_files_and_preserve_order() {
+        let index_file = temp_index_file("batches");
+        let embedder = LengthEmbedder::default();
+        let limits = BatchLimits {
+            max_inputs: 3,
+            max_tokens: 100,
+        };
+
+        let mut indexer = Indexer::new(&embedder, limits);
+        indexer
+            .add_file(&index_file, "a".into(), chunks(&["a", "aa"]))
+            .expect("add");
+        indexer
+            .add_file(&index_file, "b".into(), chunks(&["b", "bb", "bbb"]))
+            .expect("add");
+        indexer
+            .add_file(&index_file, "c".into(), chunks(&["c"]))
+            .expect("add");
+        indexer.finish(&index_file).expect("finish");
+
+        assert_eq!(*embedder.batches.borrow(), [3, 3]);
+        assert_eq!(
+            written_chunks(&index_file),
+            [
+                ("a".to_owned(), 0, 1.0),
+                ("a".to_owned(), 1, 2.0),
+                ("b".to_owned(), 0, 1.0),
+                ("b".to_owned(), 1, 2.0),
+                ("b".to_owned(), 2, 3.0),
+                ("c".to_owned(), 0, 1.0),
+            ]
+        );
+        std::fs::remove_file(&index_file.path).expect("remove");
+    }
+
+    #[test]
+    fn batches_respect_token_limit() {
+        let index_file = temp_index_file("tokens");
+        let embedder = LengthEmbedder::default();
+        let limits = BatchLimits {
+            max_inputs: 100,
+            max_tokens: 4,
+        };
+
+        let mut indexer = Indexer::new(&embedder, limits);
+        let text = "x".repeat(6); // 2 tokens
+        indexer
+            .add_file(&index_file, "a".into(), chunks(&[&text, &text, &text]))
+            .expect("add");
+        indexer.finish(&index_file).expect("finish");
+
+        assert_eq!(*embedder.batches.borrow(), [2, 1]);
+        std::fs::remove_file(&index_file.path).expect("remove");
+    }
+
+    #[test]
+    fn texts_are_embedded_in_batches() {
+        let embedder = LengthEmbedder::default();
+        let limits = BatchLimits {
+            max_inputs: 2,
+            max_tokens: 4,
+        };
+        let texts = ["a", "bb", "ccc", &"d".repeat(12), "e"].map(|t| t.to_owned());
+        let embeddings = embed_in_batches(&embedder, &texts, limits).expect("embed");
+
+        assert_eq!(*embedder.batches.borrow(), [2, 1, 1, 1]);
+        assert_eq!(
+            embeddings.iter().map(|e| e.0[0]).collect::<Vec<_>>(),
+            [1.0, 2.0, 3.0, 12.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn failed_batches_are_reported() {
+        let index_file = temp_index_file("failed");
+        let embedder = LengthEmbedder {
+            fail: true,
+            ..Default::default()
+        };
+        let limits = BatchLimits {
+            max_inputs: 10,
+            max_tokens: 100,
+        };
+
+        let mut indexer = Indexer::new(&embedder, limits);
+        indexer
+            .add_file(&index_file, "a".into(), chunks(&["a"]))
+            .expect("add");
+        indexer
+            .add_file(&index_file, "b".into(), chunks(&["b"]))
+            .expect("add");
+        indexer.finish(&index_file).expect("finish");
+
+        assert_eq!(
+            indexer.failed_files,
+            [PathBuf::from("a"), PathBuf::from("b")]
+        );
+        assert!(written_chunks(&index_file).is_empty());
+        std::fs::remove_file(&index_file.path).expect("remove");
+    }
+
+    #[test]
+    fn cached_chunks_are_not_reembedded() {
+        let index_file = temp_index_file("cached");
+        let cache_path = index_file.path.with_extension("cache");
+        let _ = std::fs::remove_file(&cache_path);
+        let mut cache = EmbeddingCache::open(&cache_path).expect("open");
+        let embedder = LengthEmbedder::default();
+        let limits = BatchLimits {
+            max_inputs: 10,
+            max_tokens: 100,
+        };
+
+        let mut indexer = Indexer::new(&embedder, limits).with_cache(Some(&mut cache));
+        indexer
+            .add_file(&index_file, "a".into(), chunks(&["foo", "bar"]))
+            .expect("add");
+        indexer.finish(&index_file).expect("finish");
+
+        let mut indexer = Indexer::new(&embedder, limits).with_cache(Some(&mut cache));
+        indexer
+            .add_file(&index_file, "b".into(), chunks(&["bar", "bazz"]))
+            .expect("add");
+        indexer.finish(&index_file).expect("finish");
+
+        assert_eq!(*embedder.batches.borrow(), [2, 1]);
+        assert_eq!(cache.hits, 1);
+        assert_eq!(written_chunks(&index_file)[2], ("b".to_owned(), 0, 3.0));
+        std::fs::remove_file(&index_file.path).expect("remove");
+        std::fs::remove_file(&cache_path).expect("remove");
+    }
+
+    #[test]
+    fn previous_embeddings_are_reused() {
+        let index_file = temp_index_file("previous");
+        let embedder = LengthEmbedder::default();
+        let limits = BatchLimits {
+            max_inputs: 10,
+            max_tokens: 100,
+        };
+        let mut headed = chunks(&["foo"]);
+        headed[0].header = Some("Path: a".to_owned());
+
+        // Embeddings are looked up by the embedded text, including the header
+        let previous = HashMap::from([
+            (ContentHash::of("foo"), Embedding(vec![10.0])),
+            (ContentHash::of("bar"), Embedding(vec![20.0])),
+        ]);
+        let mut indexer = Indexer::new(&embedder, limits);
+        indexer
+            .add_updated_file(&index_file, "a".into(), chunks(&["bar", "bazz"]), &previous)
+            .expect("add");
+        indexer
+            .add_updated_file(&index_file, "a".into(), headed, &previous)
+            .expect("add");
+        indexer.finish(&index_file).expect("finish");
+
+        assert_eq!(*embedder.batches.borrow(), [2]);
+        assert_eq!(indexer.reused, 1);
+        assert_eq!(
+            written_chunks(&index_file),
+            [
+                ("a".to_owned(), 0, 20.0),
+                ("a".to_owned(), 1, 4.0),
+                ("a".to_owned(), 0, 12.0),
+            ]
+        );
+        std::fs::remove_file(&index_file.path).expect("remove");
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{
+    chunker::ContentHash,
+    embedder::{Embedding, EmbeddingProvider, EmbeddingProviderKind},
+};
+
+/// Default dimension of the vectors generated by [`LexicalEmbedder`].
+pub const DEFAULT_LEXICAL_DIMENSION: usize = 1024;
+
+/// An embedding provider that works fully offline.
+///
+/// Each text is converted into a fixed-size vector by hashing its word tokens and
+/// character trigrams into buckets (the "hashing trick"), with sublinear term frequency weighting.
+/// This captures lexical rather than semantic similarity, but needs no external service.
+#[derive(Debug)]
+pub struct LexicalEmbedder {
+    dimension: usize,
+}
+
+impl LexicalEmbedder {
+    pub fn new(dimension: usize) -> Self {
+        Self {
+            dimension: dimension.max(1),
+        }
+    }
+
+    fn embed_one(&self, text: &str) -> Embedding {
+        // Feature hash => (weight, count)
+        let mut counts = std::collections::HashMap::<u64, (f64, usize)>::new();
+        let mut add_feature = |feature: &str, weight: f64| {
+            let hash = ContentHash::of(feature).0;
+            counts.entry(hash).or_insert((weight, 0)).1 += 1;
+        };
+
+        for token in tokenize(text) {
+            add_feature(&token, 1.0);
+
+            let chars = format!("^{token}$").chars().collect::<Vec<_>>();
+            for trigram in chars.windows(3) {
+                add_feature(&trigram.iter().collect::<String>(), 0.5);
+            }
+        }
+
+        let mut vector = vec![0.0; self.dimension];
+        for (hash, (weight, count)) in counts {
+            let bucket = (hash % self.dimension as u64) as usize;
+            let value = weight * (1.0 + (count as f64).ln());
+            // The top bit decides the sign so that collisions tend to cancel out rather than add up.
+            vector[bucket] += if hash >> 63 == 1 { -value } else { value };
+        }
+
+        let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        Embedding(vector)
+    }
+}
+
+impl EmbeddingProvider for LexicalEmbedder {
+    fn kind(&self) -> EmbeddingProviderKind {
+        EmbeddingProviderKind::Lexical
+    }
+
+    fn model(&self) -> &str {
+        "lexical-v1"
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        Some(self.dimension)
+    }
+
+    fn embed(&self, input_texts: &[String]) -> orfail::Result<Vec<Embedding>> {
+        Ok(input_texts.iter().map(|t| self.embed_one(t)).collect())
+    }
+}
+
+/// Splits a text into lowercase word tokens.
+///
+/// Identifiers are also split into their parts (e.g., "parseJson" and "parse_json"
+/// both yield "parse" and "json"), in addition to the whole identifier.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .flat_map(|word| {
+            let parts = split_identifier(word);
+            let whole = (parts.len() > 1).then(|| word.to_lowercase());
+            parts.into_iter().chain(whole)
+        })
+}
+
+fn split_identifier(word: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut prev: Option<char> = None;
+    for c in word.chars() {
+        let boundary = c == '_'
+            || prev.is_some_and(|p| {
+                (p.is_lowercase() && c.is_uppercase())
+                    || (p.is_alphabetic() != c.is_alphabetic() && p != '_')
+            });
+        if boundary && !current.is_empty() {
+            parts.push(std::mem::take(&mut current));
+        }
+        if c != '_' {
+            current.extend(c.to_lowercase());
+        }
+        prev = Some(c);
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn similarity(a: &Embedding, b: &Embedding) -> f64 {
+        a.0.iter().zip(&b.0).map(|(x, y)| x * y).sum()
+    }
+
+    #[test]
+    fn tokenize_splits_identifiers() {
+        assert_eq!(
+            tokenize("fn parseJson(raw_text: &str)").collect::<Vec<_>>(),
+            [
+                "fn",
+                "parse",
+                "json",
+                "parsejson",
+                "raw",
+                "text",
+                "raw_text",
+                "str"
+            ]
+        );
+        assert_eq!(
+            tokenize("HTTP2 v1").collect::<Vec<_>>(),
+            ["http", "2", "http2", "v", "1", "v1"]
+        );
+    }
+
+    #[test]
+    fn lexical_embeddings_reflect_shared_terms() {
+        let embedder = LexicalEmbedder::new(256);
+        let embeddings = embedder
+            .embed(&[
+                "fn parse_json(text: &str) -> Json".to_owned(),
+                "parse json text".to_owned(),
+                "let socket = TcpListener::bind(addr)".to_owned(),
+            ])
+            .expect("embed");
+
+        for e in &embeddings {
+            assert_eq!(e.0.len(), 256);
+            assert!((similarity(e, e) - 1.0).abs() < 1e-9);
+        }
+        assert!(
+            similarity(&embeddings[0], &embeddings[1]) > similarity(&embeddings[0], &embeddings[2])
+        );
+        assert!(similarity(&embeddings[0], &embeddings[1]) > 0.3);
+
+        let again = embedder
+            .embed(&["parse json text".to_owned()])
+            .expect("embed");
+        assert_eq!(again[0].0, embeddings[1].0);
+    }
+
+    #[test]
+    fn empty_text_yields_zero_vector() {
+        let embedding = LexicalEmbedder::new(8)
+            .embed(&[String::new()])
+            .expect("embed");
+        assert_eq!(embedding[0].0, [0.0; 8]);
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::{BTreeMap, HashMap, hash_map::Entry};
+
+use orfail::OrFail;
+
+use crate::{
+    embedder::Embedding,
+    glob::GlobPathFilter,
+    index_file::{
+        IndexFile, IndexFormat, MatchedChunk, Record, RepositoryEntry, unit_query_vector,
+    },
+    lexical_embedder::tokenize,
+    mmap::MappedFile,
+    term_index::TermIndex,
+};
+
+/// Tokens longer than this (e.g., base64 blobs or minified code) are not indexed.
+const MAX_TERM_LEN: usize = 64;
+
+/// BM25 term frequency saturation parameter.
+const BM25_K1: f64 = 1.2;
+
+/// BM25 document length normalization parameter.
+const BM25_B: f64 = 0.75;
+
+/// Constant of reciprocal rank fusion that dampens the influence of top ranks.
+const RRF_K: f64 = 60.0;
+
+/// Counts the occurrences of each term in a text.
+///
+/// The terms are the tokens produced by [`tokenize()`], so that identifiers
+/// can be found both as a whole and by their parts.
+pub fn term_counts(text: &str) -> BTreeMap<String, u32> {
+    let mut counts = BTreeMap::new();
+    for token in tokenize(text).filter(|t| t.len() <= MAX_TERM_LEN) {
+        *counts.entry(token).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Returns the `count` chunks that best match the terms of the query, ranked by BM25.
+///
+/// The `similarity` field of the results holds the BM25 score.
+/// Chunk entries without terms (i.e., indexed by older versions) are not searched.
+/// The vectors of the results are only read if `with_vectors` is `true` (e.g., for re-ranking).
+///
+/// Binary index files are searched through their [`TermIndex`], which is kept up to date
+/// by `add`, `sync` and `remove`. JSON Lines index files (and binary ones whose term index
+/// is missing or out of date) are scanned instead, reading the terms of every chunk.
+pub fn search(
+    index_file: &IndexFile,
+    query: &str,
+    count: usize,
+    filter: &GlobPathFilter,
+    with_vectors: bool,
+) -> orfail::Result<Vec<MatchedChunk>> {
+    let query_terms = term_counts(query).into_keys().collect::<Vec<_>>();
+    if query_terms.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if index_file.format == IndexFormat::Binary {
+        match TermIndex::load(index_file).or_fail()? {
+            Some(term_index) if term_index.is_up_to_date(index_file).or_fail()? => {
+                return search_term_index(
+                    index_file,
+                    &term_index,
+                    &query_terms,
+                    count,
+                    filter,
+                    with_vectors,
+                )
+                .or_fail();
+            }
+            _ => eprintln!(
+                "Term index is missing or out of date (run `dokosa sync` to update it); \
+                 scanning all chunks"
+            ),
+        }
+    }
+    scan_chunks(index_file, &query_terms, count, filter, with_vectors).or_fail()
+}
+
+/// Searches the chunks containing the query terms via the postings of the term index.
+fn search_term_index(
+    index_file: &IndexFile,
+    term_index: &TermIndex,
+    query_terms: &[String],
+    count: usize,
+    filter: &GlobPathFilter,
+    with_vectors: bool,
+) -> orfail::Result<Vec<MatchedChunk>> {
+    warn_unindexed(term_index.unindexed_count());
+
+    let mut frequencies = HashMap::<u32, Vec<u32>>::new();
+    let mut document_frequencies = Vec::with_capacity(query_terms.len());
+    for (i, term) in query_terms.iter().enumerate() {
+        let postings = term_index.postings(term).or_fail()?;
+        document_frequencies.push(postings.len());
+        for (chunk, tf) in postings {
+            frequencies
+                .entry(chunk)
+                .or_insert_with(|| vec![0; query_terms.len()])[i] = tf;
+        }
+    }
+
+    let bm25 = Bm25::new(
+        term_index.chunk_count(),
+        term_index.total_len(),
+        &document_frequencies,
+    );
+    let mut scored = frequencies
+        .into_iter()
+        .map(|(chunk, frequencies)| {
+            let (offset, len) = term_index.chunk(chunk).or_fail()?;
+            Ok((chunk, offset, bm25.score(u64::from(len), &frequencies)))
+        })
+        .collect::<orfail::Result<Vec<_>>>()?;
+    scored.sort_by(|(a_chunk, _, a_score), (b_chunk, _, b_score)| {
+        b_score.total_cmp(a_score).then(a_chunk.cmp(b_chunk))
+    });
+
+    // Only the best chunks are read from the index file, as the filter is applied to them
+    let file = MappedFile::open(&index_file.path).or_fail()?;
+    let data = file.as_bytes();
+    let mut repositories = HashMap::<u64, RepositoryEntry>::new();
+    let mut matched = Vec::new();
+    for (_, offset, score) in scored {
+        if matched.len() == count {
+            break;
+        }
+        let (record, _) = Record::parse(data, offset as usize).or_fail()?.or_fail()?;
+        let chunk = record.parse_chunk_metadata().or_fail()?;
+        let repository_offset = term_index.repository_offset(offset).or_fail()?;
+        let repository = match repositories.entry(repository_offset) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let (record, _) = Record::parse(data, repository_offset as usize)
+                    .or_fail()?
+                    .or_fail()?;
+                e.insert(record.parse_repository().or_fail()?)
+            }
+        };
+        if !filter.matches(repository.path.join(&chunk.path)) {
+            continue;
+        }
+        let vector = if with_vectors {
+            let mut buf = Vec::new();
+            let vector = record.vector(&mut buf);
+            unit_query_vector(&Embedding(vector.iter().map(|&x| f64::from(x)).collect()))
+        } else {
+            Vec::new()
+        };
+        matched.push(MatchedChunk {
+            repository_path: repository.path.clone(),
+            line_count: chunk.line_count.unwrap_or(repository.chunk_window_size),
+            columns: chunk.columns,
+            file_path: chunk.path,
+            line: chunk.line,
+            hash: chunk.hash,
+            similarity: score,
+            vector,
+        });
+    }
+    Ok(matched)
+}
+
+/// Searches the chunks containing the query terms by reading the terms of every chunk.
+fn scan_chunks(
+    index_file: &IndexFile,
+    query_terms: &[String],
+    count: usize,
+    filter: &GlobPathFilter,
+    with_vectors: bool,
+) -> orfail::Result<Vec<MatchedChunk>> {
+    // Corpus statistics are gathered over all chunks, while only matching chunks are kept.
+    let mut chunk_count = 0usize;
+    let mut unindexed_chunk_count = 0usize;
+    let mut total_len = 0u64;
+    let mut document_frequencies = vec![0usize; query_terms.len()];
+    let mut candidates = Vec::new();
+    index_file
+        .for_each_chunk(|repository, chunk| {
+            let ordinal = chunk.ordinal;
+            let metadata = chunk.metadata().or_fail()?;
+            let Some(terms) = &metadata.terms else {
+                unindexed_chunk_count += 1;
+                return Ok(());
+            };
+            let len = terms.values().map(|&n| u64::from(n)).sum::<u64>();
+            chunk_count += 1;
+            total_len += len;
+
+            let frequencies = query_terms
+                .iter()
+                .map(|term| terms.get(term).copied().unwrap_or(0))
+                .collect::<Vec<_>>();
+            if frequencies.iter().all(|&n| n == 0) {
+                return Ok(());
+            }
+            for (df, &tf) in document_frequencies.iter_mut().zip(&frequencies) {
+                *df += usize::from(tf > 0);
+            }
+
+            if !filter.matches(repository.path.join(&metadata.path)) {
+                return Ok(());
+            }
+            let chunk = MatchedChunk {
+                repository_path: repository.path.clone(),
+                line_count: metadata.line_count.unwrap_or(repository.chunk_window_size),
+                columns: metadata.columns,
+                file_path: metadata.path,
+                line: metadata.line,
+                hash: metadata.hash,
+                similarity: 0.0,
+                vector: Vec::new(),
+            };
+            candidates.push((ordinal, chunk, len, frequencies));
+            Ok(())
+        })
+        .or_fail()?;
+    warn_unindexed(unindexed_chunk_count);
+
+    let bm25 = Bm25::new(chunk_count, total_len, &document_frequencies);
+    let mut matched = candidates
+        .into_iter()
+        .map(|(ordinal, mut chunk, len, frequencies)| {
+            chunk.similarity = bm25.score(len, &frequencies);
+            (ordinal, chunk)
+        })
+        .collect::<Vec<_>>();
+    matched.sort_by(|(a_ordinal, a), (b_ordinal, b)| {
+        b.similarity
+            .total_cmp(&a.similarity)
+            .then(a_ordinal.cmp(b_ordinal))
+    });
+    matched.truncate(count);
+    if !with_vectors || matched.is_empty() {
+        return Ok(matched.into_iter().map(|(_, chunk)| chunk).collect());
+    }
+
+    // Vectors are only copied for the results, as there may be many candidates
+    let positions = matched
+        .iter()
+        .enumerate()
+        .map(|(i, (ordinal, _))| (*ordinal, i))
+        .collect::<HashMap<_, _>>();
+    index_file
+        .for_each_chunk(|_, chunk| {
+            if let Some(&i) = positions.get(&chunk.ordinal) {
+                matched[i].1.vector = chunk.unit_vector();
+            }
+            Ok(())
+        })
+        .or_fail()?;
+    Ok(matched.into_iter().map(|(_, chunk)| chunk).collect())
+}
+
+fn warn_unindexed(unindexed_chunk_count: usize) {
+    if unindexed_chunk_count > 0 {
+        eprintln!(
+            "{unindexed_chunk_count} chunk(s) have no term index and are excluded from lexical search \
+             (run `dokosa sync` to build it)"
+        );
+    }
+}
+
+/// BM25 scoring of chunks against the query terms, given the corpus statistics.
+#[derive(Debug)]
+struct Bm25 {
+    idfs: Vec<f64>,
+    average_len: f64,
+}
+
+impl Bm25 {
+    fn new(chunk_count: usize, total_len: u64, document_frequencies: &[usize]) -> Self {
+        let idfs = document_frequencies
+            .iter()
+            .map(|&df| {
+                let df = df as f64;
+                (1.0 + (chunk_count as f64 - df + 0.5) / (df + 0.5)).ln()
+            })
+            .collect();
+        Self {
+            idfs,
+            average_len: total_len as f64 / chunk_count.max(1) as f64,
+        }
+    }
+
+    /// Returns the score of a chunk of `len` terms with the given frequencies of the query terms.
+    fn score(&self, len: u64, frequencies: &[u32]) -> f64 {
+        let norm = BM25_K1 * (1.0 - BM25_B + BM25_B * len as f64 / self.average_len.max(1.0));
+        frequencies
+            .iter()
+            .zip(&self.idfs)
+            .filter(|&(&tf, _)| tf > 0)
+            .map(|(&tf, idf)| {
+                let tf = f64::from(tf);
+                idf * tf * (BM25_K1 + 1.0) / (tf + norm)
+            })
+            .sum()
+    }
+}
+
+/// Combines multiple rankings of chunks into one by reciprocal rank fusion.
+///
+/// Each chunk (identified by [`MatchedChunk::span_key()`]) scores `1 / (60 + rank)` per ranking it appears in, and the `similarity` field
+/// of the results holds the sum of these scores. Ties keep the order of first appearance.
+pub fn reciprocal_rank_fusion(rankings: Vec<Vec<MatchedChunk>>, count: usize) -> Vec<MatchedChunk> {
+    let mut fused = Vec::<MatchedChunk>::new();
+    let mut positions = HashMap::<_, usize>::new();
+    for ranking in rankings {
+        for (rank, mut chunk) in ranking.into_iter().enumerate() {
+            let score = 1.0 / (RRF_K + rank as f64 + 1.0);
+            let key = chunk.span_key();
+            if let Some(&i) = positions.get(&key) {
+                fused[i].similarity += score;
+            } else {
+                positions.insert(key, fused.len());
+                chunk.similarity = score;
+                fused.push(chunk);
+            }
+        }
+    }
+    fused.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    fused.truncate(count);
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, path::PathBuf};
+
+    use super::*;
+    use crate::{
+        embedder::Embedding,
+        glob::GlobPathPattern,
+        index_file::{ChunkEntry, IndexFormat, RepositoryEntry},
+        test_util::{repository_entry, temp_path},
+    };
+
+    fn matched_chunk(path: &str, line: usize) -> MatchedChunk {
+        MatchedChunk {
+            repository_path: PathBuf::from("/repo"),
+            line_count: NonZeroUsize::MIN,
+            columns: None,
+            file_path: PathBuf::from(path),
+            line,
+            hash: None,
+            similarity: 0.0,
+            vector: Vec::new(),
+        }
+    }
+
+    fn paths(chunks: &[MatchedChunk]) -> Vec<String> {
+        chunks
+            .iter()
+            .map(|c| format!("{}:{}", c.file_path.display(), c.line))
+            .collect()
+    }
+
+    #[test]
+    fn bm25_ranks_chunks_by_matching_terms() {
+        for format in [IndexFormat::JsonLines, IndexFormat::Binary] {
+            let path = temp_path(&format!("lexical-{format}"));
+            let index_file = IndexFile::create_new(&path, format).expect("create");
+            index_file
+                .append_repository(&RepositoryEntry {
+                    vector_dimension: Some(1),
+                    ..repository_entry()
+                })
+                .expect("append");
+            let texts = [
+                ("a.rs", Some("fn parse_config(text: &str) -> Config")),
+                ("b.rs", Some("fn open(path: &str) -> File")),
+                (
+                    "c.rs",
+                    Some("let config = load(path); let text = config.text;"),
+                ),
+                ("d.rs", None),
+                ("e.rs", Some("fn parse(text: &str)")),
+            ];
+            for (path, text) in texts {
+                index_file
+                    .append_chunk(&ChunkEntry {
+                        path: PathBuf::from(path),
+                        line: 0,
+                        line_count: None,
+                        columns: None,
+                        hash: None,
+                        embedding_hash: None,
+                        terms: text.map(term_counts),
+                        embedding: Embedding(vec![1.0]),
+                    })
+                    .expect("append");
+            }
+
+            // Binary index files are searched via their term index, which gives the same scores
+            let filter = GlobPathFilter::default();
+            let scanned = search(&index_file, "parse config", 10, &filter, true).expect("search");
+            TermIndex::update_sidecar(&index_file).expect("update");
+            let term_index = TermIndex::load(&index_file).expect("load");
+            assert_eq!(term_index.is_some(), format == IndexFormat::Binary);
+
+            let matched = search(&index_file, "parse config", 10, &filter, true).expect("search");
+            assert_eq!(paths(&matched), ["a.rs:0", "c.rs:0", "e.rs:0"], "{format}");
+            assert!(
+                matched
+                    .windows(2)
+                    .all(|w| w[0].similarity > w[1].similarity)
+            );
+            assert!(
+                matched
+                    .iter()
+                    .zip(&scanned)
+                    .all(|(a, b)| a.similarity == b.similarity)
+            );
+            assert!(matched.iter().all(|c| c.vector == [1.0]));
+
+            let matched = search(&index_file, "parse config", 1, &filter, false).expect("search");
+            assert_eq!(paths(&matched), ["a.rs:0"]);
+            assert!(matched[0].vector.is_empty());
+
+            let matched = search(&index_file, "socket", 10, &filter, false).expect("search");
+            assert!(matched.is_empty());
+
+            let filter = GlobPathFilter {
+                include_files: vec![GlobPathPattern::new("*c.rs")],
+                exclude_files: Vec::new(),
+            };
+            let matched = search(&index_file, "parse config", 10, &filter, false).expect("search");
+            assert_eq!(paths(&matched), ["c.rs:0"]);
+
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::remove_file(TermIndex::sidecar_path(&path));
+        }
+    }
+
+    #[test]
+    fn fusion_favors_chunks_ranked_by_both() {
+        let semantic = vec![
+            matched_chunk("a.rs", 0),
+            matched_chunk("b.rs", 0),
+            matched_chunk("c.rs", 0),
+        ];
+        let lexical = vec![
+            matched_chunk("c.rs", 0),
+            matched_chunk("d.rs", 0),
+            matched_chunk("b.rs", 0),
+        ];
+        let fused = reciprocal_rank_fusion(vec![semantic, lexical], 3);
+        assert_eq!(paths(&fused), ["c.rs:0", "b.rs:0", "a.rs:0"]);
+        assert!((fused[0].similarity - (1.0 / 63.0 + 1.0 / 61.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn fusion_keeps_pieces_of_a_line_apart() {
+        let piece = |columns| MatchedChunk {
+            columns: Some(columns),
+            ..matched_chunk("a.rs", 0)
+        };
+        let semantic = vec![piece(0..10), piece(10..20)];
+        let lexical = vec![piece(10..20)];
+        let fused = reciprocal_rank_fusion(vec![semantic, lexical], 3);
+        let columns = fused.iter().map(|c| c.columns.clone()).collect::<Vec<_>>();
+        assert_eq!(columns, [Some(10..20), Some(0..10)]);
+        assert!((fused[0].similarity - (1.0 / 62.0 + 1.0 / 61.0)).abs() < 1e-12);
+    }
+}
@@ -1,10 +1,26 @@
 pub mod chunker;
+pub mod document_chunker;
 pub mod embedder;
+pub mod embedding_cache;
 pub mod git;
 pub mod glob;
+pub mod hnsw;
+pub mod http;
 pub mod index_file;
+pub mod indexer;
+pub mod lexical_embedder;
+pub mod lexical_search;
+pub mod mmap;
+pub mod rerank;
+pub mod search_output;
 pub mod subcommand_add;
+pub mod subcommand_export;
 pub mod subcommand_list;
 pub mod subcommand_remove;
 pub mod subcommand_search;
 pub mod subcommand_sync;
+pub mod syntax_chunker;
+pub mod term_index;
+#[cfg(test)]
+pub mod test_util;
+pub mod vector_encoding;
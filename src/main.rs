@@ -40,6 +40,12 @@ fn main() -> noargs::Result<()> {
         .is_present()
     {
         dokosa::subcommand_search::run(args)?;
+    } else if noargs::cmd("export")
+        .doc("Export the index in JSON Lines or binary format")
+        .take(&mut args)
+        .is_present()
+    {
+        dokosa::subcommand_export::run(args)?;
     } else if let Some(help) = args.finish()? {
         print!("{help}");
         return Ok(());
@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use orfail::OrFail;
+
+/// A read-only view of the whole content of a file.
+///
+/// On 64-bit Unix platforms the file is memory-mapped, so that its content is read lazily by the OS
+/// and shared between processes. Elsewhere (or for empty files) the content is read into memory.
+/// In both cases the content starts at a 4-byte aligned address.
+///
+/// Note that the file must not be truncated while mapped.
+/// Index files are only ever appended to or replaced by renaming, which is safe.
+#[derive(Debug)]
+pub struct MappedFile {
+    inner: Inner,
+}
+
+#[derive(Debug)]
+enum Inner {
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    Mapped {
+        ptr: *const u8,
+        len: usize,
+    },
+    Buffer {
+        buf: Vec<u32>,
+        len: usize,
+    },
+}
+
+impl MappedFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> orfail::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .or_fail_with(|e| format!("Failed to open {}: {e}", path.display()))?;
+        let len = usize::try_from(file.metadata().or_fail()?.len()).or_fail()?;
+
+        #[cfg(all(unix, target_pointer_width = "64"))]
+        if len > 0 {
+            use std::os::fd::AsRawFd;
+
+            // SAFETY: The arguments are valid and the result is checked below.
+            let ptr = unsafe {
+                sys::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    sys::PROT_READ,
+                    sys::MAP_PRIVATE,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr != sys::MAP_FAILED {
+                return Ok(Self {
+                    inner: Inner::Mapped {
+                        ptr: ptr.cast(),
+                        len,
+                    },
+                });
+            }
+            // Fall back to reading the file
+        }
+
+        Self::read(file, len).or_fail()
+    }
+
+    fn read(mut file: std::fs::File, len: usize) -> orfail::Result<Self> {
+        use std::io::Read;
+
+        // `u32` elements guarantee the 4-byte alignment
+        let mut buf = vec![0u32; len.div_ceil(4)];
+        // SAFETY: `buf` owns at least `len` initialized bytes.
+        let bytes = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), len) };
+        file.read_exact(bytes).or_fail()?;
+        Ok(Self {
+            inner: Inner::Buffer { buf, len },
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match &self.inner {
+            #[cfg(all(unix, target_pointer_width = "64"))]
+            // SAFETY: The mapping is valid and read-only until `self` is dropped.
+            Inner::Mapped { ptr, len } => unsafe { std::slice::from_raw_parts(*ptr, *len) },
+            // SAFETY: `buf` owns at least `len` initialized bytes.
+            Inner::Buffer { buf, len } => unsafe {
+                std::slice::from_raw_parts(buf.as_ptr().cast::<u8>(), *len)
+            },
+        }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        #[cfg(all(unix, target_pointer_width = "64"))]
+        if let Inner::Mapped { ptr, len } = self.inner {
+            // SAFETY: The region was mapped by `mmap()` and is no longer referenced.
+            unsafe {
+                sys::munmap(ptr.cast_mut().cast(), len);
+            }
+        }
+    }
+}
+
+#[cfg(all(unix, target_pointer_width = "64"))]
+mod sys {
+    use std::ffi::{c_int, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const MAP_PRIVATE: c_int = 2;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    unsafe extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_path;
+
+    #[test]
+    fn map_file_content() {
+        let path = temp_path("mmap");
+        std::fs::write(&path, b"hello world").expect("write");
+
+        let mapped = MappedFile::open(&path).expect("open");
+        assert_eq!(mapped.as_bytes(), b"hello world");
+        assert_eq!(mapped.as_bytes().as_ptr().align_offset(4), 0);
+
+        let file = std::fs::File::open(&path).expect("open");
+        let read = MappedFile::read(file, 11).expect("read");
+        assert_eq!(read.as_bytes(), b"hello world");
+        assert_eq!(read.as_bytes().as_ptr().align_offset(4), 0);
+
+        std::fs::write(&path, b"").expect("write");
+        assert_eq!(MappedFile::open(&path).expect("open").as_bytes(), b"");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,194 @@
+use std::num::NonZeroUsize;
+
+use crate::index_file::MatchedChunk;
+
+/// Merges chunks of the same file whose line ranges overlap or are adjacent into a single range.
+///
+/// The chunks must be ordered by rank. A merged range takes the place, similarity and vector
+/// of its highest ranked chunk, so that the order of the results is kept.
+/// Merged ranges have no hash nor columns, as they do not correspond to indexed chunks.
+pub fn merge_overlapping(chunks: Vec<MatchedChunk>) -> Vec<MatchedChunk> {
+    let mut merged = Vec::<MatchedChunk>::new();
+    for chunk in chunks {
+        let mut start = chunk.line;
+        let mut end = chunk.line + chunk.line_count.get();
+
+        // As kept ranges never touch each other, a chunk joining several of them
+        // merges them all into the first one.
+        let mut target = None;
+        let mut i = 0;
+        while i < merged.len() {
+            let other = &merged[i];
+            let other_end = other.line + other.line_count.get();
+            let touches = other.repository_path == chunk.repository_path
+                && other.file_path == chunk.file_path
+                && other.line <= end
+                && start <= other_end;
+            if !touches {
+                i += 1;
+                continue;
+            }
+
+            start = start.min(other.line);
+            end = end.max(other_end);
+            if target.is_none() {
+                target = Some(i);
+                i += 1;
+            } else {
+                merged.remove(i);
+            }
+        }
+
+        match target {
+            Some(i) => {
+                merged[i].line = start;
+                merged[i].line_count =
+                    NonZeroUsize::new(end - start).unwrap_or(merged[i].line_count);
+                merged[i].columns = None;
+                merged[i].hash = None;
+            }
+            None => merged.push(chunk),
+        }
+    }
+    merged
+}
+
+/// Selects up to `count` chunks by maximal marginal relevance (MMR).
+///
+/// Each step selects the chunk maximizing `lambda * relevance - (1 - lambda) * redundancy`,
+/// where the relevance is the similarity relative to the best one and the redundancy is
+/// the highest cosine similarity to the embeddings of the already selected chunks.
+/// So `lambda = 1.0` keeps the original ranking and lower values favor diverse results.
+pub fn maximal_marginal_relevance(
+    chunks: Vec<MatchedChunk>,
+    lambda: f64,
+    count: usize,
+) -> Vec<MatchedChunk> {
+    let max_similarity = chunks.iter().map(|c| c.similarity).fold(0.0, f64::max);
+    let relevances = chunks
+        .iter()
+        .map(|c| {
+            if max_similarity > 0.0 {
+                c.similarity / max_similarity
+            } else {
+                0.0
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut redundancies = vec![f64::NEG_INFINITY; chunks.len()];
+    let mut remaining = chunks.into_iter().map(Some).collect::<Vec<_>>();
+
+    let mut selected = Vec::<MatchedChunk>::new();
+    while selected.len() < count {
+        let mut best = None;
+        for (i, chunk) in remaining.iter().enumerate() {
+            if chunk.is_none() {
+                continue;
+            }
+            let redundancy = if selected.is_empty() {
+                0.0
+            } else {
+                redundancies[i]
+            };
+            let score = lambda * relevances[i] - (1.0 - lambda) * redundancy;
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((i, score));
+            }
+        }
+        let Some((i, _)) = best else {
+            break;
+        };
+
+        let chunk = remaining[i].take().expect("unreachable");
+        for (j, other) in remaining.iter().enumerate() {
+            if let Some(other) = other {
+                let similarity = chunk
+                    .vector
+                    .iter()
+                    .zip(&other.vector)
+                    .map(|(&x, &y)| x * y)
+                    .sum::<f32>();
+                redundancies[j] = redundancies[j].max(f64::from(similarity));
+            }
+        }
+        selected.push(chunk);
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn matched_chunk(path: &str, line: usize, similarity: f64, vector: &[f32]) -> MatchedChunk {
+        MatchedChunk {
+            repository_path: PathBuf::from("/repo"),
+            line_count: NonZeroUsize::new(10).expect("non-zero"),
+            file_path: PathBuf::from(path),
+            line,
+            columns: None,
+            hash: None,
+            similarity,
+            vector: vector.to_vec(),
+        }
+    }
+
+    fn ranges(chunks: &[MatchedChunk]) -> Vec<(String, usize, usize)> {
+        chunks
+            .iter()
+            .map(|c| {
+                (
+                    c.file_path.display().to_string(),
+                    c.line,
+                    c.line + c.line_count.get(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_chunks_are_merged() {
+        let chunks = vec![
+            matched_chunk("a.rs", 20, 0.9, &[]),
+            matched_chunk("b.rs", 25, 0.8, &[]),
+            matched_chunk("a.rs", 45, 0.7, &[]),
+            matched_chunk("a.rs", 25, 0.6, &[]),
+            matched_chunk("a.rs", 10, 0.5, &[]),
+            matched_chunk("a.rs", 35, 0.4, &[]),
+            matched_chunk("b.rs", 36, 0.3, &[]),
+        ];
+        let merged = merge_overlapping(chunks);
+        assert_eq!(
+            ranges(&merged),
+            [
+                ("a.rs".to_owned(), 10, 55),
+                ("b.rs".to_owned(), 25, 35),
+                ("b.rs".to_owned(), 36, 46),
+            ]
+        );
+        assert_eq!(
+            merged.iter().map(|c| c.similarity).collect::<Vec<_>>(),
+            [0.9, 0.8, 0.3]
+        );
+    }
+
+    #[test]
+    fn mmr_prefers_diverse_chunks() {
+        let chunks = vec![
+            matched_chunk("a.rs", 0, 0.9, &[1.0, 0.0]),
+            matched_chunk("a.rs", 50, 0.85, &[1.0, 0.0]),
+            matched_chunk("b.rs", 0, 0.6, &[0.0, 1.0]),
+        ];
+
+        let selected = maximal_marginal_relevance(chunks.clone(), 1.0, 2);
+        assert_eq!(ranges(&selected), ranges(&chunks[..2]));
+
+        let selected = maximal_marginal_relevance(chunks.clone(), 0.5, 3);
+        assert_eq!(
+            ranges(&selected),
+            ranges(&[chunks[0].clone(), chunks[2].clone(), chunks[1].clone()])
+        );
+    }
+}
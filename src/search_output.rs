@@ -0,0 +1,514 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use orfail::OrFail;
+
+use crate::index_file::MatchedChunk;
+
+/// Output format of search results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A single JSON array.
+    Json,
+
+    /// One JSON object per line, written as soon as each result is ready.
+    JsonLines,
+
+    /// Headers with the similarity followed by the text with line numbers.
+    Text,
+
+    /// `path:line:column: snippet` lines for editor quickfix lists.
+    Vimgrep,
+
+    /// Headings followed by fenced code blocks.
+    Markdown,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "text" => Ok(OutputFormat::Text),
+            "vimgrep" => Ok(OutputFormat::Vimgrep),
+            "markdown" => Ok(OutputFormat::Markdown),
+            _ => Err(format!(
+                "unknown output format: expected 'json', 'jsonl', 'text', 'vimgrep' or 'markdown', found '{s}'"
+            )),
+        }
+    }
+}
+
+/// What the score of a result (the `similarity` field) measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScoreKind {
+    /// Cosine similarity between the query and chunk embeddings (-1.0 to 1.0).
+    #[default]
+    Cosine,
+
+    /// BM25 score of the query terms (unbounded).
+    Bm25,
+
+    /// Reciprocal rank fusion of multiple rankings (at most 1/61 per ranking).
+    Rrf,
+}
+
+impl std::fmt::Display for ScoreKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScoreKind::Cosine => write!(f, "cosine"),
+            ScoreKind::Bm25 => write!(f, "bm25"),
+            ScoreKind::Rrf => write!(f, "rrf"),
+        }
+    }
+}
+
+/// A search result as presented to the user.
+#[derive(Debug)]
+pub struct SimilarChunk {
+    pub similarity: f64,
+    pub score_kind: ScoreKind,
+    pub path: PathBuf,
+
+    /// Zero-based number of the first line.
+    pub line: usize,
+
+    /// Zero-based byte offset of the text within its line, if the chunk is a piece of a long line.
+    pub column: Option<usize>,
+    pub text: String,
+
+    /// Whether the file changed since indexing (so the text may differ from the indexed one).
+    pub stale: bool,
+    pub group: Option<GroupSummary>,
+}
+
+impl SimilarChunk {
+    /// Makes a result, reading its text from the file unless `strip_text` is `true`.
+    ///
+    /// A result whose file or lines have gone is reported as stale with an empty text.
+    /// Results without text are not checked for staleness, as that would require reading the file.
+    pub fn new(chunk: &MatchedChunk, current_dir: &Path, strip_text: bool) -> orfail::Result<Self> {
+        let (text, stale) = if strip_text {
+            (String::new(), false)
+        } else {
+            match chunk.chunk_text().or_fail()? {
+                Some(text) => {
+                    let stale = chunk.is_stale(&text);
+                    (text, stale)
+                }
+                None => (String::new(), true),
+            }
+        };
+        Ok(Self {
+            similarity: chunk.similarity,
+            score_kind: ScoreKind::Cosine,
+            path: chunk.relative_file_path(current_dir),
+            line: chunk.line,
+            column: chunk.columns.as_ref().map(|c| c.start),
+            text,
+            stale,
+            group: None,
+        })
+    }
+
+    /// Returns the score part of text and Markdown headers.
+    fn score_summary(&self) -> String {
+        let mut summary = match self.score_kind {
+            ScoreKind::Cosine => format!("similarity: {:.3}", self.similarity),
+            kind => format!("{kind}: {:.3}", self.similarity),
+        };
+        if let Some(GroupSummary {
+            mean_similarity: Some(mean),
+            hit_count: Some(hits),
+            ..
+        }) = &self.group
+        {
+            summary += &format!(", mean: {mean:.3}, hits: {hits}");
+        }
+        if self.stale {
+            summary += ", stale";
+        }
+        summary
+    }
+
+    /// Returns the one-based range of lines of the text.
+    fn line_range(&self) -> String {
+        let line_count = self.text.lines().count();
+        if line_count > 1 {
+            format!("{}-{}", self.line + 1, self.line + line_count)
+        } else {
+            (self.line + 1).to_string()
+        }
+    }
+}
+
+impl SimilarChunk {
+    fn fmt_json_members(
+        &self,
+        f: &mut nojson::JsonObjectFormatter<'_, '_, '_>,
+    ) -> std::fmt::Result {
+        f.member("similarity", self.similarity)?;
+        f.member("score_kind", self.score_kind.to_string())?;
+        if let Some(group) = &self.group {
+            if let Some(mean) = group.mean_similarity {
+                f.member("mean_similarity", mean)?;
+            }
+            if let Some(hits) = group.hit_count {
+                f.member("hit_count", hits)?;
+            }
+            if let Some(repository) = &group.repository {
+                f.member("repository", repository)?;
+            }
+        }
+        f.member("path", &self.path)?;
+        f.member("line", self.line)?;
+        if let Some(column) = self.column {
+            f.member("column", column)?;
+        }
+        f.member("text", &self.text)?;
+        if self.stale {
+            f.member("stale", true)?;
+        }
+        Ok(())
+    }
+}
+
+impl nojson::DisplayJson for SimilarChunk {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.object(|f| self.fmt_json_members(f))
+    }
+}
+
+/// Aggregated scores of a group of results, reported with its best chunk.
+///
+/// The mean and the hit count are omitted when only the top-ranked chunks were grouped,
+/// as they would not cover all matching chunks.
+#[derive(Debug)]
+pub struct GroupSummary {
+    pub repository: Option<PathBuf>,
+    pub mean_similarity: Option<f64>,
+    pub hit_count: Option<usize>,
+}
+
+const BOLD: &str = "\x1b[1m";
+const BOLD_MAGENTA: &str = "\x1b[1;35m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+/// Writes search results one by one in an [`OutputFormat`].
+///
+/// Results may be divided into sections per query (see [`ResultWriter::begin_section()`]).
+#[derive(Debug)]
+pub struct ResultWriter<W> {
+    out: W,
+    format: OutputFormat,
+    color: bool,
+    written: usize,
+    section: Option<String>,
+    section_written: usize,
+}
+
+impl<W: Write> ResultWriter<W> {
+    /// Makes a writer, where `color` enables ANSI colors in the text format.
+    pub fn new(out: W, format: OutputFormat, color: bool) -> Self {
+        Self {
+            out,
+            format,
+            color,
+            written: 0,
+            section: None,
+            section_written: 0,
+        }
+    }
+
+    /// Starts a section with the results of a query.
+    ///
+    /// In the JSON format, the output becomes an array of objects with `query` and `results` members.
+    /// JSON Lines results get a `query` member, and the text and Markdown formats get headings.
+    pub fn begin_section(&mut self, query: &str) -> orfail::Result<()> {
+        // Multi-line queries are shown in a single line
+        let title = query.split_whitespace().collect::<Vec<_>>().join(" ");
+        match self.format {
+            OutputFormat::Json => {
+                let prefix = if self.section.is_none() { "[" } else { "]}," };
+                write!(
+                    self.out,
+                    "{prefix}{{\"query\":{},\"results\":[",
+                    nojson::Json(query)
+                )
+                .or_fail()?;
+            }
+            OutputFormat::JsonLines | OutputFormat::Vimgrep => {}
+            OutputFormat::Text => {
+                if self.written > 0 {
+                    writeln!(self.out).or_fail()?;
+                }
+                let (bold, reset) = if self.color { (BOLD, RESET) } else { ("", "") };
+                writeln!(self.out, "{bold}Query: {title}{reset}").or_fail()?;
+                writeln!(self.out).or_fail()?;
+            }
+            OutputFormat::Markdown => {
+                if self.written > 0 {
+                    writeln!(self.out).or_fail()?;
+                }
+                writeln!(self.out, "## Query: {title}").or_fail()?;
+                writeln!(self.out).or_fail()?;
+            }
+        }
+        self.section = Some(query.to_owned());
+        self.section_written = 0;
+        Ok(())
+    }
+
+    pub fn write(&mut self, result: &SimilarChunk) -> orfail::Result<()> {
+        match self.format {
+            OutputFormat::Json => {
+                let separator = match (self.section_written, &self.section) {
+                    (0, None) => "[",
+                    (0, Some(_)) => "",
+                    _ => ",",
+                };
+                write!(self.out, "{separator}{}", nojson::Json(result)).or_fail()?;
+            }
+            OutputFormat::JsonLines => {
+                match &self.section {
+                    Some(query) => writeln!(
+                        self.out,
+                        "{}",
+                        nojson::json(|f| f.object(|f| {
+                            f.member("query", query)?;
+                            result.fmt_json_members(f)
+                        }))
+                    )
+                    .or_fail()?,
+                    None => writeln!(self.out, "{}", nojson::Json(result)).or_fail()?,
+                }
+                self.out.flush().or_fail()?;
+            }
+            OutputFormat::Text => self.write_text(result).or_fail()?,
+            OutputFormat::Vimgrep => {
+                // Point at the first non-blank line rather than at the (often blank) first line
+                let (offset, snippet) = result
+                    .text
+                    .lines()
+                    .enumerate()
+                    .find(|(_, line)| !line.trim().is_empty())
+                    .unwrap_or((0, ""));
+                let mut column = snippet.len() - snippet.trim_start().len() + 1;
+                if offset == 0 {
+                    column += result.column.unwrap_or(0);
+                }
+                writeln!(
+                    self.out,
+                    "{}:{}:{column}: {}",
+                    result.path.display(),
+                    result.line + offset + 1,
+                    snippet.trim()
+                )
+                .or_fail()?;
+            }
+            OutputFormat::Markdown => self.write_markdown(result).or_fail()?,
+        }
+        self.written += 1;
+        self.section_written += 1;
+        Ok(())
+    }
+
+    fn write_text(&mut self, result: &SimilarChunk) -> orfail::Result<()> {
+        let (path_color, line_color, score_color, dim, reset) = if self.color {
+            (BOLD_MAGENTA, GREEN, YELLOW, DIM, RESET)
+        } else {
+            ("", "", "", "", "")
+        };
+        if self.section_written > 0 {
+            writeln!(self.out).or_fail()?;
+        }
+        writeln!(
+            self.out,
+            "{path_color}{}{reset}:{line_color}{}{reset} {score_color}({}){reset}",
+            result.path.display(),
+            result.line_range(),
+            result.score_summary()
+        )
+        .or_fail()?;
+
+        let lines = result.text.lines().count();
+        let width = (result.line + lines).to_string().len();
+        for (i, line) in result.text.lines().enumerate() {
+            writeln!(
+                self.out,
+                "{dim}{:>width$} |{reset} {line}",
+                result.line + i + 1
+            )
+            .or_fail()?;
+        }
+        Ok(())
+    }
+
+    fn write_markdown(&mut self, result: &SimilarChunk) -> orfail::Result<()> {
+        if self.section_written > 0 {
+            writeln!(self.out).or_fail()?;
+        }
+        writeln!(
+            self.out,
+            "### {}:{} ({})",
+            result.path.display(),
+            result.line_range(),
+            result.score_summary()
+        )
+        .or_fail()?;
+        if result.text.is_empty() {
+            return Ok(());
+        }
+
+        // The fence must be longer than any backtick run in the text
+        let mut longest_run = 0;
+        let mut run = 0;
+        for c in result.text.chars() {
+            run = if c == '`' { run + 1 } else { 0 };
+            longest_run = longest_run.max(run);
+        }
+        let fence = "`".repeat((longest_run + 1).max(3));
+        let language = result
+            .path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(fence_language)
+            .unwrap_or("");
+        writeln!(self.out).or_fail()?;
+        writeln!(self.out, "{fence}{language}").or_fail()?;
+        writeln!(self.out, "{}", result.text).or_fail()?;
+        writeln!(self.out, "{fence}").or_fail()?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> orfail::Result<()> {
+        if self.format == OutputFormat::Json {
+            if self.section.is_some() {
+                writeln!(self.out, "]}}]").or_fail()?;
+            } else if self.written == 0 {
+                writeln!(self.out, "[]").or_fail()?;
+            } else {
+                writeln!(self.out, "]").or_fail()?;
+            }
+        }
+        self.out.flush().or_fail()?;
+        Ok(())
+    }
+}
+
+/// Returns the info string of a fenced code block for a file extension.
+fn fence_language(extension: &str) -> &str {
+    match extension {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "rb" => "ruby",
+        "sh" | "bash" => "bash",
+        "md" => "markdown",
+        "yml" => "yaml",
+        "h" => "c",
+        "hpp" | "cc" | "cxx" => "cpp",
+        "kt" => "kotlin",
+        _ => extension,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(path: &str, line: usize, text: &str) -> SimilarChunk {
+        SimilarChunk {
+            similarity: 0.5,
+            score_kind: ScoreKind::Cosine,
+            path: PathBuf::from(path),
+            line,
+            column: None,
+            text: text.to_owned(),
+            stale: false,
+            group: None,
+        }
+    }
+
+    fn render(format: OutputFormat, results: &[SimilarChunk]) -> String {
+        let mut out = Vec::new();
+        let mut writer = ResultWriter::new(&mut out, format, false);
+        for result in results {
+            writer.write(result).expect("write");
+        }
+        writer.finish().expect("finish");
+        String::from_utf8(out).expect("utf-8")
+    }
+
+    /// Renders each result in its own section.
+    fn render_sections(format: OutputFormat, results: &[SimilarChunk]) -> String {
+        let mut out = Vec::new();
+        let mut writer = ResultWriter::new(&mut out, format, false);
+        for (i, result) in results.iter().enumerate() {
+            writer.begin_section(&format!("q{i}")).expect("section");
+            writer.write(result).expect("write");
+        }
+        writer.finish().expect("finish");
+        String::from_utf8(out).expect("utf-8")
+    }
+
+    #[test]
+    fn write_results_in_each_format() {
+        let mut results = [
+            result("src/a.rs", 9, "\n    fn a() {}\n}"),
+            result("doc.md", 0, "```sh\nls\n```"),
+        ];
+        results[1].stale = true;
+
+        assert_eq!(
+            render(OutputFormat::Json, &results),
+            format!(
+                "[{},{}]\n",
+                nojson::Json(&results[0]),
+                nojson::Json(&results[1])
+            )
+        );
+        assert_eq!(render(OutputFormat::Json, &[]), "[]\n");
+        assert_eq!(
+            render_sections(OutputFormat::Json, &results),
+            format!(
+                "[{{\"query\":\"q0\",\"results\":[{}]}},{{\"query\":\"q1\",\"results\":[{}]}}]\n",
+                nojson::Json(&results[0]),
+                nojson::Json(&results[1])
+            )
+        );
+        assert!(
+            render_sections(OutputFormat::JsonLines, &results)
+                .lines()
+                .all(|line| line.starts_with("{\"query\":\"q"))
+        );
+        assert_eq!(
+            render_sections(OutputFormat::Markdown, &results[..1]),
+            "## Query: q0\n\n### src/a.rs:10-12 (similarity: 0.500)\n\n```rust\n\n    fn a() {}\n}\n```\n"
+        );
+        assert_eq!(render(OutputFormat::JsonLines, &results).lines().count(), 2);
+        assert_eq!(
+            render(OutputFormat::Vimgrep, &results),
+            "src/a.rs:11:5: fn a() {}\ndoc.md:1:1: ```sh\n"
+        );
+        assert_eq!(
+            render(OutputFormat::Text, &results[..1]),
+            "src/a.rs:10-12 (similarity: 0.500)\n10 | \n11 |     fn a() {}\n12 | }\n"
+        );
+        assert_eq!(
+            render(OutputFormat::Markdown, &results),
+            concat!(
+                "### src/a.rs:10-12 (similarity: 0.500)\n\n```rust\n\n    fn a() {}\n}\n```\n\n",
+                "### doc.md:1-3 (similarity: 0.500, stale)\n\n````markdown\n```sh\nls\n```\n````\n"
+            )
+        );
+    }
+}
@@ -3,11 +3,16 @@ use std::{num::NonZeroUsize, path::PathBuf};
 use orfail::OrFail;
 
 use crate::{
-    chunker::Chunker,
-    embedder::Embedder,
+    chunker::{ChunkHeaderField, ChunkStrategy, Chunker},
+    embedder::{EmbedderOptions, probe_dimension},
+    embedding_cache::EmbeddingCache,
     git::GitRepository,
     glob::{GlobPathFilter, GlobPathPattern},
-    index_file::{ChunkEntry, IndexFile, RepositoryEntry},
+    hnsw::{DEFAULT_HNSW_M_STR, HnswIndex},
+    index_file::{IndexFile, IndexFormat, RepositoryEntry},
+    indexer::{BatchLimits, Indexer},
+    term_index::TermIndex,
+    vector_encoding::VectorEncoding,
 };
 
 pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
@@ -19,19 +24,14 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
         .example("/path/to/.dokosa")
         .take(&mut args)
         .then(|a| a.value().parse())?;
-    let api_key: String = noargs::opt("openai-api-key")
-        .ty("STRING")
-        .doc("OpenAI API key for generating embeddings")
-        .example("YOUR_API_KEY")
-        .env("OPENAI_API_KEY")
-        .take(&mut args)
-        .then(|a| a.value().parse())?;
-    let model: String = noargs::opt("embedding-model")
-        .ty("STRING")
-        .doc("OpenAI embedding model to use for text vectorization")
-        .default("text-embedding-3-small")
+    let embedder_options = EmbedderOptions::take(&mut args)?;
+    let batch_limits = BatchLimits::take(&mut args)?;
+    let embedding_cache_path: Option<PathBuf> = noargs::opt("embedding-cache-file")
+        .ty("PATH")
+        .doc("Path to a file caching embeddings by content hash to avoid re-embedding unchanged chunks")
+        .env("DOKOSA_EMBEDDING_CACHE_FILE")
         .take(&mut args)
-        .then(|a| a.value().parse())?;
+        .present_and_then(|a| a.value().parse())?;
     let chunk_window_size: NonZeroUsize = noargs::opt("chunk-window-size")
         .short('w')
         .ty("LINE_COUNT")
@@ -42,10 +42,76 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
     let chunk_step_size: NonZeroUsize = noargs::opt("chunk-step-size")
         .short('s')
         .ty("LINE_COUNT")
-        .doc("Number of lines to step between overlapping chunks")
+        .doc("Number of lines to step between overlapping chunks (at most the window size)")
         .default("50")
         .take(&mut args)
         .then(|a| a.value().parse())?;
+    let chunk_strategy: ChunkStrategy = noargs::opt("chunk-strategy")
+        .ty("lines|syntax")
+        .env("DOKOSA_CHUNK_STRATEGY")
+        .doc(concat!(
+            "How source files are split into chunks:\n",
+            "- lines: sliding windows of lines\n",
+            "- syntax: top-level items (functions, classes, ...) packed into windows, ",
+            "falling back to sliding windows for oversized items and unsupported languages\n",
+            "Markdown, AsciiDoc and reStructuredText documents are split into heading sections ",
+            "embedded with their heading path with either strategy"
+        ))
+        .default("lines")
+        .take(&mut args)
+        .then(|a| a.value().parse())?;
+    let chunk_max_chars: Option<NonZeroUsize> = noargs::opt("chunk-max-chars")
+        .ty("COUNT")
+        .env("DOKOSA_CHUNK_MAX_CHARS")
+        .doc("Maximum number of characters of a chunk (larger chunks are split, down to pieces of lines)")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+    let chunk_max_tokens: Option<NonZeroUsize> = noargs::opt("chunk-max-tokens")
+        .ty("COUNT")
+        .env("DOKOSA_CHUNK_MAX_TOKENS")
+        .doc("Maximum number of (estimated) tokens of a chunk (larger chunks are split, down to pieces of lines)")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+    let chunk_header: Vec<ChunkHeaderField> = noargs::opt("chunk-header")
+        .ty("FIELD[,FIELD]...")
+        .env("DOKOSA_CHUNK_HEADER")
+        .doc(concat!(
+            "Context prepended to each chunk before embedding (the chunk text itself stays raw):\n",
+            "- repository: name of the repository directory\n",
+            "- path: path of the file in the repository\n",
+            "- language: language guessed from the file extension\n",
+            "- symbol: signature of the enclosing top-level item, or headings of the document section\n",
+            "Headers taking more than half of the chunk size limits are dropped"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| ChunkHeaderField::parse_list(a.value()))?
+        .unwrap_or_default();
+    let vector_encoding: VectorEncoding = noargs::opt("vector-encoding")
+        .ty("f32|f16|int8")
+        .doc("Encoding of the embedding vectors stored in binary index files")
+        .default("f32")
+        .env("DOKOSA_VECTOR_ENCODING")
+        .take(&mut args)
+        .then(|a| a.value().parse())?;
+    let hnsw = noargs::flag("hnsw")
+        .doc(concat!(
+            "Build an approximate nearest neighbour graph (HNSW) alongside the index\n",
+            "to speed up searches over large indices (once built, it is kept up to date)"
+        ))
+        .take(&mut args)
+        .is_present();
+    let hnsw_m: usize = noargs::opt("hnsw-m")
+        .ty("INTEGER")
+        .doc("Maximum number of neighbours per node of a newly built HNSW graph")
+        .default(DEFAULT_HNSW_M_STR)
+        .take(&mut args)
+        .then(|a| {
+            a.value()
+                .parse()
+                .ok()
+                .filter(|&m| m >= 2)
+                .ok_or("must be an integer of at least 2")
+        })?;
     let dry_run = noargs::flag("dry-run")
         .doc("Show what would be done without actually modifying the index")
         .take(&mut args)
@@ -89,29 +155,57 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
         eprintln!("Created index file: {}", index_file_path.display());
     }
 
+    if hnsw {
+        (index_file.format == IndexFormat::Binary)
+            .or_fail_with(|()| "HNSW index requires a binary index file".to_owned())?;
+    }
+
     for r in index_file.repositories() {
         (r.or_fail()?.path != repo.root_dir)
             .or_fail_with(|()| "Repository already exists".to_owned())?;
     }
 
+    let embedder = embedder_options.build().or_fail()?;
     let commit = repo.commit_hash().or_fail()?;
     eprintln!("Commit hash: {}", commit);
 
     if !dry_run {
+        let vector_dimension = probe_dimension(&*embedder).or_fail()?;
         index_file
             .append_repository(&RepositoryEntry {
                 path: repo.root_dir.clone(),
                 commit,
                 chunk_window_size,
                 chunk_step_size,
+                chunk_strategy,
+                chunk_max_chars,
+                chunk_max_tokens,
+                chunk_header: chunk_header.clone(),
                 include_files: filter.include_files.clone(),
                 exclude_files: filter.exclude_files.clone(),
+                embedding_provider: Some(embedder_options.provider()),
+                embedding_model: Some(embedder.model().to_owned()),
+                embedding_dimensions: embedder_options.dimensions,
+                embedding_endpoint: embedder_options.endpoint.clone(),
+                vector_dimension: Some(vector_dimension),
+                vector_encoding: Some(vector_encoding),
             })
             .or_fail()?;
     }
 
-    let chunker = Chunker::new(chunk_window_size, chunk_step_size);
-    let embedder = Embedder::new(api_key, model);
+    let chunker = Chunker::new(chunk_window_size, chunk_step_size)
+        .with_strategy(chunk_strategy)
+        .with_limits(chunk_max_chars, chunk_max_tokens)
+        .with_header(chunk_header, &repo.root_dir);
+    let mut embedding_cache = if dry_run {
+        None
+    } else {
+        embedding_cache_path
+            .map(EmbeddingCache::open)
+            .transpose()
+            .or_fail()?
+    };
+    let mut indexer = Indexer::new(&*embedder, batch_limits).with_cache(embedding_cache.as_mut());
 
     for file_path in repo.files().or_fail()? {
         let abs_file_path = repo.root_dir.join(&file_path);
@@ -135,29 +229,24 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
             continue;
         }
 
-        let mut chunks = chunker.apply(&content);
-        let inputs = chunks
-            .iter_mut()
-            .map(|c| std::mem::take(&mut c.data))
-            .collect::<Vec<_>>();
-        let Ok(embeddings) = embedder
-            .embed(&inputs)
-            .or_fail()
-            .inspect_err(|e| eprintln!("  Failed to embed: {e}"))
-        else {
-            continue;
-        };
-        for (chunk, embedding) in chunks.iter().zip(embeddings) {
-            index_file
-                .append_chunk(&ChunkEntry {
-                    path: file_path.clone(),
-                    line: chunk.line,
-                    embedding,
-                })
-                .or_fail()?;
-        }
+        let chunks = chunker.apply(&file_path, &content);
+        indexer.add_file(&index_file, file_path, chunks).or_fail()?;
+    }
+    indexer.finish(&index_file).or_fail()?;
+    if !dry_run {
+        HnswIndex::update_sidecar(&index_file, hnsw.then_some(hnsw_m)).or_fail()?;
+        TermIndex::update_sidecar(&index_file).or_fail()?;
     }
 
+    if !indexer.failed_files.is_empty() {
+        eprintln!("=> Failed to embed {} file(s):", indexer.failed_files.len());
+        for path in &indexer.failed_files {
+            eprintln!("  {}", path.display());
+        }
+    }
+    if let Some(cache) = &embedding_cache {
+        eprintln!("=> Reused {} cached embedding(s)", cache.hits);
+    }
     eprintln!("=> Added");
     Ok(())
 }
@@ -0,0 +1,54 @@
+use std::path::PathBuf;
+
+use orfail::OrFail;
+
+use crate::index_file::{IndexFile, IndexFileEntry, IndexFormat};
+
+pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
+    let index_file_path: PathBuf = noargs::opt("index-file")
+        .short('i')
+        .ty("PATH")
+        .doc("Path to the index file to export")
+        .env("DOKOSA_INDEX_FILE")
+        .example("/path/to/.dokosa")
+        .take(&mut args)
+        .then(|a| a.value().parse())?;
+    let format: IndexFormat = noargs::opt("format")
+        .short('f')
+        .ty("jsonl|binary")
+        .doc("Format of the exported index")
+        .default("jsonl")
+        .take(&mut args)
+        .then(|a| a.value().parse())?;
+    let output_path: Option<PathBuf> = noargs::opt("output")
+        .short('o')
+        .ty("PATH")
+        .doc("Path to a new file to write the exported index to (defaults to stdout for 'jsonl')")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+    if let Some(help) = args.finish()? {
+        print!("{help}");
+        return Ok(());
+    }
+
+    let index_file = IndexFile::load(&index_file_path).or_fail()?;
+
+    let Some(output_path) = output_path else {
+        (format == IndexFormat::JsonLines)
+            .or_fail_with(|()| format!("The '{format}' format requires --output"))?;
+        for entry in index_file.entries() {
+            println!("{}", nojson::Json(entry.or_fail()?));
+        }
+        return Ok(());
+    };
+
+    let output_file = IndexFile::create_new(&output_path, format).or_fail()?;
+    for entry in index_file.entries() {
+        match entry.or_fail()? {
+            IndexFileEntry::Repository(repo) => output_file.append_repository(&repo).or_fail()?,
+            IndexFileEntry::Chunk(chunk) => output_file.append_chunk(&chunk).or_fail()?,
+        }
+    }
+    eprintln!("=> Exported to {}", output_path.display());
+    Ok(())
+}
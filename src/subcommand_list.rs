@@ -30,6 +30,15 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
                 repo_count += 1;
                 println!("Repository: {}", repo.path.display());
                 println!("  Commit: {}", repo.commit);
+                if let (Some(provider), Some(model)) =
+                    (repo.embedding_provider, &repo.embedding_model)
+                {
+                    print!("  Embedding: {provider}/{model}");
+                    if let Some(dimension) = repo.vector_dimension {
+                        print!(" ({dimension} dimensions)");
+                    }
+                    println!();
+                }
             }
             IndexFileEntry::Chunk(chunk) => {
                 chunk_count += 1;
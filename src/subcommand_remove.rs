@@ -4,7 +4,9 @@ use orfail::OrFail;
 
 use crate::{
     git::GitRepository,
+    hnsw::HnswIndex,
     index_file::{IndexFile, IndexFileEntry},
+    term_index::TermIndex,
 };
 
 pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
@@ -44,7 +46,8 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
     }
 
     let temp_index_file =
-        IndexFile::create_new(index_file.path.with_extension("temp")).or_fail()?;
+        IndexFile::create_new(index_file.path.with_extension("temp"), index_file.format)
+            .or_fail()?;
     let mut removing = false;
     for entry in index_file.entries() {
         let entry = entry.or_fail()?;
@@ -65,7 +68,9 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
             }
         }
     }
-    std::fs::rename(temp_index_file.path, index_file.path).or_fail()?;
+    std::fs::rename(&temp_index_file.path, &index_file.path).or_fail()?;
+    HnswIndex::update_sidecar(&index_file, None).or_fail()?;
+    TermIndex::update_sidecar(&index_file).or_fail()?;
 
     eprintln!("=> Removed");
     Ok(())
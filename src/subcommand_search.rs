@@ -1,11 +1,25 @@
-use std::{io::Read, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{IsTerminal, Read},
+    num::NonZeroUsize,
+    path::PathBuf,
+};
 
 use orfail::OrFail;
 
 use crate::{
-    embedder::Embedder,
+    chunker::{Chunker, ContentHash},
+    embedder::{EmbedderOptions, Embedding, EmbeddingProvider},
+    embedding_cache::{EmbeddingCache, model_key},
     glob::{GlobPathFilter, GlobPathPattern},
-    index_file::IndexFile,
+    hnsw::{DEFAULT_EF_SEARCH_STR, HnswIndex},
+    index_file::{
+        GroupBy, IndexFile, IndexFormat, MatchedChunk, RepositoryEntry, group_matched_chunks,
+        unit_cosine_similarity, unit_query_vector,
+    },
+    indexer::{BatchLimits, embed_in_batches},
+    lexical_search, rerank,
+    search_output::{GroupSummary, OutputFormat, ResultWriter, ScoreKind, SimilarChunk},
 };
 
 pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
@@ -33,23 +47,134 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
         .doc("Minimum similarity score (0.0 to 1.0) for results to be included")
         .take(&mut args)
         .then(|a| a.value().parse())?;
-    let api_key: String = noargs::opt("openai-api-key")
-        .ty("STRING")
-        .doc("OpenAI API key for generating embeddings")
-        .example("YOUR_API_KEY")
-        .env("OPENAI_API_KEY")
+    let mode: SearchMode = noargs::opt("mode")
+        .short('m')
+        .ty("semantic|lexical|hybrid")
+        .env("DOKOSA_SEARCH_MODE")
+        .doc(concat!(
+            "Ranking of the results:\n",
+            "- semantic: cosine similarity of embeddings\n",
+            "- lexical: BM25 score of query terms (no embedding provider is needed)\n",
+            "- hybrid: reciprocal rank fusion of the semantic and lexical rankings\n",
+            "The `similarity` field of the results holds the score of the ranking, ",
+            "named by the `score_kind` field (cosine, bm25 or rrf; multiple queries are fused by rrf)\n",
+            "The similarity threshold applies to cosine similarities: to the results in semantic mode, ",
+            "and to the semantic ranking before fusion in hybrid mode (lexical results are not filtered)"
+        ))
+        .default("semantic")
+        .take(&mut args)
+        .then(|a| a.value().parse())?;
+    let merge_overlapping = noargs::flag("merge-overlapping")
+        .doc("Merge overlapping or adjacent chunks of the same file into a single result")
+        .take(&mut args)
+        .is_present();
+    let diversity: Option<f64> = noargs::opt("diversity")
+        .ty("LAMBDA")
+        .env("DOKOSA_SEARCH_DIVERSITY")
+        .doc(concat!(
+            "Re-rank results by maximal marginal relevance, trading relevance (1.0) ",
+            "for diversity (0.0)"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| {
+            a.value()
+                .parse()
+                .ok()
+                .filter(|lambda| (0.0..=1.0).contains(lambda))
+                .ok_or("must be a number between 0.0 and 1.0")
+        })?;
+    let group_by: Option<GroupBy> = noargs::opt("group-by")
+        .ty("file|repository")
+        .env("DOKOSA_SEARCH_GROUP_BY")
+        .doc(concat!(
+            "Report the best chunk of each file (or repository) together with ",
+            "the mean score and the number of matched chunks (`--count` applies to groups)\n",
+            "All chunks are scanned (without the HNSW graph) to aggregate them, ",
+            "except for fused rankings (hybrid mode or multiple queries), ",
+            "where only the best chunks are grouped and the mean and count are omitted"
+        ))
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+    let refresh_stale = noargs::flag("refresh-stale")
+        .doc(concat!(
+            "Re-embed files changed since indexing to replace their results with current chunks ",
+            "(semantic mode only)"
+        ))
+        .take(&mut args)
+        .is_present();
+    let exact = noargs::flag("exact")
+        .doc("Scan all chunks instead of using the HNSW graph (if any)")
+        .take(&mut args)
+        .is_present();
+    let ef_search: usize = noargs::opt("ef-search")
+        .ty("INTEGER")
+        .env("DOKOSA_SEARCH_EF")
+        .doc("Number of candidates explored in the HNSW graph (higher is more accurate but slower)")
+        .default(DEFAULT_EF_SEARCH_STR)
         .take(&mut args)
         .then(|a| a.value().parse())?;
-    let model: String = noargs::opt("embedding-model")
-        .ty("STRING")
-        .doc("OpenAI embedding model to use for text vectorization")
-        .default("text-embedding-3-small")
+    let threads: Option<NonZeroUsize> = noargs::opt("threads")
+        .ty("INTEGER")
+        .env("DOKOSA_SEARCH_THREADS")
+        .doc("Number of threads to scan the index with [default: number of available CPUs]")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+    let embedder_options = EmbedderOptions::take(&mut args)?;
+    let batch_limits = BatchLimits::take(&mut args)?;
+    let embedding_cache_path: Option<PathBuf> = noargs::opt("embedding-cache-file")
+        .ty("PATH")
+        .doc("Path to a file caching embeddings by content hash, used to re-embed stale files")
+        .env("DOKOSA_EMBEDDING_CACHE_FILE")
+        .take(&mut args)
+        .present_and_then(|a| a.value().parse())?;
+    let format: OutputFormat = noargs::opt("format")
+        .short('f')
+        .ty("json|jsonl|text|vimgrep|markdown")
+        .env("DOKOSA_SEARCH_FORMAT")
+        .doc(concat!(
+            "Output format of the results:\n",
+            "- json: a JSON array\n",
+            "- jsonl: one JSON object per line, written as each result is ready\n",
+            "- text: headers and text with line numbers (colored if stdout is a terminal)\n",
+            "- vimgrep: `path:line:column: snippet` lines for quickfix lists\n",
+            "- markdown: headings and fenced code blocks"
+        ))
+        .default("json")
         .take(&mut args)
         .then(|a| a.value().parse())?;
     let strip_text: bool = noargs::flag("strip-text")
-        .doc("Exclude text content from results, returning only metadata")
+        .doc(concat!(
+            "Exclude text content from results, returning only metadata ",
+            "(files are not read, so results are not marked as stale)"
+        ))
         .take(&mut args)
         .is_present();
+    let per_query = noargs::flag("per-query")
+        .doc(concat!(
+            "Report the results of each query in its own section ",
+            "instead of a single ranking combined by reciprocal rank fusion"
+        ))
+        .take(&mut args)
+        .is_present();
+    let mut queries = Vec::new();
+    while let Some(a) = noargs::opt("query")
+        .short('q')
+        .ty("TEXT")
+        .doc("Query text (can be used multiple times)")
+        .take(&mut args)
+        .present()
+    {
+        queries.push(a.value().to_owned());
+    }
+    let mut query_files = Vec::new();
+    while let Some(a) = noargs::opt("query-file")
+        .ty("PATH")
+        .doc("Read a query from this file (can be used multiple times)")
+        .take(&mut args)
+        .present()
+    {
+        query_files.push(PathBuf::from(a.value()));
+    }
     let mut filter = GlobPathFilter::default();
     while let Some(a) = noargs::opt("include-files")
         .short('I')
@@ -69,56 +194,513 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
     {
         filter.exclude_files.push(GlobPathPattern::new(a.value()));
     }
+    let mut words = Vec::new();
+    while let Some(a) = noargs::arg("[QUERY]...")
+        .doc(concat!(
+            "Query text (the words are joined with spaces)\n",
+            "If no query is given by arguments or options, it is read from stdin"
+        ))
+        .take(&mut args)
+        .present()
+    {
+        words.push(a.value().to_owned());
+    }
     if let Some(help) = args.finish()? {
         print!("{help}");
         return Ok(());
     }
 
+    (group_by.is_none() || !(merge_overlapping || diversity.is_some())).or_fail_with(|()| {
+        "--group-by cannot be combined with --merge-overlapping or --diversity".to_owned()
+    })?;
+
+    if !words.is_empty() {
+        queries.insert(0, words.join(" "));
+    }
+    for path in &query_files {
+        let query = std::fs::read_to_string(path)
+            .or_fail_with(|e| format!("Failed to read query file {}: {e}", path.display()))?;
+        queries.push(query);
+    }
+    if queries.is_empty() {
+        (!std::io::stdin().is_terminal()).or_fail_with(|()| {
+            "No query given (pass it as arguments, --query, --query-file or stdin)".to_owned()
+        })?;
+        let mut query = String::new();
+        std::io::stdin().read_to_string(&mut query).or_fail()?;
+        queries.push(query);
+    }
+
+    (!refresh_stale || (mode == SearchMode::Semantic && group_by.is_none())).or_fail_with(
+        |()| "--refresh-stale is only supported in semantic mode without --group-by".to_owned(),
+    )?;
+    (!refresh_stale || per_query || queries.len() == 1).or_fail_with(|()| {
+        "--refresh-stale requires --per-query when multiple queries are given".to_owned()
+    })?;
+
     let index_file = IndexFile::load(&index_file_path).or_fail()?;
-    let embedder = Embedder::new(api_key, model);
 
-    let mut query = String::new();
-    std::io::stdin().read_to_string(&mut query).or_fail()?;
+    let (embedder, embeddings, hnsw) = if mode == SearchMode::Lexical {
+        (None, Vec::new(), None)
+    } else {
+        let (embedder, embeddings) =
+            embed_queries(&index_file, &queries, embedder_options, &filter).or_fail()?;
+        let hnsw = if exact {
+            None
+        } else {
+            load_hnsw(&index_file).or_fail()?
+        };
+        (Some(embedder), embeddings, hnsw)
+    };
+    let mut embedding_cache = if refresh_stale {
+        embedding_cache_path
+            .map(EmbeddingCache::open)
+            .transpose()
+            .or_fail()?
+    } else {
+        None
+    };
+    if index_file.format == IndexFormat::JsonLines && threads.is_some_and(|n| n.get() > 1) {
+        eprintln!(
+            "--threads is ignored for JSON Lines index files, which are scanned sequentially"
+        );
+    }
+    let threads = threads
+        .or_else(|| std::thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get);
+    let semantic_search = |i: usize, count: usize| match (embeddings.get(i), &hnsw) {
+        (None, _) => Ok(Vec::new()),
+        (Some(embedding), Some(hnsw)) => hnsw.search(
+            &index_file,
+            embedding,
+            count,
+            similarity_threshold,
+            &filter,
+            ef_search,
+        ),
+        (Some(embedding), None) => {
+            index_file.search(embedding, count, similarity_threshold, &filter, threads)
+        }
+    };
+    let search_one = |i: usize, count: usize| match mode {
+        SearchMode::Semantic => semantic_search(i, count).or_fail(),
+        SearchMode::Lexical => lexical_search::search(
+            &index_file,
+            &queries[i],
+            count,
+            &filter,
+            diversity.is_some(),
+        )
+        .or_fail(),
+        SearchMode::Hybrid => {
+            let semantic = semantic_search(i, count).or_fail()?;
+            let lexical = lexical_search::search(
+                &index_file,
+                &queries[i],
+                count,
+                &filter,
+                diversity.is_some(),
+            )
+            .or_fail()?;
+            Ok(lexical_search::reciprocal_rank_fusion(
+                vec![semantic, lexical],
+                count,
+            ))
+        }
+    };
+    // The rankings of multiple queries are combined in the same way as in hybrid mode
+    let search = |section: &[usize], count: usize| -> orfail::Result<Vec<MatchedChunk>> {
+        if let [i] = section {
+            return search_one(*i, count);
+        }
+        let rankings = section
+            .iter()
+            .map(|&i| search_one(i, count))
+            .collect::<orfail::Result<Vec<_>>>()?;
+        Ok(lexical_search::reciprocal_rank_fusion(rankings, count))
+    };
+
+    let sections = if per_query {
+        (0..queries.len()).map(|i| vec![i]).collect::<Vec<_>>()
+    } else {
+        vec![(0..queries.len()).collect()]
+    };
+    let current_dir = std::env::current_dir().or_fail()?;
+    let color = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    let mut writer = ResultWriter::new(std::io::stdout().lock(), format, color);
+    for section in &sections {
+        if per_query {
+            writer.begin_section(&queries[section[0]]).or_fail()?;
+        }
+
+        // Re-ranked, fused and grouped results are chosen from more candidates than requested.
+        // For example, chunks ranked moderately high by both rankings in hybrid mode
+        // can outrank chunks ranked high by only one of them.
+        let reranked = merge_overlapping || diversity.is_some();
+        let fused = mode == SearchMode::Hybrid || section.len() > 1;
+        let score_kind = match mode {
+            _ if fused => ScoreKind::Rrf,
+            SearchMode::Lexical => ScoreKind::Bm25,
+            _ => ScoreKind::Cosine,
+        };
+        let depth = if reranked || group_by.is_some() || fused {
+            count
+                .saturating_mul(CANDIDATE_DEPTH_FACTOR)
+                .max(CANDIDATE_MIN_DEPTH)
+        } else {
+            count
+        };
+        if let Some(group_by) = group_by {
+            let (groups, aggregated) = match (section.as_slice(), mode) {
+                (&[i], SearchMode::Semantic) => {
+                    let groups = index_file
+                        .search_groups(
+                            &embeddings[i],
+                            count,
+                            similarity_threshold,
+                            &filter,
+                            group_by,
+                            threads,
+                        )
+                        .or_fail()?;
+                    (groups, true)
+                }
+                (&[i], SearchMode::Lexical) => {
+                    // Lexical search scores all matching chunks anyway
+                    let chunks = lexical_search::search(
+                        &index_file,
+                        &queries[i],
+                        usize::MAX,
+                        &filter,
+                        false,
+                    )
+                    .or_fail()?;
+                    (group_matched_chunks(chunks, group_by, count), true)
+                }
+                _ => {
+                    let chunks = search(section, depth).or_fail()?;
+                    (group_matched_chunks(chunks, group_by, count), false)
+                }
+            };
+            for group in groups {
+                let mut chunk =
+                    SimilarChunk::new(&group.best, &current_dir, strip_text).or_fail()?;
+                chunk.score_kind = score_kind;
+                chunk.group = Some(GroupSummary {
+                    repository: (group_by == GroupBy::Repository)
+                        .then(|| group.best.relative_repository_path(&current_dir)),
+                    mean_similarity: aggregated.then_some(group.mean_similarity),
+                    hit_count: aggregated.then_some(group.hit_count),
+                });
+                writer.write(&chunk).or_fail()?;
+            }
+        } else {
+            let mut matched_chunks = search(section, depth).or_fail()?;
+            if let (true, Some(embedder), &[i]) = (refresh_stale, &embedder, section.as_slice()) {
+                matched_chunks = refresh_stale_chunks(
+                    &index_file,
+                    matched_chunks,
+                    &**embedder,
+                    &embeddings[i],
+                    similarity_threshold,
+                    batch_limits,
+                    embedding_cache.as_mut(),
+                )
+                .or_fail()?;
+            }
+            if merge_overlapping {
+                matched_chunks = rerank::merge_overlapping(matched_chunks);
+            }
+            if let Some(lambda) = diversity {
+                matched_chunks = rerank::maximal_marginal_relevance(matched_chunks, lambda, count);
+            }
+            matched_chunks.truncate(count);
+            for chunk in &matched_chunks {
+                let mut chunk = SimilarChunk::new(chunk, &current_dir, strip_text).or_fail()?;
+                chunk.score_kind = score_kind;
+                writer.write(&chunk).or_fail()?;
+            }
+        }
+    }
+    writer.finish().or_fail()?;
+    Ok(())
+}
+
+/// Minimum number of candidates for results that are fused, re-ranked or grouped.
+const CANDIDATE_MIN_DEPTH: usize = 50;
+
+/// Number of candidates for results that are fused, re-ranked or grouped, relative to the result count.
+const CANDIDATE_DEPTH_FACTOR: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SearchMode {
+    Semantic,
+    Lexical,
+    Hybrid,
+}
+
+impl std::str::FromStr for SearchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "semantic" => Ok(SearchMode::Semantic),
+            "lexical" => Ok(SearchMode::Lexical),
+            "hybrid" => Ok(SearchMode::Hybrid),
+            _ => Err(format!(
+                "unknown search mode: expected 'semantic', 'lexical' or 'hybrid', found '{s}'"
+            )),
+        }
+    }
+}
 
-    let embedding = embedder.embed(&[query]).or_fail()?.remove(0);
-    let matched_chunks = index_file
-        .search(&embedding, count, similarity_threshold, &filter)
+/// Builds the embedder the repositories were indexed with and embeds the queries.
+///
+/// Repositories whose chunks are all excluded by the filter do not need to share
+/// the embedding settings of the others.
+fn embed_queries(
+    index_file: &IndexFile,
+    queries: &[String],
+    embedder_options: EmbedderOptions,
+    filter: &GlobPathFilter,
+) -> orfail::Result<(Box<dyn EmbeddingProvider>, Vec<Embedding>)> {
+    let mut repositories = index_file
+        .repositories()
+        .collect::<orfail::Result<Vec<_>>>()
         .or_fail()?;
+    let mut inherited = inherit_embedding_settings(&repositories, &embedder_options);
+    if inherited.is_err() && !(filter.include_files.is_empty() && filter.exclude_files.is_empty()) {
+        let mut searched = HashSet::new();
+        index_file
+            .for_each_chunk(|repository, chunk| {
+                if !searched.contains(&repository.path) {
+                    let metadata = chunk.metadata().or_fail()?;
+                    if filter.matches(repository.path.join(&metadata.path)) {
+                        searched.insert(repository.path.clone());
+                    }
+                }
+                Ok(())
+            })
+            .or_fail()?;
+        repositories.retain(|repo| searched.contains(&repo.path));
+        inherited = inherit_embedding_settings(&repositories, &embedder_options);
+    }
+    let (embedder_options, vector_dimension) = inherited.or_fail()?;
+    let embedder = embedder_options.build().or_fail()?;
 
-    let current_dir = std::env::current_dir().or_fail()?;
-    let mut chunks = Vec::new();
-    for chunk in matched_chunks {
-        chunks.push(SimilarChunk {
-            similarity: chunk.similarity,
-            path: chunk.relative_file_path(&current_dir),
-            line: chunk.line,
-            text: if strip_text {
-                "".to_owned()
-            } else {
-                chunk.chunk_text().or_fail()?
-            },
-        });
+    let embeddings = embedder.embed(queries).or_fail()?;
+    (embeddings.len() == queries.len()).or_fail()?;
+    if let Some(dimension) = vector_dimension {
+        for embedding in &embeddings {
+            (embedding.0.len() == dimension).or_fail_with(|()| {
+                format!(
+                    "Query embedding dimension {} does not match the indexed dimension {dimension}",
+                    embedding.0.len()
+                )
+            })?;
+        }
     }
+    Ok((embedder, embeddings))
+}
 
-    println!("{}", nojson::Json(&chunks));
-    Ok(())
+/// Inherits the embedding settings of the repositories, which must all agree
+/// as each query is embedded only once.
+fn inherit_embedding_settings(
+    repositories: &[RepositoryEntry],
+    embedder_options: &EmbedderOptions,
+) -> orfail::Result<(EmbedderOptions, Option<usize>)> {
+    let mut inherited = None::<(&RepositoryEntry, EmbedderOptions)>;
+    let mut vector_dimension = None;
+    for repo in repositories {
+        let options = embedder_options.inherit(repo).or_fail()?;
+        match &mut inherited {
+            None => inherited = Some((repo, options)),
+            Some((first_repo, first)) => first.merge(&options).map_err(|e| {
+                orfail::Failure::new(format!(
+                    "Repositories {} and {} were indexed with different embedding settings ({e}), \
+                     so a query cannot be compared with both",
+                    first_repo.path.display(),
+                    repo.path.display()
+                ))
+            })?,
+        }
+        if let Some(dimension) = repo.vector_dimension {
+            (*vector_dimension.get_or_insert(dimension) == dimension).or_fail_with(|()| {
+                format!(
+                    "Embedding dimension {dimension} of {} differs from other repositories",
+                    repo.path.display()
+                )
+            })?;
+        }
+    }
+    let embedder_options = inherited.map_or_else(|| embedder_options.clone(), |(_, o)| o);
+    Ok((embedder_options, vector_dimension))
 }
 
-#[derive(Debug)]
-struct SimilarChunk {
-    similarity: f64,
-    path: PathBuf,
-    line: usize,
-    text: String,
+/// Loads the HNSW graph of the index file if it exists and is up to date.
+fn load_hnsw(index_file: &IndexFile) -> orfail::Result<Option<HnswIndex>> {
+    let Some(hnsw) = HnswIndex::load(HnswIndex::sidecar_path(&index_file.path)).or_fail()? else {
+        return Ok(None);
+    };
+    if !hnsw.is_up_to_date(index_file).or_fail()? {
+        eprintln!(
+            "HNSW index is out of date (run `dokosa sync` to update it); \
+             falling back to exact search"
+        );
+        return Ok(None);
+    }
+    Ok(Some(hnsw))
 }
 
-impl nojson::DisplayJson for SimilarChunk {
-    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
-        f.object(|f| {
-            f.member("similarity", self.similarity)?;
-            f.member("path", &self.path)?;
-            f.member("line", self.line)?;
-            f.member("text", &self.text)
+/// Replaces the results from files changed since indexing with the most similar chunks
+/// of their current content, which are embedded on the fly.
+///
+/// A stale file contributes as many current chunks as it had results.
+/// Results from files that no longer exist are dropped.
+/// Chunks whose embedded text is unchanged reuse their indexed (or cached) embeddings,
+/// and the others are embedded in batches within `batch_limits`.
+fn refresh_stale_chunks(
+    index_file: &IndexFile,
+    chunks: Vec<MatchedChunk>,
+    embedder: &dyn EmbeddingProvider,
+    query: &Embedding,
+    similarity_threshold: f64,
+    batch_limits: BatchLimits,
+    mut embedding_cache: Option<&mut EmbeddingCache>,
+) -> orfail::Result<Vec<MatchedChunk>> {
+    let mut refreshed = Vec::new();
+    let mut stale_files = Vec::<(MatchedChunk, usize)>::new();
+    for chunk in chunks {
+        let is_stale = chunk
+            .chunk_text()
+            .or_fail()?
+            .is_none_or(|text| chunk.is_stale(&text));
+        if !is_stale {
+            refreshed.push(chunk);
+        } else if let Some((_, count)) = stale_files.iter_mut().find(|(c, _)| {
+            c.repository_path == chunk.repository_path && c.file_path == chunk.file_path
+        }) {
+            *count += 1;
+        } else {
+            stale_files.push((chunk, 1));
+        }
+    }
+    if stale_files.is_empty() {
+        return Ok(refreshed);
+    }
+
+    let repositories = index_file
+        .repositories()
+        .map(|r| r.map(|r| (r.path.clone(), r)))
+        .collect::<orfail::Result<HashMap<_, _>>>()
+        .or_fail()?;
+    let mut stale_chunks = Vec::new();
+    for (chunk, count) in stale_files {
+        let repository = repositories.get(&chunk.repository_path).or_fail()?;
+        let Ok(content) = std::fs::read_to_string(repository.path.join(&chunk.file_path)) else {
+            continue;
+        };
+        let file_chunks = Chunker::new(repository.chunk_window_size, repository.chunk_step_size)
+            .with_strategy(repository.chunk_strategy)
+            .with_limits(repository.chunk_max_chars, repository.chunk_max_tokens)
+            .with_header(repository.chunk_header.clone(), &repository.path)
+            .apply(&chunk.file_path, &content);
+        stale_chunks.push((chunk, count, file_chunks));
+    }
+
+    // The indexed vectors of the stale files, keyed by the hashes of their embedded texts
+    let mut indexed_vectors = stale_chunks
+        .iter()
+        .map(|(c, _, _)| {
+            let key = (c.repository_path.clone(), c.file_path.clone());
+            (key, HashMap::new())
         })
+        .collect::<HashMap<_, _>>();
+    let stale_repositories = stale_chunks
+        .iter()
+        .map(|(c, _, _)| c.repository_path.clone())
+        .collect::<HashSet<_>>();
+    index_file
+        .for_each_chunk(|repository, chunk| {
+            if !stale_repositories.contains(&repository.path) {
+                return Ok(());
+            }
+            let metadata = chunk.metadata().or_fail()?;
+            if let Some(key) = metadata.embedding_key()
+                && let Some(vectors) =
+                    indexed_vectors.get_mut(&(repository.path.clone(), metadata.path))
+            {
+                vectors.insert(key, chunk.unit_vector());
+            }
+            Ok(())
+        })
+        .or_fail()?;
+
+    let model_key = model_key(embedder);
+    let mut vectors = Vec::new();
+    let mut unembedded = Vec::new();
+    for (chunk, _, file_chunks) in &stale_chunks {
+        let indexed = &indexed_vectors[&(chunk.repository_path.clone(), chunk.file_path.clone())];
+        let mut file_vectors = Vec::with_capacity(file_chunks.len());
+        for c in file_chunks {
+            let text = c.embedding_text();
+            let key = ContentHash::of(&text);
+            let vector = if let Some(vector) = indexed.get(&key) {
+                Some(vector.clone())
+            } else if let Some(cache) = &mut embedding_cache {
+                cache
+                    .get(&model_key, key)
+                    .or_fail()?
+                    .map(|e| unit_query_vector(&e))
+            } else {
+                None
+            };
+            if vector.is_none() {
+                unembedded.push((vectors.len(), file_vectors.len(), key, text.into_owned()));
+            }
+            file_vectors.push(vector);
+        }
+        vectors.push(file_vectors);
     }
+
+    let texts = unembedded
+        .iter()
+        .map(|(_, _, _, text)| text.clone())
+        .collect::<Vec<_>>();
+    let embeddings = embed_in_batches(embedder, &texts, batch_limits).or_fail()?;
+    for ((file, i, key, _), embedding) in unembedded.into_iter().zip(embeddings) {
+        if let Some(cache) = &mut embedding_cache {
+            cache.insert(&model_key, key, &embedding).or_fail()?;
+        }
+        vectors[file][i] = Some(unit_query_vector(&embedding));
+    }
+
+    let query_vector = unit_query_vector(query);
+    for ((chunk, count, file_chunks), file_vectors) in stale_chunks.into_iter().zip(vectors) {
+        let mut file_chunks = file_chunks
+            .iter()
+            .zip(file_vectors)
+            .map(|(c, vector)| {
+                let vector = vector.or_fail()?;
+                Ok(MatchedChunk {
+                    line: c.line,
+                    line_count: NonZeroUsize::new(c.line_count).unwrap_or(chunk.line_count),
+                    columns: c.columns.clone(),
+                    hash: Some(ContentHash::of(&c.data)),
+                    similarity: unit_cosine_similarity(&query_vector, &vector, true),
+                    vector,
+                    ..chunk.clone()
+                })
+            })
+            .collect::<orfail::Result<Vec<_>>>()?;
+        file_chunks.retain(|c| c.similarity >= similarity_threshold);
+        file_chunks.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        refreshed.extend(file_chunks.into_iter().take(count));
+    }
+
+    // A refreshed chunk may coincide with another result
+    let mut seen = HashSet::new();
+    refreshed.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    refreshed.retain(|c| seen.insert(c.span_key()));
+    Ok(refreshed)
 }
@@ -1,13 +1,22 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
 
 use orfail::OrFail;
 
 use crate::{
-    chunker::Chunker,
-    embedder::Embedder,
+    chunker::{Chunker, ContentHash, extract_chunk_text},
+    embedder::{EmbedderOptions, Embedding, EmbeddingProvider},
+    embedding_cache::EmbeddingCache,
     git::GitRepository,
     glob::GlobPathFilter,
+    hnsw::HnswIndex,
     index_file::{ChunkEntry, IndexFile, IndexFileEntry},
+    indexer::{BatchLimits, Indexer},
+    lexical_search::term_counts,
+    term_index::TermIndex,
 };
 
 pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
@@ -19,19 +28,14 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
         .example("/path/to/.dokosa")
         .take(&mut args)
         .then(|a| a.value().parse())?;
-    let api_key: String = noargs::opt("openai-api-key")
-        .ty("STRING")
-        .doc("OpenAI API key for generating embeddings")
-        .example("YOUR_API_KEY")
-        .env("OPENAI_API_KEY")
-        .take(&mut args)
-        .then(|a| a.value().parse())?;
-    let model: String = noargs::opt("embedding-model")
-        .ty("STRING")
-        .doc("OpenAI embedding model to use for text vectorization")
-        .default("text-embedding-3-small")
+    let embedder_options = EmbedderOptions::take(&mut args)?;
+    let batch_limits = BatchLimits::take(&mut args)?;
+    let embedding_cache_path: Option<PathBuf> = noargs::opt("embedding-cache-file")
+        .ty("PATH")
+        .doc("Path to a file caching embeddings by content hash to avoid re-embedding chunks not found in the updated files' old chunks")
+        .env("DOKOSA_EMBEDDING_CACHE_FILE")
         .take(&mut args)
-        .then(|a| a.value().parse())?;
+        .present_and_then(|a| a.value().parse())?;
     let dry_run = noargs::flag("dry-run")
         .doc("Show what would be done without actually modifying the index")
         .take(&mut args)
@@ -45,17 +49,44 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
     let temp_index_file = if dry_run {
         None
     } else {
-        Some(IndexFile::create_new(index_file_path.with_extension(".temp")).or_fail()?)
+        Some(
+            IndexFile::create_new(index_file_path.with_extension(".temp"), index_file.format)
+                .or_fail()?,
+        )
     };
 
-    let embedder = Embedder::new(api_key, model);
+    let mut embedding_cache = if dry_run {
+        None
+    } else {
+        embedding_cache_path
+            .map(EmbeddingCache::open)
+            .transpose()
+            .or_fail()?
+    };
     let mut removing = false;
+    let mut repository = None;
+    let mut file_content = None;
+    let mut failed_files = Vec::new();
     let mut updated_files = Vec::new();
     let mut removed_files = Vec::new();
+    let mut modified_files = Vec::new();
+    let mut pending_repository: Option<UpdatedRepository> = None;
+    let mut reused = 0;
     for entry in index_file.entries() {
         let entry = entry.or_fail()?;
         match entry {
             IndexFileEntry::Repository(mut repo) => {
+                if let (Some(pending), Some(temp)) = (pending_repository.take(), &temp_index_file) {
+                    reused += pending
+                        .index(
+                            temp,
+                            batch_limits,
+                            embedding_cache.as_mut(),
+                            &mut failed_files,
+                        )
+                        .or_fail()?;
+                }
+
                 eprintln!("Repository: {} ({})", repo.path.display(), repo.commit);
                 let Ok(git) = GitRepository::new(&repo.path)
                     .or_fail()
@@ -66,6 +97,10 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
                     continue;
                 };
                 removing = false;
+                repository = Some((repo.path.clone(), repo.chunk_window_size));
+                updated_files.clear();
+                removed_files.clear();
+                modified_files = git.modified_files().or_fail()?;
 
                 let new_commit = git.commit_hash().or_fail()?;
                 if repo.commit == new_commit {
@@ -78,7 +113,25 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
 
                 (updated_files, removed_files) = git.diff_files(&repo.commit).or_fail()?;
 
+                let embedder_options = embedder_options.inherit(&repo).or_fail()?;
+                let embedder = embedder_options.build().or_fail()?;
+                if let (Some(expected), Some(actual)) =
+                    (repo.vector_dimension, embedder.dimension())
+                {
+                    (expected == actual).or_fail_with(|()| {
+                        format!("Embedding dimension mismatch: expected {expected}, got {actual}")
+                    })?;
+                }
+
                 repo.commit = new_commit;
+                repo.embedding_endpoint = embedder_options.endpoint.clone();
+
+                // Entries written by older versions lack the settings, which are assumed
+                // to be those used now and recorded so that later runs check them
+                repo.embedding_provider
+                    .get_or_insert(embedder_options.provider());
+                repo.embedding_model
+                    .get_or_insert_with(|| embedder.model().to_owned());
                 if let Some(temp) = &temp_index_file {
                     temp.append_repository(&repo).or_fail()?;
                 }
@@ -87,51 +140,28 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
                     include_files: repo.include_files.clone(),
                     exclude_files: repo.exclude_files.clone(),
                 };
-                let chunker = Chunker::new(repo.chunk_window_size, repo.chunk_step_size);
+                let mut files = Vec::new();
                 for updated_file in &updated_files {
-                    if !filter.matches(updated_file) {
-                        continue;
-                    }
-                    eprintln!("  => Updated file: {}", updated_file.display());
-
-                    let Some(temp) = &temp_index_file else {
-                        continue;
-                    };
-
-                    let abs_file_path = repo.path.join(updated_file);
-                    let Ok(content) = std::fs::read_to_string(&abs_file_path)
-                        .or_fail()
-                        .inspect_err(|e| eprintln!("  Failed to read file: {}", e))
-                    else {
-                        continue;
-                    };
-                    if content.is_empty() {
-                        continue;
-                    }
-
-                    let mut chunks = chunker.apply(&content);
-                    let inputs = chunks
-                        .iter_mut()
-                        .map(|c| std::mem::take(&mut c.data))
-                        .collect::<Vec<_>>();
-                    let Ok(embeddings) = embedder
-                        .embed(&inputs)
-                        .or_fail()
-                        .inspect_err(|e| eprintln!("  Failed to embed: {e}"))
-                    else {
-                        continue;
-                    };
-                    for (chunk, embedding) in chunks.iter().zip(embeddings) {
-                        temp.append_chunk(&ChunkEntry {
-                            path: updated_file.clone(),
-                            line: chunk.line,
-                            embedding,
-                        })
-                        .or_fail()?;
+                    if filter.matches(updated_file) {
+                        eprintln!("  => Updated file: {}", updated_file.display());
+                        files.push(updated_file.clone());
                     }
                 }
+
+                // The updated files are indexed after the old chunks of this repository
+                // have been read, so that the embeddings of their unchanged chunks can be reused
+                pending_repository = Some(UpdatedRepository {
+                    chunker: Chunker::new(repo.chunk_window_size, repo.chunk_step_size)
+                        .with_strategy(repo.chunk_strategy)
+                        .with_limits(repo.chunk_max_chars, repo.chunk_max_tokens)
+                        .with_header(repo.chunk_header.clone(), &repo.path),
+                    path: repo.path,
+                    files,
+                    embedder,
+                    previous_embeddings: HashMap::new(),
+                });
             }
-            IndexFileEntry::Chunk(chunk) => {
+            IndexFileEntry::Chunk(mut chunk) => {
                 if removing {
                     continue;
                 }
@@ -142,20 +172,150 @@ pub fn run(mut args: noargs::RawArgs) -> noargs::Result<()> {
                     continue;
                 }
                 if updated_files.contains(&chunk.path) {
+                    if let (Some(pending), Some(key)) =
+                        (&mut pending_repository, chunk.embedding_key())
+                    {
+                        pending
+                            .previous_embeddings
+                            .entry(chunk.path)
+                            .or_default()
+                            .insert(key, chunk.embedding);
+                    }
                     continue;
                 }
 
                 if let Some(temp) = &temp_index_file {
+                    if chunk.terms.is_none()
+                        && let Some((repository_path, chunk_window_size)) = &repository
+                    {
+                        let path = repository_path.join(&chunk.path);
+                        let unmodified = !modified_files.contains(&chunk.path);
+                        backfill_terms(
+                            &mut chunk,
+                            &path,
+                            *chunk_window_size,
+                            unmodified,
+                            &mut file_content,
+                        );
+                    }
                     temp.append_chunk(&chunk).or_fail()?;
                 }
             }
         }
     }
 
+    if let (Some(pending), Some(temp)) = (pending_repository, &temp_index_file) {
+        reused += pending
+            .index(
+                temp,
+                batch_limits,
+                embedding_cache.as_mut(),
+                &mut failed_files,
+            )
+            .or_fail()?;
+    }
     if let Some(temp) = temp_index_file {
-        std::fs::rename(temp.path, index_file.path).or_fail()?;
+        std::fs::rename(&temp.path, &index_file.path).or_fail()?;
+        HnswIndex::update_sidecar(&index_file, None).or_fail()?;
+        TermIndex::update_sidecar(&index_file).or_fail()?;
     }
 
+    if !failed_files.is_empty() {
+        eprintln!("=> Failed to embed {} file(s):", failed_files.len());
+        for path in &failed_files {
+            eprintln!("  {}", path.display());
+        }
+    }
+    if reused > 0 {
+        eprintln!("=> Reused {reused} embedding(s) of unchanged chunks");
+    }
+    if let Some(cache) = &embedding_cache {
+        eprintln!("=> Reused {} cached embedding(s)", cache.hits);
+    }
     eprintln!("=> Synced");
     Ok(())
 }
+
+/// A repository with updated files to be indexed.
+#[derive(Debug)]
+struct UpdatedRepository {
+    path: PathBuf,
+    files: Vec<PathBuf>,
+    chunker: Chunker,
+    embedder: Box<dyn EmbeddingProvider>,
+
+    /// Embeddings of the old chunks of the updated files, keyed by [`ChunkEntry::embedding_key()`].
+    previous_embeddings: HashMap<PathBuf, HashMap<ContentHash, Embedding>>,
+}
+
+impl UpdatedRepository {
+    /// Appends the chunks of the updated files to the index file, returning the number of reused embeddings.
+    fn index(
+        self,
+        index_file: &IndexFile,
+        batch_limits: BatchLimits,
+        embedding_cache: Option<&mut EmbeddingCache>,
+        failed_files: &mut Vec<PathBuf>,
+    ) -> orfail::Result<usize> {
+        let mut indexer = Indexer::new(&*self.embedder, batch_limits).with_cache(embedding_cache);
+        let no_embeddings = HashMap::new();
+        for file in self.files {
+            let abs_file_path = self.path.join(&file);
+            let Ok(content) = std::fs::read_to_string(&abs_file_path)
+                .or_fail()
+                .inspect_err(|e| eprintln!("  Failed to read file: {}", e))
+            else {
+                continue;
+            };
+            if content.is_empty() {
+                continue;
+            }
+
+            let chunks = self.chunker.apply(&file, &content);
+            let previous_embeddings = self
+                .previous_embeddings
+                .get(&file)
+                .unwrap_or(&no_embeddings);
+            indexer
+                .add_updated_file(index_file, file, chunks, previous_embeddings)
+                .or_fail()?;
+        }
+        indexer.finish(index_file).or_fail()?;
+        failed_files.extend(indexer.failed_files.iter().map(|p| self.path.join(p)));
+        Ok(indexer.reused)
+    }
+}
+
+/// Builds the missing term counts of an unchanged chunk (indexed by an older version) from the file,
+/// provided that the chunk text still has the indexed hash.
+///
+/// Chunks without a hash are backfilled (together with their hash) if the file has no uncommitted
+/// changes (`unmodified`), as its content is then the same as at the recorded commit.
+/// `file_content` caches the last read file, as the chunks of a file are stored consecutively.
+fn backfill_terms(
+    chunk: &mut ChunkEntry,
+    path: &Path,
+    default_line_count: NonZeroUsize,
+    unmodified: bool,
+    file_content: &mut Option<(PathBuf, Option<String>)>,
+) {
+    if file_content.as_ref().is_none_or(|(p, _)| p != path) {
+        *file_content = Some((path.to_path_buf(), std::fs::read_to_string(path).ok()));
+    }
+    let Some((_, Some(content))) = file_content else {
+        return;
+    };
+    let Some(text) = extract_chunk_text(
+        content,
+        chunk.line,
+        chunk.line_count.unwrap_or(default_line_count).get(),
+        chunk.columns.as_ref(),
+    ) else {
+        return;
+    };
+    let hash = ContentHash::of(&text);
+    if chunk.hash.map_or(unmodified, |h| h == hash) {
+        chunk.hash = Some(hash);
+        chunk.terms = Some(term_counts(&text));
+    }
+}
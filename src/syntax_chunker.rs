@@ -0,0 +1,324 @@
+//! Heuristic splitting of source files into top-level items (functions, classes, ...).
+//!
+//! Items are found without parsing: a line starts an item if it is not indented, is outside
+//! any bracket, comment or string, and does not continue the previous item (e.g., `}` or `else`).
+//! Comments, attributes and decorators just before an item belong to the item.
+use std::{ops::Range, path::Path};
+
+/// Family of languages sharing the same item heuristics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Syntax {
+    /// C-like languages whose blocks are delimited by braces.
+    Braces,
+
+    /// Languages whose blocks are delimited by indentation (or by `end` keywords).
+    Indentation,
+}
+
+impl Syntax {
+    fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "rs" | "c" | "h" | "cc" | "cpp" | "cxx" | "hh" | "hpp" | "java" | "js" | "jsx"
+            | "mjs" | "cjs" | "ts" | "tsx" | "go" | "cs" | "kt" | "kts" | "swift" | "scala"
+            | "php" | "dart" | "zig" => Some(Syntax::Braces),
+            "py" | "pyi" | "rb" => Some(Syntax::Indentation),
+            _ => None,
+        }
+    }
+
+    fn line_comment(self) -> &'static [u8] {
+        match self {
+            Syntax::Braces => b"//",
+            Syntax::Indentation => b"#",
+        }
+    }
+
+    /// Returns whether a top-level line belongs to the item that follows it.
+    fn is_prefix(self, line: &str) -> bool {
+        match self {
+            Syntax::Braces => ["//", "/*", "#[", "#!", "@"]
+                .iter()
+                .any(|p| line.starts_with(p)),
+            Syntax::Indentation => line.starts_with('#') || line.starts_with('@'),
+        }
+    }
+
+    /// Returns whether a top-level line continues the previous item.
+    fn is_continuation(self, line: &str) -> bool {
+        if line.starts_with([')', ']', '}', '{']) {
+            return true;
+        }
+        let word = line
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .next()
+            .unwrap_or("");
+        let keywords: &[&str] = match self {
+            Syntax::Braces => &["else", "catch", "finally", "where"],
+            Syntax::Indentation => &[
+                "else", "elif", "except", "finally", "end", "rescue", "ensure", "when",
+            ],
+        };
+        keywords.contains(&word)
+    }
+}
+
+/// Splits the lines of a file into consecutive line ranges of top-level items covering all lines.
+///
+/// Returns `None` if the language of the file is not supported.
+pub fn item_ranges(path: &Path, lines: &[&str]) -> Option<Vec<Range<usize>>> {
+    let syntax = Syntax::from_path(path)?;
+    let mut scanner = Scanner::new(syntax);
+    let mut starts = vec![0];
+    let mut prefix_start = None;
+    for (i, line) in lines.iter().enumerate() {
+        let top_level = scanner.is_top_level();
+        scanner.scan(line);
+
+        let starts_item = top_level
+            && !line.is_empty()
+            && !line.starts_with(char::is_whitespace)
+            && !syntax.is_continuation(line);
+        if !starts_item {
+            continue;
+        }
+        if syntax.is_prefix(line) {
+            prefix_start.get_or_insert(i);
+            continue;
+        }
+        let start = prefix_start.take().unwrap_or(i);
+        if start > *starts.last().expect("non-empty") {
+            starts.push(start);
+        }
+    }
+    starts.push(lines.len());
+    Some(
+        starts
+            .windows(2)
+            .filter(|w| w[0] < w[1])
+            .map(|w| w[0]..w[1])
+            .collect(),
+    )
+}
+
+/// Returns the signature of an item, that is its first line that is not a comment or an attribute,
+/// without the opening of its body (e.g., `fn main()` for `fn main() {`).
+///
+/// Returns `None` for imports and preprocessor directives, and if the language of the file is not supported.
+pub fn item_signature(path: &Path, item_lines: &[&str]) -> Option<String> {
+    let syntax = Syntax::from_path(path)?;
+    let line = item_lines.iter().find(|line| {
+        !line.is_empty()
+            && !line.starts_with(char::is_whitespace)
+            && !syntax.is_prefix(line)
+            && !syntax.is_continuation(line)
+    })?;
+    let word = line.split(|c: char| !c.is_alphanumeric()).next()?;
+    if line.starts_with('#') || IMPORT_KEYWORDS.contains(&word) {
+        return None;
+    }
+
+    let signature = line.trim_end().trim_end_matches(['{', ':']).trim_end();
+    match signature.char_indices().nth(MAX_SIGNATURE_CHARS) {
+        Some((end, _)) => Some(format!("{}...", &signature[..end])),
+        None => Some(signature.to_owned()),
+    }
+}
+
+/// First words of lines importing other modules, which are not items worth naming.
+const IMPORT_KEYWORDS: &[&str] = &[
+    "use", "import", "from", "package", "using", "require", "include",
+];
+
+const MAX_SIGNATURE_CHARS: usize = 120;
+
+/// Tracks brackets, block comments and multi-line strings across lines.
+#[derive(Debug)]
+struct Scanner {
+    syntax: Syntax,
+    depth: usize,
+    in_block_comment: bool,
+
+    /// Closing delimiter of the multi-line string being scanned (e.g., `"""` in Python).
+    in_string: Option<&'static [u8]>,
+}
+
+impl Scanner {
+    fn new(syntax: Syntax) -> Self {
+        Self {
+            syntax,
+            depth: 0,
+            in_block_comment: false,
+            in_string: None,
+        }
+    }
+
+    fn is_top_level(&self) -> bool {
+        self.depth == 0 && !self.in_block_comment && self.in_string.is_none()
+    }
+
+    fn scan(&mut self, line: &str) {
+        let bytes = line.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            let rest = &bytes[i..];
+            if self.in_block_comment {
+                match find(rest, b"*/") {
+                    Some(end) => {
+                        self.in_block_comment = false;
+                        i += end + 2;
+                        continue;
+                    }
+                    None => return,
+                }
+            }
+            if let Some(delimiter) = self.in_string {
+                match find(rest, delimiter) {
+                    Some(end) => {
+                        self.in_string = None;
+                        i += end + delimiter.len();
+                        continue;
+                    }
+                    None => return,
+                }
+            }
+
+            if rest.starts_with(self.syntax.line_comment()) {
+                return;
+            }
+            if self.syntax == Syntax::Braces && rest.starts_with(b"/*") {
+                self.in_block_comment = true;
+                i += 2;
+                continue;
+            }
+            if self.syntax == Syntax::Indentation
+                && let Some(delimiter) = [&b"\"\"\""[..], &b"'''"[..]]
+                    .into_iter()
+                    .find(|d| rest.starts_with(d))
+            {
+                self.in_string = Some(delimiter);
+                i += delimiter.len();
+                continue;
+            }
+            match bytes[i] {
+                b'"' | b'`' => i = skip_quoted(bytes, i),
+                b'\'' if self.syntax == Syntax::Indentation => i = skip_quoted(bytes, i),
+                // Character literals (but not Rust lifetimes such as `'a`)
+                b'\'' if bytes.get(i + 1) == Some(&b'\\') => i = skip_quoted(bytes, i),
+                b'\'' if bytes.get(i + 2) == Some(&b'\'') => i += 3,
+                b'(' | b'[' | b'{' => {
+                    self.depth += 1;
+                    i += 1;
+                }
+                b')' | b']' | b'}' => {
+                    self.depth = self.depth.saturating_sub(1);
+                    i += 1;
+                }
+                _ => i += 1,
+            }
+        }
+    }
+}
+
+fn find(bytes: &[u8], needle: &[u8]) -> Option<usize> {
+    bytes.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Returns the position just after the string literal starting at `start` (or the end of the line).
+fn skip_quoted(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b if b == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item_starts(path: &str, source: &str) -> Vec<usize> {
+        let lines = source.lines().collect::<Vec<_>>();
+        item_ranges(Path::new(path), &lines)
+            .expect("supported")
+            .into_iter()
+            .map(|r| r.start)
+            .collect()
+    }
+
+    #[test]
+    fn rust_items() {
+        let source = r#"use std::path::Path;
+
+/// Doc comment
+#[derive(Debug)]
+struct Foo {
+    bar: &'static str,
+}
+
+impl Foo {
+    fn new() -> Self {
+        let s = "}";
+        let c = '{';
+        Self { bar: s }
+    }
+}
+
+fn generic<T>()
+where
+    T: Clone,
+{
+    /* } */
+}
+"#;
+        assert_eq!(item_starts("a.rs", source), [0, 2, 8, 16]);
+    }
+
+    #[test]
+    fn python_items() {
+        let source = r#"import os
+
+@decorator
+def f(
+    x,
+):
+    """
+def not_an_item():
+    """
+    return x
+
+if x:
+    pass
+else:
+    pass
+"#;
+        assert_eq!(item_starts("a.py", source), [0, 2, 11]);
+    }
+
+    #[test]
+    fn item_signatures() {
+        let signature = |path: &str, source: &str| {
+            item_signature(Path::new(path), &source.lines().collect::<Vec<_>>())
+        };
+        assert_eq!(
+            signature("a.rs", "/// Doc\n#[test]\nfn f() {\n}").as_deref(),
+            Some("fn f()")
+        );
+        assert_eq!(
+            signature("a.py", "@decorator\nclass A:\n    pass").as_deref(),
+            Some("class A")
+        );
+        assert_eq!(signature("a.rs", "use std::path::Path;"), None);
+        assert_eq!(signature("a.c", "#include <stdio.h>"), None);
+    }
+
+    #[test]
+    fn unsupported_language() {
+        assert!(item_ranges(Path::new("a.txt"), &["text"]).is_none());
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use orfail::OrFail;
+
+use crate::{
+    hnsw::modified_nanos,
+    index_file::{BINARY_HEADER_SIZE, IndexFile, IndexFormat, RECORD_KIND_REPOSITORY, Record},
+    mmap::MappedFile,
+};
+
+const MAGIC: &[u8; 8] = b"DOKOSATI";
+
+const VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 64;
+
+const CHUNK_SIZE: usize = 12;
+
+const TERM_SIZE: usize = 24;
+
+const POSTING_SIZE: usize = 8;
+
+/// An inverted index from the terms of the chunks of a binary index file to the chunks containing them,
+/// used by [`lexical_search::search()`](crate::lexical_search::search).
+///
+/// The index is stored in a sidecar file next to the index file, which is memory-mapped when searching
+/// so that only the postings of the query terms are read. Like [`HnswIndex`](crate::hnsw::HnswIndex),
+/// it refers to chunks by the offsets of their records and records the length and modification time
+/// of the index file to detect changes to it.
+///
+/// The file consists of a header, the offsets of the repository records, the offsets and lengths
+/// (numbers of terms) of the chunk records with terms, a dictionary of the terms sorted bytewise,
+/// the term bytes, and the postings (chunk number and term frequency) of each term in chunk order.
+/// All integers are little-endian.
+#[derive(Debug)]
+pub struct TermIndex {
+    file: MappedFile,
+    index_len: u64,
+    index_modified: u64,
+    unindexed_count: u64,
+    total_len: u64,
+    repository_count: usize,
+    chunk_count: usize,
+    term_count: usize,
+    names_len: usize,
+}
+
+impl TermIndex {
+    /// Returns the path of the sidecar file of the given index file (e.g., `.dokosa.terms`).
+    pub fn sidecar_path<P: AsRef<Path>>(index_file_path: P) -> PathBuf {
+        let mut path = index_file_path.as_ref().as_os_str().to_owned();
+        path.push(".terms");
+        PathBuf::from(path)
+    }
+
+    /// Rebuilds the sidecar file of a binary index file (JSON Lines index files have none).
+    pub fn update_sidecar(index_file: &IndexFile) -> orfail::Result<()> {
+        if index_file.format != IndexFormat::Binary {
+            return Ok(());
+        }
+        let (data, chunk_count, term_count) = Self::build(index_file).or_fail()?;
+
+        let path = Self::sidecar_path(&index_file.path);
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".temp");
+        let temp_path = PathBuf::from(temp_path);
+        let mut file = std::fs::File::create(&temp_path).or_fail()?;
+        file.write_all(&data).or_fail()?;
+        std::fs::rename(&temp_path, &path).or_fail()?;
+        eprintln!("=> Updated term index ({chunk_count} chunk(s), {term_count} term(s))");
+        Ok(())
+    }
+
+    /// Loads the sidecar file of the index file, returning `None` if it does not exist.
+    pub fn load(index_file: &IndexFile) -> orfail::Result<Option<Self>> {
+        let path = Self::sidecar_path(&index_file.path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Self::decode(MappedFile::open(&path).or_fail()?)
+            .or_fail_with(|e| format!("Invalid term index file {}: {e}", path.display()))
+            .map(Some)
+    }
+
+    /// Returns `true` if the postings reflect the current content of the index file.
+    pub fn is_up_to_date(&self, index_file: &IndexFile) -> orfail::Result<bool> {
+        let metadata = std::fs::metadata(&index_file.path).or_fail()?;
+        Ok(index_file.format == IndexFormat::Binary
+            && metadata.len() == self.index_len
+            && modified_nanos(&metadata) == self.index_modified)
+    }
+
+    /// Returns the number of chunks with terms.
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_count
+    }
+
+    /// Returns the number of chunks without terms (i.e., indexed by older versions).
+    pub fn unindexed_count(&self) -> usize {
+        self.unindexed_count as usize
+    }
+
+    /// Returns the total number of terms in the chunks.
+    pub fn total_len(&self) -> u64 {
+        self.total_len
+    }
+
+    /// Returns the record offset and the number of terms of the given chunk.
+    pub fn chunk(&self, chunk: u32) -> orfail::Result<(u64, u32)> {
+        let chunk = chunk as usize;
+        (chunk < self.chunk_count).or_fail()?;
+        let data = self.file.as_bytes();
+        let pos = self.chunks_start() + chunk * CHUNK_SIZE;
+        Ok((
+            u64_at(data, pos).or_fail()?,
+            u32_at(data, pos + 8).or_fail()?,
+        ))
+    }
+
+    /// Returns the offset of the record of the repository that the record at `offset` belongs to.
+    pub fn repository_offset(&self, offset: u64) -> orfail::Result<u64> {
+        // The repository offsets are sorted, so the last one before `offset` is searched for
+        let data = self.file.as_bytes();
+        let (mut low, mut high) = (0, self.repository_count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if u64_at(data, HEADER_SIZE + mid * 8).or_fail()? < offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let i = low.checked_sub(1).or_fail()?;
+        u64_at(data, HEADER_SIZE + i * 8).or_fail()
+    }
+
+    /// Returns the chunk numbers and frequencies of the chunks containing the term.
+    pub fn postings(&self, term: &str) -> orfail::Result<Vec<(u32, u32)>> {
+        let data = self.file.as_bytes();
+        let (mut low, mut high) = (0, self.term_count);
+        while low < high {
+            let mid = (low + high) / 2;
+            let pos = self.terms_start() + mid * TERM_SIZE;
+            let name_start = u64_at(data, pos).or_fail()? as usize;
+            let name_len = u32_at(data, pos + 16).or_fail()? as usize;
+            let name = bytes_at(data, self.names_start() + name_start, name_len).or_fail()?;
+            match name.cmp(term.as_bytes()) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => {
+                    let postings_start = u64_at(data, pos + 8).or_fail()? as usize;
+                    let posting_count = u32_at(data, pos + 20).or_fail()? as usize;
+                    let bytes = bytes_at(
+                        data,
+                        self.postings_start() + postings_start * POSTING_SIZE,
+                        posting_count * POSTING_SIZE,
+                    )
+                    .or_fail()?;
+                    return Ok(bytes
+                        .chunks_exact(POSTING_SIZE)
+                        .map(|b| {
+                            (
+                                u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                                u32::from_le_bytes([b[4], b[5], b[6], b[7]]),
+                            )
+                        })
+                        .collect());
+                }
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    /// Builds the content of the sidecar file, returning it with the numbers of chunks and terms.
+    fn build(index_file: &IndexFile) -> orfail::Result<(Vec<u8>, usize, usize)> {
+        let metadata = std::fs::metadata(&index_file.path).or_fail()?;
+        let file = MappedFile::open(&index_file.path).or_fail()?;
+        let data = file.as_bytes();
+
+        let mut repository_offsets = Vec::new();
+        let mut chunks = Vec::new();
+        let mut postings = BTreeMap::<String, Vec<(u32, u32)>>::new();
+        let mut unindexed_count = 0u64;
+        let mut total_len = 0u64;
+        let mut offset = BINARY_HEADER_SIZE;
+        while let Some((record, next)) = Record::parse(data, offset).or_fail()? {
+            if record.kind == RECORD_KIND_REPOSITORY {
+                repository_offsets.push(offset as u64);
+            } else if let Some(terms) = record.parse_chunk_metadata().or_fail()?.terms {
+                let chunk = u32::try_from(chunks.len()).or_fail()?;
+                let len = terms.values().sum::<u32>();
+                chunks.push((offset as u64, len));
+                total_len += u64::from(len);
+                for (term, tf) in terms {
+                    postings.entry(term).or_default().push((chunk, tf));
+                }
+            } else {
+                unindexed_count += 1;
+            }
+            offset = next;
+        }
+
+        let names_len = postings.keys().map(|t| t.len()).sum::<usize>();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&(repository_offsets.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&modified_nanos(&metadata).to_le_bytes());
+        buf.extend_from_slice(&unindexed_count.to_le_bytes());
+        buf.extend_from_slice(&total_len.to_le_bytes());
+        buf.extend_from_slice(&(names_len as u64).to_le_bytes());
+        buf.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(postings.len() as u32).to_le_bytes());
+
+        for offset in &repository_offsets {
+            buf.extend_from_slice(&offset.to_le_bytes());
+        }
+        for (offset, len) in &chunks {
+            buf.extend_from_slice(&offset.to_le_bytes());
+            buf.extend_from_slice(&len.to_le_bytes());
+        }
+        let (mut name_start, mut postings_start) = (0u64, 0u64);
+        for (term, term_postings) in &postings {
+            buf.extend_from_slice(&name_start.to_le_bytes());
+            buf.extend_from_slice(&postings_start.to_le_bytes());
+            buf.extend_from_slice(&(term.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&(term_postings.len() as u32).to_le_bytes());
+            name_start += term.len() as u64;
+            postings_start += term_postings.len() as u64;
+        }
+        for term in postings.keys() {
+            buf.extend_from_slice(term.as_bytes());
+        }
+        for (chunk, tf) in postings.values().flatten() {
+            buf.extend_from_slice(&chunk.to_le_bytes());
+            buf.extend_from_slice(&tf.to_le_bytes());
+        }
+        Ok((buf, chunks.len(), postings.len()))
+    }
+
+    fn decode(file: MappedFile) -> orfail::Result<Self> {
+        let data = file.as_bytes();
+        (bytes_at(data, 0, MAGIC.len()).or_fail()? == MAGIC)
+            .or_fail_with(|()| "bad magic bytes".to_owned())?;
+        let version = u32_at(data, 8).or_fail()?;
+        (version == VERSION).or_fail_with(|()| format!("unsupported version {version}"))?;
+
+        let this = Self {
+            repository_count: u32_at(data, 12).or_fail()? as usize,
+            index_len: u64_at(data, 16).or_fail()?,
+            index_modified: u64_at(data, 24).or_fail()?,
+            unindexed_count: u64_at(data, 32).or_fail()?,
+            total_len: u64_at(data, 40).or_fail()?,
+            names_len: u64_at(data, 48).or_fail()? as usize,
+            chunk_count: u32_at(data, 56).or_fail()? as usize,
+            term_count: u32_at(data, 60).or_fail()? as usize,
+            file,
+        };
+        (this.postings_start() <= this.file.as_bytes().len())
+            .or_fail_with(|()| "unexpected end of file".to_owned())?;
+        Ok(this)
+    }
+
+    fn chunks_start(&self) -> usize {
+        HEADER_SIZE + self.repository_count * 8
+    }
+
+    fn terms_start(&self) -> usize {
+        self.chunks_start() + self.chunk_count * CHUNK_SIZE
+    }
+
+    fn names_start(&self) -> usize {
+        self.terms_start() + self.term_count * TERM_SIZE
+    }
+
+    fn postings_start(&self) -> usize {
+        self.names_start() + self.names_len
+    }
+}
+
+fn bytes_at(data: &[u8], pos: usize, len: usize) -> orfail::Result<&[u8]> {
+    data.get(pos..pos + len)
+        .or_fail_with(|()| "unexpected end of term index file".to_owned())
+}
+
+fn u32_at(data: &[u8], pos: usize) -> orfail::Result<u32> {
+    let b = bytes_at(data, pos, 4).or_fail()?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn u64_at(data: &[u8], pos: usize) -> orfail::Result<u64> {
+    let b = bytes_at(data, pos, 8).or_fail()?;
+    Ok(u64::from_le_bytes([
+        b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        embedder::Embedding,
+        index_file::{ChunkEntry, RepositoryEntry},
+        lexical_search::term_counts,
+        test_util::{repository_entry, temp_path},
+    };
+
+    fn chunk_entry(path: &str, text: Option<&str>) -> ChunkEntry {
+        ChunkEntry {
+            path: PathBuf::from(path),
+            line: 0,
+            line_count: None,
+            columns: None,
+            hash: None,
+            embedding_hash: None,
+            terms: text.map(term_counts),
+            embedding: Embedding(vec![1.0]),
+        }
+    }
+
+    #[test]
+    fn postings_refer_to_chunk_records() {
+        let path = temp_path("term-index");
+        let index_file = IndexFile::create_new(&path, IndexFormat::Binary).expect("create");
+        for (repository, texts) in [
+            ("/a", [Some("foo bar foo"), None]),
+            ("/b", [Some("bar"), Some("baz")]),
+        ] {
+            index_file
+                .append_repository(&RepositoryEntry {
+                    path: PathBuf::from(repository),
+                    vector_dimension: Some(1),
+                    ..repository_entry()
+                })
+                .expect("append");
+            for (i, text) in texts.into_iter().enumerate() {
+                index_file
+                    .append_chunk(&chunk_entry(&format!("{i}.rs"), text))
+                    .expect("append");
+            }
+        }
+        TermIndex::update_sidecar(&index_file).expect("update");
+        let term_index = TermIndex::load(&index_file).expect("load").expect("exists");
+        assert!(term_index.is_up_to_date(&index_file).expect("check"));
+        assert_eq!(term_index.chunk_count(), 3);
+        assert_eq!(term_index.unindexed_count(), 1);
+        assert_eq!(term_index.total_len(), 5);
+
+        assert_eq!(term_index.postings("foo").expect("postings"), [(0, 2)]);
+        assert_eq!(
+            term_index.postings("bar").expect("postings"),
+            [(0, 1), (1, 1)]
+        );
+        assert!(term_index.postings("qux").expect("postings").is_empty());
+
+        // Each chunk is resolved to its record and the record of its repository
+        let file = MappedFile::open(&path).expect("open");
+        let data = file.as_bytes();
+        for (chunk, (repository, chunk_path, len)) in
+            [("/a", "0.rs", 3), ("/b", "0.rs", 1), ("/b", "1.rs", 1)]
+                .into_iter()
+                .enumerate()
+        {
+            let (offset, chunk_len) = term_index.chunk(chunk as u32).expect("chunk");
+            assert_eq!(chunk_len, len);
+            let (record, _) = Record::parse(data, offset as usize)
+                .expect("parse")
+                .expect("record");
+            assert_eq!(
+                record.parse_chunk_metadata().expect("metadata").path,
+                PathBuf::from(chunk_path)
+            );
+            let offset = term_index.repository_offset(offset).expect("repository");
+            let (record, _) = Record::parse(data, offset as usize)
+                .expect("parse")
+                .expect("record");
+            assert_eq!(
+                record.parse_repository().expect("repository").path,
+                PathBuf::from(repository)
+            );
+        }
+
+        index_file
+            .append_chunk(&chunk_entry("2.rs", Some("qux")))
+            .expect("append");
+        assert!(!term_index.is_up_to_date(&index_file).expect("check"));
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(TermIndex::sidecar_path(&path));
+    }
+}
@@ -0,0 +1,62 @@
+//! Fixtures shared by the unit tests of several modules.
+use std::{num::NonZeroUsize, path::PathBuf};
+
+use crate::{chunker::ChunkStrategy, index_file::RepositoryEntry};
+
+/// Returns a path in the temporary directory unique to this process, removing any existing file.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("dokosa-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Returns the entry of a repository at `/repo` indexed with the default settings,
+/// to be customized with the struct update syntax.
+pub fn repository_entry() -> RepositoryEntry {
+    RepositoryEntry {
+        path: PathBuf::from("/repo"),
+        commit: "abc".to_owned(),
+        chunk_window_size: NonZeroUsize::MIN,
+        chunk_step_size: NonZeroUsize::MIN,
+        chunk_strategy: ChunkStrategy::Lines,
+        chunk_max_chars: None,
+        chunk_max_tokens: None,
+        chunk_header: Vec::new(),
+        include_files: Vec::new(),
+        exclude_files: Vec::new(),
+        embedding_provider: None,
+        embedding_model: None,
+        embedding_dimensions: None,
+        embedding_endpoint: None,
+        vector_dimension: None,
+        vector_encoding: None,
+    }
+}
+
+/// A seeded pseudo-random number generator (xorshift64*) for reproducible test inputs.
+#[derive(Debug)]
+pub struct TestRng(u64);
+
+impl TestRng {
+    /// Makes a generator from a non-zero seed.
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns a number in `0..max`.
+    pub fn below(&mut self, max: u64) -> u64 {
+        (self.next_u64() >> 32) % max
+    }
+
+    /// Returns a number in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
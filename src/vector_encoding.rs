@@ -0,0 +1,271 @@
+/// Encoding of embedding vectors in binary index files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VectorEncoding {
+    /// Little-endian 32-bit floats.
+    #[default]
+    F32,
+
+    /// Little-endian 16-bit (half precision) floats.
+    F16,
+
+    /// A little-endian 32-bit float scale followed by signed 8-bit integers
+    /// (each element is approximated as `scale * value`).
+    Int8,
+}
+
+impl VectorEncoding {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            VectorEncoding::F32 => 0,
+            VectorEncoding::F16 => 1,
+            VectorEncoding::Int8 => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(VectorEncoding::F32),
+            1 => Some(VectorEncoding::F16),
+            2 => Some(VectorEncoding::Int8),
+            _ => None,
+        }
+    }
+
+    /// Returns the size in bytes of an encoded vector with the given number of elements,
+    /// including the padding to keep the following data 4-byte aligned.
+    pub fn block_size(self, len: usize) -> usize {
+        if len == 0 {
+            return 0;
+        }
+        match self {
+            VectorEncoding::F32 => len * 4,
+            VectorEncoding::F16 => (len * 2).next_multiple_of(4),
+            VectorEncoding::Int8 => 4 + len.next_multiple_of(4),
+        }
+    }
+
+    pub fn encode(self, vector: &[f64], buf: &mut Vec<u8>) {
+        let start = buf.len();
+        match self {
+            VectorEncoding::F32 => {
+                for &x in vector {
+                    buf.extend_from_slice(&(x as f32).to_le_bytes());
+                }
+            }
+            VectorEncoding::F16 => {
+                for &x in vector {
+                    buf.extend_from_slice(&f32_to_f16(x as f32).to_le_bytes());
+                }
+            }
+            VectorEncoding::Int8 if !vector.is_empty() => {
+                let max = vector.iter().fold(0.0f64, |acc, x| acc.max(x.abs()));
+                let scale = if max > 0.0 { (max / 127.0) as f32 } else { 0.0 };
+                buf.extend_from_slice(&scale.to_le_bytes());
+                for &x in vector {
+                    let q = if scale > 0.0 {
+                        (x / f64::from(scale)).round().clamp(-127.0, 127.0) as i8
+                    } else {
+                        0
+                    };
+                    buf.push(q as u8);
+                }
+            }
+            VectorEncoding::Int8 => {}
+        }
+        buf.resize(start + self.block_size(vector.len()), 0);
+    }
+
+    /// Decodes a vector with `len` elements from the beginning of `bytes`, appending them to `out`.
+    pub fn decode_into(self, bytes: &[u8], len: usize, out: &mut Vec<f32>) {
+        match self {
+            VectorEncoding::F32 => out.extend(
+                bytes[..len * 4]
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            ),
+            VectorEncoding::F16 => out.extend(
+                bytes[..len * 2]
+                    .chunks_exact(2)
+                    .map(|b| f16_to_f32(u16::from_le_bytes([b[0], b[1]]))),
+            ),
+            VectorEncoding::Int8 if len > 0 => {
+                let scale = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                out.extend(
+                    bytes[4..4 + len]
+                        .iter()
+                        .map(|&q| scale * f32::from(q as i8)),
+                );
+            }
+            VectorEncoding::Int8 => {}
+        }
+    }
+}
+
+impl std::fmt::Display for VectorEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VectorEncoding::F32 => write!(f, "f32"),
+            VectorEncoding::F16 => write!(f, "f16"),
+            VectorEncoding::Int8 => write!(f, "int8"),
+        }
+    }
+}
+
+impl std::str::FromStr for VectorEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(VectorEncoding::F32),
+            "f16" => Ok(VectorEncoding::F16),
+            "int8" => Ok(VectorEncoding::Int8),
+            _ => Err(format!(
+                "unknown vector encoding: expected 'f32', 'f16' or 'int8', found '{s}'"
+            )),
+        }
+    }
+}
+
+impl nojson::DisplayJson for VectorEncoding {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.string(self)
+    }
+}
+
+impl<'text> nojson::FromRawJsonValue<'text> for VectorEncoding {
+    fn from_raw_json_value(
+        value: nojson::RawJsonValue<'text, '_>,
+    ) -> Result<Self, nojson::JsonParseError> {
+        value
+            .to_unquoted_string_str()?
+            .parse()
+            .map_err(|e| nojson::JsonParseError::invalid_value(value, e))
+    }
+}
+
+/// Converts an `f32` to the bits of the nearest IEEE 754 half precision float (ties to even).
+fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exp == 0xff {
+        // Infinity or NaN
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    let half_exp = exp - 127 + 15;
+    if half_exp >= 0x1f {
+        // Overflow
+        return sign | 0x7c00;
+    }
+
+    if half_exp <= 0 {
+        // Subnormal or zero
+        if half_exp < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - half_exp) as u32;
+        let half_mantissa = mantissa >> shift;
+        let rest = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round_up = rest > halfway || (rest == halfway && half_mantissa & 1 == 1);
+        return sign | (half_mantissa as u16 + u16::from(round_up));
+    }
+
+    let half = ((half_exp as u32) << 10) | (mantissa >> 13);
+    let rest = mantissa & 0x1fff;
+    let round_up = rest > 0x1000 || (rest == 0x1000 && half & 1 == 1);
+    // A carry from the mantissa correctly rounds up into the exponent (possibly to infinity).
+    sign | (half + u32::from(round_up)) as u16
+}
+
+/// Converts the bits of an IEEE 754 half precision float to an `f32`.
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = u32::from(h & 0x8000) << 16;
+    let exp = u32::from((h >> 10) & 0x1f);
+    let mantissa = u32::from(h & 0x3ff);
+
+    let bits = match (exp, mantissa) {
+        (0, 0) => sign,
+        (0, _) => {
+            // Subnormal: normalize the mantissa
+            let shift = mantissa.leading_zeros() - 21;
+            let mantissa = (mantissa << shift) & 0x3ff;
+            let exp = 127 - 15 + 1 - shift;
+            sign | (exp << 23) | (mantissa << 13)
+        }
+        (0x1f, _) => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exp + 127 - 15) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(encoding: VectorEncoding, vector: &[f64]) -> Vec<f32> {
+        let mut buf = Vec::new();
+        encoding.encode(vector, &mut buf);
+        assert_eq!(buf.len(), encoding.block_size(vector.len()));
+        assert_eq!(buf.len() % 4, 0);
+
+        let mut out = Vec::new();
+        encoding.decode_into(&buf, vector.len(), &mut out);
+        out
+    }
+
+    #[test]
+    fn f32_roundtrip() {
+        let vector = [0.25, -1.5, 3.0e-8, 12345.0];
+        let decoded = roundtrip(VectorEncoding::F32, &vector);
+        assert_eq!(decoded, vector.map(|x| x as f32));
+    }
+
+    #[test]
+    fn f16_roundtrip() {
+        let vector = [0.0, -0.0, 1.0, -2.5, 0.1, 65504.0, 1.0e-6, 1.0e6, -3.0e-8];
+        let decoded = roundtrip(VectorEncoding::F16, &vector);
+        let expected = [
+            0.0,
+            -0.0,
+            1.0,
+            -2.5,
+            0.099975586,
+            65504.0,
+            1.013279e-6,
+            f32::INFINITY,
+            -5.9604645e-8,
+        ];
+        assert_eq!(decoded, expected);
+
+        for bits in 0..=u16::MAX {
+            let x = f16_to_f32(bits);
+            if !x.is_nan() {
+                assert_eq!(f32_to_f16(x), bits, "{bits:#x}");
+            }
+        }
+    }
+
+    #[test]
+    fn int8_roundtrip() {
+        let vector = [0.5, -1.0, 0.25, 0.0, 0.999];
+        let decoded = roundtrip(VectorEncoding::Int8, &vector);
+        for (x, y) in vector.iter().zip(&decoded) {
+            assert!((x - f64::from(*y)).abs() <= 1.0 / 254.0, "{x} vs {y}");
+        }
+        assert_eq!(decoded[1], -1.0);
+
+        let decoded_again = roundtrip(
+            VectorEncoding::Int8,
+            &decoded.iter().map(|&x| f64::from(x)).collect::<Vec<_>>(),
+        );
+        assert_eq!(decoded, decoded_again);
+
+        assert_eq!(roundtrip(VectorEncoding::Int8, &[0.0, 0.0]), [0.0, 0.0]);
+    }
+}